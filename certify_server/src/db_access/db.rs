@@ -5,7 +5,7 @@ use chrono::NaiveDateTime;
 use common_lib::{internal_error_dyn, internal_error};
 use jwt_lib::{encryption};
 use sqlx::postgres::PgPool;
-use tracing::info;
+use tracing::{debug, info, instrument};
 use uuid::Uuid;
 
 use crate::{
@@ -14,6 +14,7 @@ use crate::{
     },
 };
 
+#[instrument(skip(pool))]
 pub async fn find_user_by_email(
     pool: &PgPool,
     email: String,
@@ -36,17 +37,18 @@ pub async fn find_user_by_email(
     Ok(users)
 }
 
+#[instrument(skip(pool, user))]
 pub async fn add_new_user_from_db(
     pool: &PgPool,
     user: SignUser,
 ) -> Result<Uuid, (StatusCode, String)> {
-    println!("add_new_user_from_db user: {}", user.email);
+    info!("add_new_user_from_db user: {}", user.email);
 
     let email = user.email.clone();
 
     let find_user = find_user_by_email(pool, user.email).await;
     if let Ok(f_user) = find_user {
-        println!("add_new_user_from_db but find registered user {:?}.", f_user);
+        info!("add_new_user_from_db but find registered user {:?}.", f_user);
 
         //这个email已经注册过了。
         return Err((
@@ -61,7 +63,7 @@ pub async fn add_new_user_from_db(
             .await
             .map_err(internal_error_dyn)?;
     
-        println!("add_new_user_from_db password_hash: {}", password_hash);
+        debug!("add_new_user_from_db password hashed.");
     
         let insert_result: Result<Uuid, (StatusCode, String)> = sqlx::query!(
             "INSERT INTO users (email, password_hash, create_time) VALUES ($1, $2, $3) RETURNING id",
@@ -3,7 +3,6 @@ use std::f32::consts::E;
 use axum::{
     extract::{Query, State},
     http::StatusCode,
-    response::Html,
     Json,
 };
 
@@ -29,12 +28,6 @@ use crate::{
     },
 };
 
-#[instrument]
-pub async fn health_handler() -> Html<&'static str> {
-    println!("some one call health check api.");
-    Html("<h1>Certify server health ok.</h1>")
-}
-
 /**
  * 注册
  */
@@ -46,7 +39,7 @@ pub async fn sign_up(
     validate_payload(&user).map_err(internal_error)?;
     let addResultId = add_new_user_from_db(&state.pool, user).await?;
 
-    println!("sign_up add_new_user_from_db success.");
+    info!("sign_up add_new_user_from_db success.");
 
     let encodingKey: EncodingKey = EncodingKey::from_secret(env::JWT_SECRET.as_bytes());
     let token = jwt::sign(addResultId, &encodingKey).map_err(internal_error)?;
@@ -0,0 +1,115 @@
+/**
+ * 管理接口的身份验证。
+ *
+ * 管理员是环境变量（逗号分隔的用户id）中列出的用户，请求头中需要带上certify_server签发的jwt。
+ * 不同的管理接口使用不同的环境变量，各自实现AdminRole，然后用Admin<R>作为extractor：
+ * 请求头中没有合法的jwt时返回401，jwt中的用户不在名单中时返回403。
+ */
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Debug,
+    marker::PhantomData,
+    sync::Mutex,
+};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use jwt_lib::jwt::Claims;
+use uuid::Uuid;
+
+/**
+ * 一类管理员。ENV为保存管理员id的环境变量，NAME用于403的提示信息，比如goods admin
+ */
+pub trait AdminRole: Debug + Clone + Send + Sync + 'static {
+    const ENV: &'static str;
+    const NAME: &'static str;
+
+    /**
+     * 管理员的用户id，第一次调用时读取环境变量，配置不合法时直接退出
+     */
+    fn ids() -> &'static [Uuid] {
+        admin_ids_from_env(Self::ENV)
+    }
+}
+
+/**
+ * 通过身份验证的管理员
+ */
+#[derive(Debug, Clone)]
+pub struct Admin<R: AdminRole> {
+    pub id: Uuid,
+    role: PhantomData<R>,
+}
+
+/**
+ * 从环境变量中读取管理员的用户id，每个环境变量只读取一次。没有配置时返回空，任何人都不是管理员。
+ */
+pub fn admin_ids_from_env(name: &'static str) -> &'static [Uuid] {
+    static ADMIN_IDS: Mutex<Option<HashMap<&'static str, &'static [Uuid]>>> = Mutex::new(None);
+
+    let mut cache = ADMIN_IDS.lock().unwrap();
+    cache
+        .get_or_insert_with(HashMap::new)
+        .entry(name)
+        .or_insert_with(|| Vec::leak(parse_admin_ids(name, &env::var(name).unwrap_or_default())))
+}
+
+fn parse_admin_ids(name: &str, value: &str) -> Vec<Uuid> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| Uuid::parse_str(id).unwrap_or_else(|e| panic!("illegal {} {}: {}", name, id, e)))
+        .collect()
+}
+
+#[async_trait]
+impl<S, R> FromRequestParts<S> for Admin<R>
+where
+    S: Send + Sync,
+    R: AdminRole,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state)
+            .await
+            .map_err(|(_, e)| (StatusCode::UNAUTHORIZED, e))?;
+
+        if !R::ids().contains(&claims.sub) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("user {} is not a {}.", claims.sub, R::NAME),
+            ));
+        }
+        Ok(Admin {
+            id: claims.sub,
+            role: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_admin_ids() {
+        let id = "00000000-0000-0000-0000-000000000001";
+        assert!(parse_admin_ids("TEST_ADMIN_IDS", "").is_empty());
+        assert_eq!(
+            parse_admin_ids("TEST_ADMIN_IDS", &format!(" {} ,,", id)),
+            vec![Uuid::parse_str(id).unwrap()]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "illegal TEST_ADMIN_IDS")]
+    fn test_parse_illegal_admin_ids() {
+        parse_admin_ids("TEST_ADMIN_IDS", "not-a-uuid");
+    }
+}
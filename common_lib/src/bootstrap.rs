@@ -0,0 +1,601 @@
+/**
+ * 微服务启动器。
+ *
+ * 各个微服务的main里原本都重复着同样的流程：读取.env配置、初始化日志、创建数据库连接池、
+ * 注册consul、启动http服务。这里统一封装起来，并且在收到SIGTERM（或者ctrl-c）的时候优雅退出：
+ * 先进入维护模式并同步到consul，等调用方摘除实例之后停止接收新连接，等待处理中的请求完成，
+ * 然后停止后台任务，从consul注销，最后关闭连接池。
+ */
+use std::{
+    collections::HashMap, convert::Infallible, env, error::Error, future::Future, net::SocketAddr,
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{Request, Response},
+};
+use futures::future::BoxFuture;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use tokio::{task::JoinHandle, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::NamedService;
+use tonic_health::proto::health_server::{Health, HealthServer};
+use tower::Service;
+use tracing::{error, info, warn};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::{
+    health::{self, Readiness},
+    maintenance::{self, Maintenance, TrackInFlight},
+    metrics, telemetry,
+};
+use consul_reg_lib::{
+    consul::Consul,
+    model::{self, HealthCheck, Registration},
+};
+
+/**
+ * 检查实例是否还注册在consul上的间隔
+ */
+const REGISTRATION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+type BackgroundTask = Box<dyn FnOnce(CancellationToken) -> BoxFuture<'static, ()> + Send>;
+
+pub struct ServiceBuilder {
+    name: String,
+    addr: String,
+    instance_id: String,
+    health_check_path: String,
+    register_consul: bool,
+    ttl_check: Option<Duration>,
+    tcp_check: bool,
+    grpc_service: Option<String>,
+    meta: HashMap<String, String>,
+    shutdown_timeout: Duration,
+    shutdown_delay: Duration,
+    pools: Vec<PgPool>,
+    readiness: Readiness,
+    maintenance: Maintenance,
+    tasks: Vec<(String, BackgroundTask)>,
+    log_guard: Option<WorkerGuard>,
+}
+
+impl ServiceBuilder {
+    /**
+     * name 服务名，注册到consul时也使用这个名字
+     * default_addr 默认监听地址，可以通过环境变量SERVICE_ADDR覆盖
+     */
+    pub fn new(name: &str, default_addr: &str) -> Self {
+        dotenv::dotenv().ok();
+        metrics::init(name);
+
+        let addr = env::var("SERVICE_ADDR").unwrap_or_else(|_| default_addr.to_string());
+        let socket_addr: SocketAddr = addr
+            .parse()
+            .unwrap_or_else(|e| panic!("illegal service addr {}: {}", addr, e));
+        let instance_id = model::instance_id(
+            name,
+            &socket_addr.ip().to_string(),
+            socket_addr.port() as i32,
+        );
+
+        //维护期间就绪检查失败，实例从consul和grpc健康检查中摘除
+        let readiness = Readiness::new();
+        let maintenance = Maintenance::new();
+        let in_maintenance = maintenance.clone();
+        readiness.add_check("maintenance", move || {
+            let reason = in_maintenance.reason();
+            async move {
+                match reason {
+                    Some(reason) => Err(format!("in maintenance mode: {}", reason)),
+                    None => Ok(()),
+                }
+            }
+        });
+
+        Self {
+            name: name.to_string(),
+            addr,
+            instance_id,
+            health_check_path: "/health/ready".to_string(),
+            register_consul: false,
+            ttl_check: None,
+            tcp_check: false,
+            grpc_service: None,
+            meta: HashMap::new(),
+            shutdown_timeout: Duration::from_secs(10),
+            shutdown_delay: Duration::from_secs(5),
+            pools: vec![],
+            readiness,
+            maintenance,
+            tasks: vec![],
+            log_guard: None,
+        }
+    }
+
+    /**
+     * 初始化tracing，输出JSON格式的日志，每行日志都带上所在span的字段（比如request_id）。
+     * 日志级别由RUST_LOG控制（默认info，比如RUST_LOG=info,sqlx=warn），
+     * 默认输出到标准输出，设置了LOG_DIR时改为按小时滚动写入该目录。
+     * 写日志的guard会一直保留到服务退出，保证退出前缓冲的日志都能落盘。
+     * 配置了OTEL_EXPORTER_OTLP_ENDPOINT时，span同时导出到OTLP collector。
+     */
+    pub fn init_logging(mut self) -> Self {
+        let (non_blocking, guard) = match env::var("LOG_DIR") {
+            Ok(log_dir) => tracing_appender::non_blocking(tracing_appender::rolling::hourly(
+                log_dir,
+                format!("{}.log", self.name),
+            )),
+            Err(_) => tracing_appender::non_blocking(std::io::stdout()),
+        };
+        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .with_writer(non_blocking),
+            )
+            .with(telemetry::otel_layer(&self.name))
+            .init();
+
+        self.log_guard = Some(guard);
+        self
+    }
+
+    pub fn health_check(mut self, path: &str) -> Self {
+        self.health_check_path = path.to_string();
+        self
+    }
+
+    /**
+     * 启动时注册到consul中心，退出时注销
+     */
+    pub fn register_consul(mut self) -> Self {
+        self.register_consul = true;
+        self
+    }
+
+    /**
+     * 使用TTL检查代替http检查，服务每ttl/3根据就绪检查的结果向consul上报一次状态，
+     * 降级时上报warning。consul不需要能访问到服务的地址。
+     */
+    pub fn ttl_check(mut self, ttl: Duration) -> Self {
+        self.ttl_check = Some(ttl);
+        self
+    }
+
+    /**
+     * 额外添加一个tcp检查，只检查端口能否连接
+     */
+    pub fn tcp_check(mut self) -> Self {
+        self.tcp_check = true;
+        self
+    }
+
+    /**
+     * 注册到consul时附带的元数据，调用方发现服务时可以拿到
+     */
+    pub fn meta(mut self, key: &str, value: &str) -> Self {
+        self.meta.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /**
+     * 退出时等待每个后台任务结束的最长时间
+     */
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /**
+     * 退出时进入维护模式之后，等待调用方通过consul感知到实例被摘除的时间，这期间仍然正常处理请求。
+     * 没有注册consul时不等待。
+     */
+    pub fn shutdown_delay(mut self, delay: Duration) -> Self {
+        self.shutdown_delay = delay;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /**
+     * 实例的唯一id，注册到consul时作为服务id，每次启动都不一样
+     */
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    pub fn health_check_path(&self) -> &str {
+        &self.health_check_path
+    }
+
+    /**
+     * 服务的就绪检查，通过pg_pool创建的连接池会自动加入检查，
+     * 其它关键依赖由服务自己通过add_check添加。
+     */
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    /**
+     * 服务的维护模式，管理接口通过maintenance::router添加到服务的路由中
+     */
+    pub fn maintenance(&self) -> Maintenance {
+        self.maintenance.clone()
+    }
+
+    /**
+     * 创建grpc.health.v1.Health服务，S为服务器上提供的grpc服务，
+     * 它的健康状态随就绪检查的结果定期刷新。
+     * 注册到consul时会额外添加grpc检查，并单独登记grpc地址。
+     */
+    pub fn grpc_health<S: NamedService + 'static>(&mut self) -> HealthServer<impl Health> {
+        self.grpc_service = Some(S::NAME.to_string());
+        let (reporter, service) = health::grpc_health_service();
+        let readiness = self.readiness();
+        self.background_task("grpc_health", move |shutdown| {
+            health::report_grpc_health::<S>(readiness, reporter, shutdown)
+        });
+        service
+    }
+
+    /**
+     * 根据环境变量中的数据库地址创建连接池，连接池会在服务退出时关闭。
+     * 连接池本身可以clone共享，restful和grpc服务不需要再各自创建一个。
+     */
+    pub async fn pg_pool(&mut self, url_env: &str) -> PgPool {
+        let database_url =
+            env::var(url_env).unwrap_or_else(|_| panic!("{} should be set.", url_env));
+        let pool = PgPoolOptions::new()
+            .connect(&database_url)
+            .await
+            .unwrap_or_else(|e| panic!("connect to {} failed: {}", url_env, e));
+
+        metrics::watch_pool(url_env, pool.clone());
+        self.readiness.add_pg_pool(url_env, pool.clone());
+        self.pools.push(pool.clone());
+        pool
+    }
+
+    /**
+     * 添加一个随服务一起运行的后台任务。
+     * 服务退出时，处理中的请求排空之后传入的CancellationToken会被取消，任务需要据此自行结束。
+     */
+    pub fn background_task<F, Fut>(&mut self, name: &str, task: F)
+    where
+        F: FnOnce(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.push((
+            name.to_string(),
+            Box::new(move |shutdown| -> BoxFuture<'static, ()> { Box::pin(task(shutdown)) }),
+        ));
+    }
+
+    /**
+     * 启动http服务，直到收到退出信号并完成清理后才返回。
+     * service可以是axum的Router，也可以是rest和grpc合并后的MultiplexService。
+     */
+    pub async fn serve<S, B>(self, service: S)
+    where
+        S: Service<Request<Body>, Response = Response<B>, Error = Infallible>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+        B: HttpBody + Unpin + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let addr: SocketAddr = self
+            .addr
+            .parse()
+            .unwrap_or_else(|e| panic!("illegal service addr {}: {}", self.addr, e));
+
+        let registration = self.register_consul.then(|| self.registration(addr));
+        let ttl_check_id = self.check_id("ttl");
+
+        //收到退出信号只触发退出流量和排空请求，后台任务等请求排空之后才停止
+        let shutdown = CancellationToken::new();
+        tokio::spawn(wait_for_signal(shutdown.clone()));
+        let background_stop = CancellationToken::new();
+        //维护模式的同步要持续到请求排空之后，退出过程中的状态变化才能到达consul
+        let maintenance_stop = CancellationToken::new();
+
+        let mut tasks: Vec<(String, JoinHandle<()>)> = self
+            .tasks
+            .into_iter()
+            .map(|(name, task)| {
+                info!("start background task {}.", name);
+                (name, tokio::spawn(task(background_stop.child_token())))
+            })
+            .collect();
+
+        let consul = match registration {
+            Some(registration) => {
+                let consul = register_consul(&registration).await;
+                if let Some(consul) = &consul {
+                    let supervisor =
+                        supervise_registration(consul.clone(), registration, background_stop.child_token());
+                    info!("start background task registration.");
+                    tasks.push(("registration".to_string(), tokio::spawn(supervisor)));
+
+                    let sync = maintenance::sync_consul(
+                        self.maintenance.clone(),
+                        consul.clone(),
+                        self.instance_id.clone(),
+                        maintenance_stop.clone(),
+                    );
+                    info!("start background task maintenance.");
+                    tasks.push(("maintenance".to_string(), tokio::spawn(sync)));
+                }
+                consul
+            }
+            None => None,
+        };
+
+        if let (Some(consul), Some(ttl)) = (&consul, self.ttl_check) {
+            let heartbeat = health::report_ttl_check(
+                self.readiness.clone(),
+                consul.clone(),
+                ttl_check_id,
+                ttl,
+                background_stop.child_token(),
+            );
+            info!("start background task ttl_check.");
+            tasks.push(("ttl_check".to_string(), tokio::spawn(heartbeat)));
+        }
+
+        info!("{} listening on {}", self.name, addr);
+        //停止接收新连接的通知，等实例退出流量之后才发出
+        let stop_accepting = CancellationToken::new();
+        let accepting = stop_accepting.clone();
+        let service = TrackInFlight::new(service, self.maintenance.clone());
+        let server = axum::Server::bind(&addr)
+            .serve(tower::make::Shared::new(service))
+            .with_graceful_shutdown(async move { accepting.cancelled().await });
+        let mut server = tokio::spawn(server);
+
+        let stopped = tokio::select! {
+            result = &mut server => Some(result),
+            _ = shutdown.cancelled() => None,
+        };
+        let result = match stopped {
+            Some(result) => result,
+            None => {
+                let delay = if consul.is_some() { self.shutdown_delay } else { Duration::ZERO };
+                leave_rotation(&self.maintenance, consul.as_ref(), &self.instance_id, delay).await;
+
+                let deadline = Instant::now() + self.shutdown_timeout;
+                stop_accepting.cancel();
+                if self.maintenance.drain(self.shutdown_timeout).await {
+                    info!("all in-flight requests finished.");
+                } else {
+                    warn!(
+                        "{} requests still in flight after {:?}.",
+                        self.maintenance.in_flight(),
+                        self.shutdown_timeout
+                    );
+                }
+                match tokio::time::timeout_at(deadline, &mut server).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        warn!("{} server did not stop in time.", self.name);
+                        server.abort();
+                        Ok(Ok(()))
+                    }
+                }
+            }
+        };
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("{} server error: {}", self.name, e),
+            Err(e) => error!("{} server task failed: {}", self.name, e),
+        }
+        maintenance_stop.cancel();
+
+        //请求排空、服务器退出之后再停止后台任务，排空期间定时任务和健康上报照常运行
+        background_stop.cancel();
+        //服务器自己退出时也结束等待信号的任务
+        shutdown.cancel();
+        info!("{} drained, stop background tasks.", self.name);
+
+        for (name, handle) in tasks {
+            match tokio::time::timeout(self.shutdown_timeout, handle).await {
+                Ok(Ok(())) => info!("background task {} stopped.", name),
+                Ok(Err(e)) => error!("background task {} failed: {}", name, e),
+                Err(_) => warn!("background task {} did not stop in time.", name),
+            }
+        }
+
+        //注册的后台任务已经停止，注销之后不会再被重新注册
+        if let Some(consul) = consul {
+            match consul.deregister(&self.instance_id).await {
+                Ok(_) => info!("deregister consul done."),
+                Err(e) => error!("deregister consul failed: {}", e),
+            }
+        }
+
+        for pool in self.pools {
+            pool.close().await;
+        }
+
+        info!("{} shutdown complete.", self.name);
+        telemetry::shutdown();
+    }
+}
+
+impl ServiceBuilder {
+    /**
+     * 注册到consul的参数。
+     * 默认使用http检查，配置了ttl_check时改为TTL检查，grpc和tcp检查是额外添加的。
+     * rest地址和grpc地址分别作为tagged_addresses登记。
+     */
+    fn registration(&self, addr: SocketAddr) -> Registration {
+        let host = addr.ip().to_string();
+        let port = addr.port() as i32;
+
+        let mut checks = vec![];
+        match self.ttl_check {
+            Some(ttl) => checks.push(HealthCheck::ttl(ttl).with_id(&self.check_id("ttl"))),
+            None => {
+                let health_check_url = format!("http://{}:{}{}", host, port, self.health_check_path);
+                checks.push(HealthCheck::new(health_check_url).with_id(&self.check_id("http")));
+            }
+        }
+        if let Some(grpc_service) = &self.grpc_service {
+            let target = format!("{}:{}/{}", host, port, grpc_service);
+            checks.push(HealthCheck::grpc(target).with_id(&self.check_id("grpc")));
+        }
+        if self.tcp_check {
+            let target = format!("{}:{}", host, port);
+            checks.push(HealthCheck::tcp(target).with_id(&self.check_id("tcp")));
+        }
+
+        let mut registration = Registration {
+            name: self.name.clone(),
+            id: self.instance_id.clone(),
+            address: host.clone(),
+            port,
+            meta: self.meta.clone(),
+            ..Default::default()
+        }
+        .with_tagged_address("rest", &host, port);
+        if let Some(grpc_service) = &self.grpc_service {
+            registration = registration
+                .with_tagged_address("grpc", &host, port)
+                .with_meta("grpc_service", grpc_service);
+        }
+        for check in checks {
+            registration = registration.with_check(check);
+        }
+        registration
+    }
+
+    fn check_id(&self, kind: &str) -> String {
+        format!("{}:{}", self.instance_id, kind)
+    }
+}
+
+/**
+ * 退出前先让实例退出流量：进入维护模式并立即同步到consul，
+ * 再等待delay让调用方的服务发现感知到实例被摘除，这期间仍然正常接收和处理请求。
+ */
+async fn leave_rotation(
+    maintenance: &Maintenance,
+    consul: Option<&Consul>,
+    instance_id: &str,
+    delay: Duration,
+) {
+    if !maintenance.is_enabled() {
+        maintenance.enable("shutting down");
+    }
+    if let Some(consul) = consul {
+        let reason = maintenance.reason().unwrap_or_default();
+        match consul.enable_maintenance(instance_id, &reason).await {
+            Ok(_) => info!("sync maintenance mode of {} to consul before shutdown.", instance_id),
+            Err(e) => warn!("sync maintenance mode of {} failed: {}", instance_id, e),
+        }
+    }
+    if !delay.is_zero() {
+        info!("wait {:?} before stop accepting connections.", delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/**
+ * 等待SIGTERM或者ctrl-c，收到后通知所有监听者退出
+ */
+async fn wait_for_signal(shutdown: CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install ctrl-c handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+        _ = shutdown.cancelled() => return,
+    }
+
+    info!("receive shutdown signal.");
+    shutdown.cancel();
+}
+
+/**
+ * 注册微服务到consul中。
+ * 注册失败不影响服务启动，只记录错误，之后由supervise_registration重试。
+ * 只有创建consul客户端失败（比如证书配置错误）时返回None。
+ */
+async fn register_consul(registration: &Registration) -> Option<Consul> {
+    let cs = match Consul::from_env() {
+        Ok(cs) => cs,
+        Err(e) => {
+            error!("create consul client failed: {}", e);
+            return None;
+        }
+    };
+
+    info!("register consul {} health checks:{:?}", registration.id, registration.checks);
+    match cs.register(registration).await {
+        Ok(_) => info!("register consul done."),
+        Err(e) => error!("register consul failed: {}", e),
+    }
+    Some(cs)
+}
+
+/**
+ * 定期检查实例是否还注册在consul agent上，
+ * agent重启、注册信息被清理或者启动时注册失败的，都在这里重新注册，直到收到退出通知。
+ */
+async fn supervise_registration(
+    consul: Consul,
+    registration: Registration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(REGISTRATION_CHECK_INTERVAL);
+    //第一次tick立即返回，启动时刚刚注册过，跳过
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown.cancelled() => return,
+        }
+
+        match consul.agent_service(&registration.id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                warn!("{} is not registered in consul, register again.", registration.id);
+                match consul.register(&registration).await {
+                    Ok(_) => info!("register consul done."),
+                    Err(e) => error!("register consul failed: {}", e),
+                }
+            }
+            Err(e) => warn!("check registration of {} failed: {}", registration.id, e),
+        }
+    }
+}
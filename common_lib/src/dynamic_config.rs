@@ -0,0 +1,154 @@
+/**
+ * 动态配置。
+ *
+ * 配置以JSON格式保存在consul KV中，比如config/order-srv，修改之后通过阻塞查询在几秒内推送到各个服务，不需要重新部署。
+ * 新的值必须能解析成配置类型并且通过validator校验才会生效，否则继续使用上一次的值；
+ * consul不可用时同样保留上一次的值，启动时读取不到则使用配置类型的默认值。
+ * key被删除时恢复为默认值。
+ */
+use std::{sync::Arc, time::Duration};
+
+use consul_reg_lib::{consul::Consul, watch::watch_kv};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use validator::Validate;
+
+/**
+ * 启动时等待第一次读取配置的最长时间
+ */
+const INITIAL_LOAD_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct DynamicConfig<T> {
+    key: String,
+    rx: watch::Receiver<Arc<T>>,
+}
+
+impl<T> Clone for DynamicConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            rx: self.rx.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for DynamicConfig<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DynamicConfig").field("key", &self.key).finish()
+    }
+}
+
+impl<T> DynamicConfig<T>
+where
+    T: DeserializeOwned + Validate + Default + Send + Sync + 'static,
+{
+    /**
+     * 读取并持续监听consul KV中key对应的配置
+     */
+    pub async fn load(consul: Consul, key: &str) -> Self {
+        let (tx, rx) = watch::channel(Arc::new(T::default()));
+        let mut values = watch_kv(consul, key);
+
+        match tokio::time::timeout(INITIAL_LOAD_TIMEOUT, values.next()).await {
+            Ok(Some(value)) => apply(&tx, key, value),
+            _ => warn!("load config {} failed, use default value.", key),
+        }
+
+        let watch_key = key.to_string();
+        tokio::spawn(async move {
+            while let Some(value) = values.next().await {
+                apply(&tx, &watch_key, value);
+            }
+        });
+
+        Self {
+            key: key.to_string(),
+            rx,
+        }
+    }
+}
+
+impl<T> DynamicConfig<T> {
+    /**
+     * 当前生效的配置，每次使用时调用，不要长时间持有
+     */
+    pub fn get(&self) -> Arc<T> {
+        self.rx.borrow().clone()
+    }
+
+    /**
+     * 订阅配置的变化，需要在配置变化时做额外处理（比如重建连接）时使用
+     */
+    pub fn subscribe(&self) -> watch::Receiver<Arc<T>> {
+        self.rx.clone()
+    }
+}
+
+/**
+ * 校验新读取到的配置，合法时才替换当前的配置
+ */
+fn apply<T>(tx: &watch::Sender<Arc<T>>, key: &str, value: Option<Vec<u8>>)
+where
+    T: DeserializeOwned + Validate + Default,
+{
+    let config = match value {
+        Some(bytes) => match serde_json::from_slice::<T>(&bytes) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("config {} is not legal, keep last value: {}", key, e);
+                return;
+            }
+        },
+        None => {
+            info!("config {} not found, use default value.", key);
+            T::default()
+        }
+    };
+
+    if let Err(e) = config.validate() {
+        error!("config {} is not valid, keep last value: {}", key, e);
+        return;
+    }
+
+    info!("config {} updated.", key);
+    tx.send_replace(Arc::new(config));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, Validate, Debug, PartialEq)]
+    #[serde(default)]
+    struct TestConfig {
+        #[validate(range(min = 1))]
+        retries: i32,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> Self {
+            Self { retries: 3 }
+        }
+    }
+
+    #[test]
+    fn test_apply_keeps_last_good_value() {
+        let (tx, rx) = watch::channel(Arc::new(TestConfig::default()));
+
+        apply(&tx, "test", Some(br#"{"retries": 5}"#.to_vec()));
+        assert_eq!(rx.borrow().retries, 5);
+
+        apply(&tx, "test", Some(b"not json".to_vec()));
+        assert_eq!(rx.borrow().retries, 5);
+
+        apply(&tx, "test", Some(br#"{"retries": 0}"#.to_vec()));
+        assert_eq!(rx.borrow().retries, 5);
+
+        apply(&tx, "test", None);
+        assert_eq!(rx.borrow().retries, 3);
+    }
+}
@@ -0,0 +1,367 @@
+/**
+ * 健康检查。
+ *
+ * - GET /health/live 存活检查，只要进程还能处理请求就返回up，用于判断是否需要重启
+ * - GET /health/ready 就绪检查，逐个执行注册的检查项（数据库连接池、依赖的下游服务等），
+ *   任何一项必需的检查失败都返回503，consul据此把实例从可用列表中摘除；
+ *   只有可选的检查失败时返回degraded，服务仍然可用，只是部分功能受影响
+ *
+ * grpc服务同时实现标准的grpc.health.v1.Health协议，服务状态由就绪检查的结果定期刷新。
+ * 使用consul的TTL检查时，由report_ttl_check定期把就绪检查的结果上报给consul。
+ */
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{http::StatusCode, routing::get, Json, Router};
+use consul_reg_lib::{consul::Consul, discovery::ServiceDiscovery, model::CheckStatus};
+use futures::future::{join_all, BoxFuture};
+use serde::Serialize;
+use sqlx::{Connection, PgPool};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Channel, NamedService};
+use tonic_health::{
+    proto::{
+        health_check_response::ServingStatus as ProtoServingStatus, health_client::HealthClient,
+        health_server::{Health, HealthServer},
+        HealthCheckRequest,
+    },
+    server::HealthReporter,
+    ServingStatus,
+};
+use tracing::warn;
+
+/**
+ * 单个检查项的超时时间，超时按失败处理
+ */
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/**
+ * grpc健康状态的刷新间隔
+ */
+const GRPC_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+type Check = Arc<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+#[derive(Clone)]
+struct NamedCheck {
+    name: String,
+    optional: bool,
+    check: Check,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Up,
+    /**
+     * 只有可选的检查项失败
+     */
+    Degraded,
+    Down,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckResult {
+    pub status: HealthStatus,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+impl HealthReport {
+    /**
+     * 服务是否可用，降级时也算可用
+     */
+    pub fn is_up(&self) -> bool {
+        self.status != HealthStatus::Down
+    }
+}
+
+/**
+ * 就绪检查项的集合，clone出来的实例共享同一组检查项，
+ * 所以在创建路由之后再添加的检查项也会生效。
+ */
+#[derive(Clone, Default)]
+pub struct Readiness {
+    checks: Arc<Mutex<Vec<NamedCheck>>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * 添加一个检查项，返回Err时说明依赖不可用，错误信息会出现在检查结果中
+     */
+    pub fn add_check<F, Fut>(&self, name: &str, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.push_check(name, false, check);
+    }
+
+    /**
+     * 添加一个可选的检查项，失败时服务只是降级，仍然算就绪
+     */
+    pub fn add_optional_check<F, Fut>(&self, name: &str, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.push_check(name, true, check);
+    }
+
+    fn push_check<F, Fut>(&self, name: &str, optional: bool, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let check: Check = Arc::new(move || -> BoxFuture<'static, Result<(), String>> {
+            Box::pin(check())
+        });
+        self.checks.lock().unwrap().push(NamedCheck {
+            name: name.to_string(),
+            optional,
+            check,
+        });
+    }
+
+    /**
+     * 检查数据库连接池能否取到可用的连接
+     */
+    pub fn add_pg_pool(&self, name: &str, pool: PgPool) {
+        self.add_check(name, move || {
+            let pool = pool.clone();
+            async move {
+                let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+                conn.ping().await.map_err(|e| e.to_string())
+            }
+        });
+    }
+
+    /**
+     * 并发执行所有检查项
+     */
+    pub async fn report(&self) -> HealthReport {
+        let checks = self.checks.lock().unwrap().clone();
+
+        let results = join_all(checks.into_iter().map(|named| async move {
+            let NamedCheck { name, optional, check } = named;
+            let start = Instant::now();
+            let result = match tokio::time::timeout(CHECK_TIMEOUT, check()).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timeout after {:?}", CHECK_TIMEOUT)),
+            };
+            let latency_ms = start.elapsed().as_millis();
+
+            let check_result = match result {
+                Ok(_) => CheckResult {
+                    status: HealthStatus::Up,
+                    latency_ms,
+                    optional,
+                    error: None,
+                },
+                Err(e) => {
+                    warn!("readiness check {} failed: {}", name, e);
+                    CheckResult {
+                        status: HealthStatus::Down,
+                        latency_ms,
+                        optional,
+                        error: Some(e),
+                    }
+                }
+            };
+            (name, check_result)
+        }))
+        .await;
+
+        let checks: BTreeMap<String, CheckResult> = results.into_iter().collect();
+        //失败的检查项中只要有一个是必需的，服务就不可用
+        let failed = checks.values().filter(|c| c.status != HealthStatus::Up);
+        let status = match failed.map(|c| c.optional).min() {
+            None => HealthStatus::Up,
+            Some(true) => HealthStatus::Degraded,
+            Some(false) => HealthStatus::Down,
+        };
+
+        HealthReport { status, checks }
+    }
+}
+
+/**
+ * GET /health/live
+ */
+pub async fn live_handler() -> Json<HealthReport> {
+    Json(HealthReport {
+        status: HealthStatus::Up,
+        checks: BTreeMap::new(),
+    })
+}
+
+/**
+ * GET /health/ready
+ */
+pub async fn ready_handler(readiness: Readiness) -> (StatusCode, Json<HealthReport>) {
+    let report = readiness.report().await;
+    let code = if report.is_up() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report))
+}
+
+/**
+ * 健康检查的路由，合并到各个服务自己的路由中
+ */
+pub fn router<S>(readiness: Readiness) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/health/live", get(live_handler))
+        .route("/health/ready", get(move || ready_handler(readiness.clone())))
+}
+
+/**
+ * 创建grpc.health.v1.Health服务，返回的reporter交给report_grpc_health定期刷新状态
+ */
+pub fn grpc_health_service() -> (HealthReporter, HealthServer<impl Health>) {
+    tonic_health::server::health_reporter()
+}
+
+/**
+ * 定期执行就绪检查，并把结果同步为服务S和整个服务器（空服务名）的grpc健康状态，直到收到退出通知。
+ * 退出时标记为NOT_SERVING，让调用方尽快切走。
+ */
+pub async fn report_grpc_health<S: NamedService>(
+    readiness: Readiness,
+    mut reporter: HealthReporter,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(GRPC_STATUS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown.cancelled() => break,
+        }
+
+        let status = if readiness.report().await.is_up() {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        reporter.set_service_status(S::NAME, status).await;
+        reporter.set_service_status("", status).await;
+    }
+
+    reporter.set_not_serving::<S>().await;
+    reporter
+        .set_service_status("", ServingStatus::NotServing)
+        .await;
+}
+
+/**
+ * 定期执行就绪检查，并通过consul的TTL检查上报结果，直到收到退出通知。
+ * 上报间隔是ttl的三分之一，偶尔一次上报失败不会让检查超时。
+ * 可用时上报passing，降级时上报warning，不可用时上报critical，检查结果作为output可以在consul中查看。
+ * 退出时上报critical，让调用方在注销之前就不再使用这个实例。
+ */
+pub async fn report_ttl_check(
+    readiness: Readiness,
+    consul: Consul,
+    check_id: String,
+    ttl: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(ttl / 3);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown.cancelled() => break,
+        }
+
+        let report = readiness.report().await;
+        let status = match report.status {
+            HealthStatus::Up => CheckStatus::Passing,
+            HealthStatus::Degraded => CheckStatus::Warning,
+            HealthStatus::Down => CheckStatus::Critical,
+        };
+        let output = serde_json::to_string(&report).unwrap_or_default();
+        if let Err(e) = consul.update_ttl_check(&check_id, status, &output).await {
+            warn!("update ttl check {} failed: {}", check_id, e);
+        }
+    }
+
+    if let Err(e) = consul
+        .update_ttl_check(&check_id, CheckStatus::Critical, "shutting down")
+        .await
+    {
+        warn!("update ttl check {} failed: {}", check_id, e);
+    }
+}
+
+/**
+ * 从服务发现中取出下游服务的实例，逐个通过grpc健康检查协议检查，至少有一个实例可以正常提供服务时返回Ok。
+ * 实例登记了grpc地址时使用grpc地址。
+ * srv_name 服务发现中的服务名，比如inventory-srv
+ * service 要检查的grpc服务全名，比如inventory.InventoryService
+ */
+pub async fn check_srv_health(
+    discovery: Arc<dyn ServiceDiscovery>,
+    srv_name: &str,
+    service: &str,
+) -> Result<(), String> {
+    let instances = discovery.instances(srv_name);
+
+    let mut last_err = format!("cannot found {} from service discovery.", srv_name);
+    for srv in instances.iter() {
+        match check_grpc_health(srv.tagged_endpoint("grpc", "http"), service).await {
+            Ok(_) => return Ok(()),
+            Err(e) => last_err = format!("{}: {}", srv.id, e),
+        }
+    }
+    Err(last_err)
+}
+
+/**
+ * 使用grpc.health.v1.Health协议检查下游grpc服务是否可用
+ * uri 服务地址，比如http://127.0.0.1:3001
+ * service 要检查的服务全名，比如inventory.InventoryService，为空时检查整个服务器
+ */
+pub async fn check_grpc_health(uri: String, service: &str) -> Result<(), String> {
+    let channel = Channel::from_shared(uri)
+        .map_err(|e| e.to_string())?
+        .connect_timeout(CHECK_TIMEOUT)
+        .connect()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let response = HealthClient::new(channel)
+        .check(HealthCheckRequest {
+            service: service.to_string(),
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .into_inner();
+
+    if response.status == ProtoServingStatus::Serving as i32 {
+        Ok(())
+    } else {
+        Err(format!("{} is not serving.", service))
+    }
+}
@@ -1,3 +1,17 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod admin;
+pub mod bootstrap;
+pub mod dynamic_config;
+pub mod health;
+pub mod maintenance;
+pub mod metrics;
+pub mod multiplex;
+pub mod pagination;
+pub mod request_id;
+pub mod telemetry;
+
 use validator::{Validate, ValidationErrors};
 
 use http::StatusCode;
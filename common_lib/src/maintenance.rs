@@ -0,0 +1,416 @@
+/**
+ * 维护模式和连接排空。
+ *
+ * 发布之前先把实例切到维护模式，让它退出流量：
+ * - PUT /admin/maintenance?reason=deploy 开启维护模式，DELETE /admin/maintenance 关闭
+ * - GET /admin/maintenance 查看是否在维护中，以及还有多少个处理中的rest/grpc请求
+ *
+ * 管理接口只有环境变量MAINTENANCE_ADMIN_IDS（逗号分隔的用户id）中的用户可以调用，请求头中需要带上certify_server签发的jwt。
+ *
+ * 维护期间就绪检查返回不可用，注册了consul的服务同时通过agent的维护接口把实例标记为critical，
+ * 调用方不再发现这个实例，新的流量也就不会再过来。处理中的请求数降为0之后（drained），就可以放心地停掉服务。
+ * 服务收到退出信号时同样会先进入维护模式，等待处理中的请求完成之后才关闭服务器。
+ */
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::HttpBody,
+    extract::Query,
+    http::{HeaderMap, Request, Response},
+    routing::get,
+    Json, Router,
+};
+use consul_reg_lib::consul::Consul;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Notify};
+use tokio_util::sync::CancellationToken;
+use tower::Service;
+use tracing::{info, warn};
+
+use crate::admin::{Admin, AdminRole};
+
+/**
+ * 管理接口的路径前缀，这些请求不计入处理中的请求数
+ */
+pub const ADMIN_PATH: &str = "/admin/";
+
+const DEFAULT_REASON: &str = "maintenance";
+
+
+struct Inner {
+    reason: watch::Sender<Option<String>>,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/**
+ * 维护模式的状态和处理中的请求数，clone出来的实例共享同一份状态
+ */
+#[derive(Clone)]
+pub struct Maintenance {
+    inner: Arc<Inner>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub in_flight: usize,
+    pub drained: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MaintenanceRequest {
+    pub reason: Option<String>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                reason: watch::channel(None).0,
+                in_flight: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * 开启维护模式，已经在维护中时只更新原因
+     */
+    pub fn enable(&self, reason: &str) {
+        info!("enter maintenance mode: {}", reason);
+        self.inner.reason.send_replace(Some(reason.to_string()));
+    }
+
+    pub fn disable(&self) {
+        info!("leave maintenance mode.");
+        self.inner.reason.send_replace(None);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.reason.borrow().is_some()
+    }
+
+    /**
+     * 维护的原因，不在维护中时返回None
+     */
+    pub fn reason(&self) -> Option<String> {
+        self.inner.reason.borrow().clone()
+    }
+
+    /**
+     * 处理中的rest和grpc请求数
+     */
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        let in_flight = self.in_flight();
+        MaintenanceStatus {
+            enabled: self.is_enabled(),
+            reason: self.reason(),
+            in_flight,
+            drained: in_flight == 0,
+        }
+    }
+
+    /**
+     * 等待处理中的请求全部完成，最多等待timeout，返回是否已经排空
+     */
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let drained = async {
+            loop {
+                //先登记等待再检查，避免错过检查之后、等待之前的通知
+                let idle = self.inner.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        tokio::time::timeout(timeout, drained).await.is_ok()
+    }
+
+    fn track(&self) -> InFlightGuard {
+        self.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/**
+ * 请求处理完成（响应体发送完或者请求被取消）时减少处理中的请求数
+ */
+struct InFlightGuard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.inner.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/**
+ * 统计处理中的请求数，包在rest和grpc合并之后的服务外面，由ServiceBuilder::serve自动添加。
+ * 计数跟着响应体走，流式和较大的响应要等响应体发送完（或者连接断开）才算处理完成。
+ * 管理接口的请求不计入，否则查询排空状态的请求自己就会让服务永远排不空。
+ */
+#[derive(Clone)]
+pub struct TrackInFlight<S> {
+    inner: S,
+    maintenance: Maintenance,
+}
+
+impl<S> TrackInFlight<S> {
+    pub fn new(inner: S, maintenance: Maintenance) -> Self {
+        Self { inner, maintenance }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TrackInFlight<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<InFlightBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let guard = (!req.uri().path().starts_with(ADMIN_PATH)).then(|| self.maintenance.track());
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let res = future.await?;
+            Ok(res.map(|inner| InFlightBody {
+                inner,
+                _guard: guard,
+            }))
+        })
+    }
+}
+
+/**
+ * 带着处理中计数的响应体，hyper发送完响应体之后丢弃它，计数随之减少
+ */
+pub struct InFlightBody<B> {
+    inner: B,
+    _guard: Option<InFlightGuard>,
+}
+
+impl<B> HttpBody for InFlightBody<B>
+where
+    B: HttpBody + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/**
+ * 可以调用维护接口的用户，没有配置MAINTENANCE_ADMIN_IDS时任何人都不能调用
+ */
+#[derive(Debug, Clone)]
+pub struct MaintenanceRole;
+
+impl AdminRole for MaintenanceRole {
+    const ENV: &'static str = "MAINTENANCE_ADMIN_IDS";
+    const NAME: &'static str = "maintenance admin";
+}
+
+pub type MaintenanceAdmin = Admin<MaintenanceRole>;
+
+/**
+ * GET /admin/maintenance
+ */
+pub async fn status_handler(maintenance: Maintenance) -> Json<MaintenanceStatus> {
+    Json(maintenance.status())
+}
+
+/**
+ * PUT /admin/maintenance?reason=xxx
+ */
+pub async fn enable_handler(
+    maintenance: Maintenance,
+    admin: MaintenanceAdmin,
+    request: MaintenanceRequest,
+) -> Json<MaintenanceStatus> {
+    let reason = request
+        .reason
+        .filter(|r| !r.is_empty())
+        .unwrap_or_else(|| DEFAULT_REASON.to_string());
+    info!("admin {} enables maintenance mode.", admin.id);
+    maintenance.enable(&reason);
+    Json(maintenance.status())
+}
+
+/**
+ * DELETE /admin/maintenance
+ */
+pub async fn disable_handler(
+    maintenance: Maintenance,
+    admin: MaintenanceAdmin,
+) -> Json<MaintenanceStatus> {
+    info!("admin {} disables maintenance mode.", admin.id);
+    maintenance.disable();
+    Json(maintenance.status())
+}
+
+/**
+ * 维护模式的管理路由，合并到各个服务自己的路由中。
+ * 所有请求都需要MaintenanceAdmin，另外只应该在内网访问，不要通过网关暴露出去。
+ */
+pub fn router<S>(maintenance: Maintenance) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    if MaintenanceRole::ids().is_empty() {
+        warn!("MAINTENANCE_ADMIN_IDS is empty, maintenance endpoints are disabled.");
+    }
+    let status = maintenance.clone();
+    let enable = maintenance.clone();
+    Router::new().route(
+        "/admin/maintenance",
+        get(move |_admin: MaintenanceAdmin| status_handler(status.clone()))
+            .put(
+                move |admin: MaintenanceAdmin, Query(request): Query<MaintenanceRequest>| {
+                    enable_handler(enable.clone(), admin, request)
+                },
+            )
+            .delete(move |admin: MaintenanceAdmin| disable_handler(maintenance.clone(), admin)),
+    )
+}
+
+/**
+ * 把维护模式同步到consul agent上，直到收到退出通知。
+ * 同步失败时只记录日志，维护期间就绪检查本身也是失败的，实例仍然会被consul摘除，只是稍慢一些。
+ */
+pub async fn sync_consul(
+    maintenance: Maintenance,
+    consul: Consul,
+    service_id: String,
+    shutdown: CancellationToken,
+) {
+    let mut reason = maintenance.inner.reason.subscribe();
+    //启动之前就已经开启的维护模式也要同步
+    let mut synced: Option<String> = None;
+    loop {
+        let current = reason.borrow_and_update().clone();
+        if current != synced {
+            let result = match &current {
+                Some(r) => consul.enable_maintenance(&service_id, r).await,
+                None => consul.disable_maintenance(&service_id).await,
+            };
+            match result {
+                Ok(_) => info!("sync maintenance mode of {} to consul: {:?}", service_id, current),
+                Err(e) => warn!("sync maintenance mode of {} failed: {}", service_id, e),
+            }
+            synced = current;
+        }
+
+        tokio::select! {
+            changed = reason.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            },
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight_requests() {
+        let maintenance = Maintenance::new();
+        assert!(maintenance.drain(Duration::from_millis(10)).await);
+
+        let guard = maintenance.track();
+        assert_eq!(maintenance.in_flight(), 1);
+        assert!(!maintenance.drain(Duration::from_millis(10)).await);
+
+        let waiting = maintenance.clone();
+        let drain = tokio::spawn(async move { waiting.drain(Duration::from_secs(5)).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(guard);
+        assert!(drain.await.unwrap());
+        assert!(maintenance.status().drained);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_until_body_finished() {
+        use axum::body::Body;
+        use tower::{service_fn, ServiceExt};
+
+        let maintenance = Maintenance::new();
+        let (mut sender, body) = Body::channel();
+        let mut body = Some(body);
+        let service = service_fn(move |_: Request<Body>| {
+            let body = body.take().unwrap();
+            async move { Ok::<_, std::convert::Infallible>(Response::new(body)) }
+        });
+
+        let req = Request::builder().uri("/goods").body(Body::empty()).unwrap();
+        let mut res = TrackInFlight::new(service, maintenance.clone())
+            .oneshot(req)
+            .await
+            .unwrap();
+        //响应头已经返回，响应体还在发送
+        assert_eq!(maintenance.in_flight(), 1);
+
+        sender.send_data("chunk".into()).await.unwrap();
+        drop(sender);
+        while res.body_mut().data().await.is_some() {}
+        drop(res);
+        assert_eq!(maintenance.in_flight(), 0);
+    }
+}
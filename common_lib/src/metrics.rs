@@ -0,0 +1,380 @@
+/**
+ * Prometheus指标。
+ *
+ * 所有微服务使用同样的指标名和标签，注册表上带有service常量标签，这样一个dashboard就能看全部服务：
+ * - http_requests_total / http_request_duration_seconds：method、route、status
+ * - grpc_requests_total / grpc_request_duration_seconds：grpc_method、grpc_code
+ * - db_pool_connections：pool、state(idle/active)
+ *
+ * 各服务自己的业务指标通过register注册到同一个注册表中。
+ */
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Mutex, OnceLock},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::{
+    body::HttpBody,
+    extract::MatchedPath,
+    http::{header::CONTENT_TYPE, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::{future::BoxFuture, ready};
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use sqlx::PgPool;
+use tonic::Code;
+use tower::Service;
+
+static SERVICE_NAME: OnceLock<String> = OnceLock::new();
+static POOLS: Mutex<Vec<(String, PgPool)>> = Mutex::new(Vec::new());
+
+lazy_static! {
+    static ref REGISTRY: Registry = {
+        let service = SERVICE_NAME.get().cloned().unwrap_or_else(|| "unknown".to_string());
+        Registry::new_custom(None, Some(HashMap::from([("service".to_string(), service)])))
+            .expect("create metrics registry failed")
+    };
+    static ref HTTP_REQUESTS: IntCounterVec = register_metric(
+        IntCounterVec::new(
+            Opts::new("http_requests_total", "Total number of http requests."),
+            &["method", "route", "status"],
+        )
+        .unwrap()
+    );
+    static ref HTTP_DURATION: HistogramVec = register_metric(
+        HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Http request latency in seconds."),
+            &["method", "route"],
+        )
+        .unwrap()
+    );
+    static ref GRPC_REQUESTS: IntCounterVec = register_metric(
+        IntCounterVec::new(
+            Opts::new("grpc_requests_total", "Total number of grpc requests."),
+            &["grpc_method", "grpc_code"],
+        )
+        .unwrap()
+    );
+    static ref GRPC_DURATION: HistogramVec = register_metric(
+        HistogramVec::new(
+            HistogramOpts::new("grpc_request_duration_seconds", "Grpc request latency in seconds."),
+            &["grpc_method"],
+        )
+        .unwrap()
+    );
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_metric(
+        IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Connections of the sqlx pool."),
+            &["pool", "state"],
+        )
+        .unwrap()
+    );
+}
+
+fn register_metric<C: Collector + Clone + 'static>(collector: C) -> C {
+    register(Box::new(collector.clone()));
+    collector
+}
+
+/**
+ * 设置service标签的值，需要在任何指标被使用之前调用。ServiceBuilder::new中会自动调用。
+ */
+pub fn init(service: &str) {
+    let _ = SERVICE_NAME.set(service.to_string());
+}
+
+/**
+ * 注册服务自己的业务指标
+ */
+pub fn register(collector: Box<dyn Collector>) {
+    if let Err(e) = REGISTRY.register(collector) {
+        tracing::error!("register metric failed: {}", e);
+    }
+}
+
+/**
+ * 记录一个连接池，每次抓取指标时刷新它的连接数
+ */
+pub fn watch_pool(name: &str, pool: PgPool) {
+    POOLS.lock().unwrap().push((name.to_string(), pool));
+}
+
+/**
+ * GET /metrics
+ */
+pub async fn metrics_handler() -> Response {
+    for (name, pool) in POOLS.lock().unwrap().iter() {
+        let idle = pool.num_idle() as i64;
+        let size = pool.size() as i64;
+        DB_POOL_CONNECTIONS.with_label_values(&[name, "idle"]).set(idle);
+        DB_POOL_CONNECTIONS
+            .with_label_values(&[name, "active"])
+            .set(size - idle);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response()
+}
+
+/**
+ * 统计http请求的中间件，使用route_layer挂载，这样才能拿到匹配到的路由模板作为route标签，
+ * 避免路径参数导致标签数量爆炸。
+ */
+pub async fn track_http<B>(req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(req).await;
+
+    let status = response.status().as_u16().to_string();
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, &status])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/**
+ * 统计grpc请求的包装服务，grpc_method标签为请求路径，比如/inventory.InventoryService/deductionInventory。
+ * 提前失败的响应（trailers-only）grpc-status在header中，正常返回时tonic把它放在trailers中，
+ * 所以要等响应体发送完、读到trailers之后才记录，耗时也包含发送响应体的时间。
+ */
+#[derive(Clone)]
+pub struct GrpcMetrics<S> {
+    inner: S,
+}
+
+impl<S> GrpcMetrics<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GrpcMetrics<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<GrpcMetricsBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let record = GrpcRecord {
+            grpc_method: req.uri().path().to_string(),
+            start: Instant::now(),
+        };
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            match future.await {
+                Ok(res) => {
+                    let (parts, body) = res.into_parts();
+                    let record = match grpc_status(&parts.headers) {
+                        Some(code) => {
+                            record.finish(&code);
+                            None
+                        }
+                        None => Some(record),
+                    };
+                    Ok(Response::from_parts(parts, GrpcMetricsBody { inner: body, record }))
+                }
+                Err(e) => {
+                    record.finish(&code_label(Code::Unavailable));
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+struct GrpcRecord {
+    grpc_method: String,
+    start: Instant,
+}
+
+impl GrpcRecord {
+    fn finish(self, code: &str) {
+        GRPC_REQUESTS
+            .with_label_values(&[&self.grpc_method, code])
+            .inc();
+        GRPC_DURATION
+            .with_label_values(&[&self.grpc_method])
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/**
+ * grpc_code标签统一使用数字状态码，和grpc-status中的值一致
+ */
+fn code_label(code: Code) -> String {
+    (code as i32).to_string()
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/**
+ * 读到trailers时记录grpc-status，响应体没有发送完就被丢弃（客户端断开）时记为1(CANCELLED)
+ */
+pub struct GrpcMetricsBody<B> {
+    inner: B,
+    record: Option<GrpcRecord>,
+}
+
+impl<B> HttpBody for GrpcMetricsBody<B>
+where
+    B: HttpBody + Unpin,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let result = ready!(Pin::new(&mut self.inner).poll_trailers(cx));
+        if let Some(record) = self.record.take() {
+            let code = match &result {
+                Ok(trailers) => trailers
+                    .as_ref()
+                    .and_then(grpc_status)
+                    .unwrap_or_else(|| code_label(Code::Unknown)),
+                Err(_) => code_label(Code::Unavailable),
+            };
+            record.finish(&code);
+        }
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for GrpcMetricsBody<B> {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            record.finish(&code_label(Code::Cancelled));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn grpc_count(grpc_method: &str, code: &str) -> u64 {
+        GRPC_REQUESTS.with_label_values(&[grpc_method, code]).get()
+    }
+
+    #[tokio::test]
+    async fn test_grpc_code_is_read_from_trailers() {
+        let method = "/test.TestService/trailers";
+        let (mut sender, body) = Body::channel();
+        let mut body = Some(body);
+        let service = GrpcMetrics::new(service_fn(move |_: Request<Body>| {
+            let body = body.take().unwrap();
+            async move { Ok::<_, std::convert::Infallible>(Response::new(body)) }
+        }));
+
+        let req = Request::builder().uri(method).body(Body::empty()).unwrap();
+        let mut res = service.oneshot(req).await.unwrap();
+        //响应头返回时还不知道状态码
+        assert_eq!(grpc_count(method, "5"), 0);
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "5".parse().unwrap());
+        sender.send_trailers(trailers).await.unwrap();
+        drop(sender);
+        while res.body_mut().data().await.is_some() {}
+        res.body_mut().trailers().await.unwrap();
+        assert_eq!(grpc_count(method, "5"), 1);
+
+        //trailers中的状态码只记录一次
+        drop(res);
+        assert_eq!(grpc_count(method, "5"), 1);
+        assert_eq!(grpc_count(method, "1"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_code_in_headers_and_cancelled_body() {
+        let method = "/test.TestService/headers";
+        let service = service_fn(|req: Request<Body>| async move {
+            let mut res = Response::new(Body::empty());
+            if req.headers().contains_key("fail") {
+                res.headers_mut().insert("grpc-status", "3".parse().unwrap());
+            }
+            Ok::<_, std::convert::Infallible>(res)
+        });
+
+        let req = Request::builder().uri(method).header("fail", "1").body(Body::empty()).unwrap();
+        let res = GrpcMetrics::new(service).oneshot(req).await.unwrap();
+        assert_eq!(grpc_count(method, "3"), 1);
+        drop(res);
+        assert_eq!(grpc_count(method, "1"), 0);
+
+        //没有读到trailers就丢弃响应体
+        let req = Request::builder().uri(method).body(Body::empty()).unwrap();
+        let res = GrpcMetrics::new(service).oneshot(req).await.unwrap();
+        drop(res);
+        assert_eq!(grpc_count(method, "1"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_code_without_status_is_numeric() {
+        let method = "/test.TestService/no_status";
+        let service = service_fn(|_: Request<Body>| async move {
+            Ok::<_, std::convert::Infallible>(Response::new(Body::empty()))
+        });
+
+        //没有grpc-status的trailers记为2(UNKNOWN)
+        let req = Request::builder().uri(method).body(Body::empty()).unwrap();
+        let mut res = GrpcMetrics::new(service).oneshot(req).await.unwrap();
+        res.body_mut().trailers().await.unwrap();
+        assert_eq!(grpc_count(method, "2"), 1);
+        assert_eq!(grpc_count(method, "unknown"), 0);
+        assert_eq!(code_label(Code::Unavailable), "14");
+    }
+}
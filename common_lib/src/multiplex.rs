@@ -1,16 +1,19 @@
-/**
- * 拷贝自axum官方 rest-grpc-multiplex 示例
- */
-
-use axum::{body::BoxBody, http::header::CONTENT_TYPE, response::IntoResponse};
+use axum::{
+    body::{Body, BoxBody},
+    http::{header::CONTENT_TYPE, Request, Response},
+    response::IntoResponse,
+};
 use futures::{future::BoxFuture, ready};
-use hyper::{Body, Request, Response};
 use std::{
     convert::Infallible,
     task::{Context, Poll},
 };
 use tower::Service;
 
+/**
+ * REST和grpc共用一个端口：content-type为application/grpc的请求交给grpc服务，其他的交给REST路由。
+ * 拷贝自axum官方 rest-grpc-multiplex 示例
+ */
 pub struct MultiplexService<A, B> {
     rest: A,
     rest_ready: bool,
@@ -116,3 +119,17 @@ fn is_grpc_request<B>(req: &Request<B>) -> bool {
         .filter(|content_type| content_type.starts_with(b"application/grpc"))
         .is_some()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_grpc_request() {
+        let request = |content_type: &str| Request::builder().header(CONTENT_TYPE, content_type).body(()).unwrap();
+        assert!(is_grpc_request(&request("application/grpc")));
+        assert!(is_grpc_request(&request("application/grpc+proto")));
+        assert!(!is_grpc_request(&request("application/json")));
+        assert!(!is_grpc_request(&Request::new(())));
+    }
+}
@@ -0,0 +1,209 @@
+/**
+ * 分页。
+ *
+ * 使用keyset分页代替LIMIT/OFFSET：按自增主键排序，下一页从上一页最后一条记录的主键之后开始查，
+ * 这样翻到很深的页也能走索引，并且翻页期间有新数据插入时结果也是稳定的。
+ * 不按主键排序的列表（比如按相关度、价格排序）使用 (排序值, 主键) 作为游标，见encode_keyset_cursor。
+ * 游标对调用方是不透明的字符串，每页大小有上限。
+ */
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+/**
+ * cursor 上一页返回的next_cursor，第一页不传或者传空字符串
+ * page_size 每页大小，默认DEFAULT_PAGE_SIZE，最大MAX_PAGE_SIZE
+ * with_total 是否需要返回总数，总数需要额外的count查询
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PageRequest {
+    #[serde(default, deserialize_with = "non_empty_cursor")]
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+    #[serde(default)]
+    pub with_total: bool,
+}
+
+impl PageRequest {
+    pub fn new(cursor: Option<String>, page_size: Option<i64>, with_total: bool) -> Self {
+        Self {
+            cursor: cursor.filter(|c| !c.is_empty()),
+            page_size,
+            with_total,
+        }
+    }
+
+    /**
+     * 本页实际使用的大小
+     */
+    pub fn limit(&self) -> i64 {
+        self.page_size
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .min(MAX_PAGE_SIZE)
+    }
+
+    /**
+     * 查询时使用的LIMIT，多查一条用来判断是否还有下一页
+     */
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit() + 1
+    }
+
+    /**
+     * 游标中记录的上一页最后一条记录的主键，第一页时返回None
+     */
+    pub fn after(&self) -> Result<Option<i64>, (StatusCode, String)> {
+        self.cursor.as_deref().map(decode_cursor).transpose()
+    }
+}
+
+/**
+ * 反序列化游标时把空字符串当作没有游标，和PageRequest::new一致，?cursor=表示第一页
+ */
+pub fn non_empty_cursor<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let cursor = Option::<String>::deserialize(deserializer)?;
+    Ok(cursor.filter(|c| !c.is_empty()))
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub total: Option<i64>,
+}
+
+impl<T> Page<T> {
+    /**
+     * rows 按fetch_limit查询出来的结果，key 取出记录的排序主键
+     */
+    pub fn from_rows<F>(rows: Vec<T>, request: &PageRequest, key: F) -> Self
+    where
+        F: Fn(&T) -> i64,
+    {
+        Self::from_rows_by(rows, request, |last| encode_cursor(key(last)))
+    }
+
+    /**
+     * 同from_rows，cursor 由本页最后一条记录生成游标
+     */
+    pub fn from_rows_by<F>(mut rows: Vec<T>, request: &PageRequest, cursor: F) -> Self
+    where
+        F: Fn(&T) -> String,
+    {
+        let limit = request.limit() as usize;
+        let next_cursor = if rows.len() > limit {
+            rows.truncate(limit);
+            rows.last().map(cursor)
+        } else {
+            None
+        };
+
+        Self {
+            items: rows,
+            next_cursor,
+            total: None,
+        }
+    }
+
+    pub fn with_total(mut self, total: Option<i64>) -> Self {
+        self.total = total;
+        self
+    }
+
+    pub fn map<U, F>(self, f: F) -> Page<U>
+    where
+        F: FnMut(T) -> U,
+    {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+            total: self.total,
+        }
+    }
+}
+
+pub fn encode_cursor(key: i64) -> String {
+    URL_SAFE_NO_PAD.encode(key.to_string())
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<i64, (StatusCode, String)> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|key| key.parse().ok())
+        .ok_or((StatusCode::BAD_REQUEST, "illegal cursor.".to_string()))
+}
+
+/**
+ * 由多个字段组成的游标，比如 (排序值, 主键)
+ */
+pub fn encode_keyset_cursor<K: Serialize>(key: &K) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(key).unwrap_or_default())
+}
+
+pub fn decode_keyset_cursor<K: DeserializeOwned>(cursor: &str) -> Result<K, (StatusCode, String)> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or((StatusCode::BAD_REQUEST, "illegal cursor.".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_is_clamped() {
+        assert_eq!(PageRequest::default().limit(), DEFAULT_PAGE_SIZE);
+        assert_eq!(PageRequest::new(None, Some(0), false).limit(), DEFAULT_PAGE_SIZE);
+        assert_eq!(PageRequest::new(None, Some(5), false).limit(), 5);
+        assert_eq!(PageRequest::new(None, Some(100000), false).limit(), MAX_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = encode_cursor(42);
+        assert_eq!(decode_cursor(&cursor), Ok(42));
+        assert!(decode_cursor("not a cursor").is_err());
+        assert_eq!(PageRequest::new(Some("".to_string()), None, false).after(), Ok(None));
+
+        let cursor = encode_keyset_cursor(&(0.5f64, 7));
+        assert_eq!(decode_keyset_cursor::<(f64, i32)>(&cursor), Ok((0.5, 7)));
+        assert!(decode_keyset_cursor::<(f64, i32)>(&encode_cursor(42)).is_err());
+    }
+
+    #[test]
+    fn test_query_with_empty_cursor() {
+        let query = |uri: &str| {
+            axum::extract::Query::<PageRequest>::try_from_uri(&uri.parse().unwrap())
+                .unwrap()
+                .0
+        };
+        assert_eq!(query("/goods_list?cursor=").after(), Ok(None));
+        assert_eq!(query("/goods_list?cursor=&page_size=5").limit(), 5);
+        assert_eq!(query("/goods_list").cursor, None);
+        let cursor = encode_cursor(42);
+        assert_eq!(query(&format!("/goods_list?cursor={}", cursor)).after(), Ok(Some(42)));
+    }
+
+    #[test]
+    fn test_page_from_rows() {
+        let request = PageRequest::new(None, Some(2), false);
+        let page = Page::from_rows(vec![1, 2, 3], &request, |id| *id);
+        assert_eq!(page.items, vec![1, 2]);
+        assert_eq!(page.next_cursor, Some(encode_cursor(2)));
+
+        let last = Page::from_rows(vec![3], &request, |id| *id);
+        assert_eq!(last.items, vec![3]);
+        assert_eq!(last.next_cursor, None);
+    }
+}
@@ -0,0 +1,121 @@
+/**
+ * 请求id。
+ *
+ * 入口处从x-request-id请求头中取出请求id，没有的话生成一个新的，并在响应头中返回。
+ * 请求id会记录在span上，所以处理这个请求时打印的每一行日志都会带上它；
+ * 同时保存在task local中，调用下游grpc服务时由客户端拦截器写入metadata，
+ * 这样就能用同一个id把certify、order、inventory的日志串起来。
+ */
+use std::task::{Context, Poll};
+
+use axum::{
+    http::{HeaderMap, HeaderValue, Request, Response},
+    middleware::Next,
+};
+use futures::future::BoxFuture;
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tower::Service;
+use tracing::{info_span, Instrument, Span};
+use uuid::Uuid;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/**
+ * 当前请求的id，不在请求处理过程中（比如定时任务）时返回None
+ */
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/**
+ * 取出请求头中的请求id，不存在或者不合法时生成一个新的
+ */
+fn request_id_from(headers: &HeaderMap) -> String {
+    headers
+        .get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+fn request_id_span(id: &str) -> Span {
+    info_span!("request_id", request_id = %id)
+}
+
+fn set_response_header<B>(response: &mut Response<B>, id: &str) {
+    if let Ok(value) = HeaderValue::from_str(id) {
+        response.headers_mut().insert(X_REQUEST_ID, value);
+    }
+}
+
+/**
+ * rest请求的请求id中间件，需要挂在最外层
+ */
+pub async fn request_id_http<B>(req: Request<B>, next: Next<B>) -> axum::response::Response {
+    let id = request_id_from(req.headers());
+    let span = request_id_span(&id);
+
+    let mut response = REQUEST_ID
+        .scope(id.clone(), next.run(req).instrument(span))
+        .await;
+    set_response_header(&mut response, &id);
+    response
+}
+
+/**
+ * grpc服务端的请求id包装，请求id从metadata（即http2请求头）中读取
+ */
+#[derive(Clone)]
+pub struct GrpcRequestId<S> {
+    inner: S,
+}
+
+impl<S> GrpcRequestId<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for GrpcRequestId<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let id = request_id_from(req.headers());
+        let span = request_id_span(&id);
+
+        let future = span.in_scope(|| self.inner.call(req));
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let mut result = future.instrument(span).await;
+            if let Ok(response) = &mut result {
+                set_response_header(response, &id);
+            }
+            result
+        }))
+    }
+}
+
+/**
+ * 把当前请求id写入下游grpc请求的metadata中
+ */
+pub fn inject(metadata: &mut MetadataMap) {
+    if let Some(id) = current() {
+        if let Ok(value) = MetadataValue::try_from(id) {
+            metadata.insert(X_REQUEST_ID, value);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/**
+ * OpenTelemetry分布式追踪。
+ *
+ * 使用W3C traceparent在服务之间传递追踪上下文：
+ * - rest入口使用trace_http中间件从请求头中取出上下文
+ * - grpc服务端使用GrpcTracing包装，grpc客户端使用inject_trace_context拦截器写入metadata（同时写入请求id）
+ *
+ * 设置了环境变量OTEL_EXPORTER_OTLP_ENDPOINT（比如http://127.0.0.1:4317）之后才会通过OTLP导出到collector。
+ */
+use std::{
+    env,
+    task::{Context, Poll},
+};
+
+use axum::{
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use futures::future::BoxFuture;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tower::Service;
+use tracing::{info_span, Instrument, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::request_id;
+
+/**
+ * 创建导出到OTLP collector的tracing layer，没有配置collector地址时返回None。
+ */
+pub fn otel_layer<S>(service: &str) -> Option<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            service.to_string(),
+        )])))
+        .install_batch(opentelemetry::runtime::Tokio);
+
+    match tracer {
+        Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(e) => {
+            eprintln!("install otlp tracer failed: {}", e);
+            None
+        }
+    }
+}
+
+/**
+ * 退出前把还没有导出的span全部发送出去
+ */
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl<'a> Injector for MetadataInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+/**
+ * 以请求头中的traceparent作为父节点创建span
+ */
+fn server_span(name: &'static str, method: &str, path: &str, headers: &HeaderMap) -> Span {
+    let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+    let span = info_span!(
+        "request",
+        otel.name = name,
+        otel.kind = "server",
+        http.method = %method,
+        http.target = %path,
+    );
+    span.set_parent(parent);
+    span
+}
+
+/**
+ * rest请求的追踪中间件
+ */
+pub async fn trace_http<B>(req: Request<B>, next: Next<B>) -> Response {
+    let span = server_span(
+        "http_request",
+        req.method().as_str(),
+        req.uri().path(),
+        req.headers(),
+    );
+    next.run(req).instrument(span).await
+}
+
+/**
+ * grpc服务端的追踪包装
+ */
+#[derive(Clone)]
+pub struct GrpcTracing<S> {
+    inner: S,
+}
+
+impl<S> GrpcTracing<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for GrpcTracing<S>
+where
+    S: Service<Request<ReqBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let span = server_span(
+            "grpc_request",
+            req.method().as_str(),
+            req.uri().path(),
+            req.headers(),
+        );
+        let future = span.in_scope(|| self.inner.call(req));
+        Box::pin(future.instrument(span))
+    }
+}
+
+/**
+ * grpc客户端拦截器，把当前span的上下文和请求id写入请求metadata
+ */
+#[allow(clippy::result_large_err)] // 签名由tonic的Interceptor决定
+pub fn inject_trace_context(
+    mut req: tonic::Request<()>,
+) -> Result<tonic::Request<()>, tonic::Status> {
+    let cx = Span::current().context();
+    global::get_text_map_propagator(|p| p.inject_context(&cx, &mut MetadataInjector(req.metadata_mut())));
+    request_id::inject(req.metadata_mut());
+    Ok(req)
+}
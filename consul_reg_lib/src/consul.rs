@@ -1,67 +1,202 @@
-use std::time::Duration;
+use std::{fs, time::Duration};
 
-use super::model::{ConsulOption, Filter, Services, Service, Registration};
+use reqwest::{header::HeaderMap, Certificate, Identity, Method, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use tracing::warn;
 
+use super::{
+    error::ConsulError,
+    model::{
+        CheckStatus, CheckUpdate, ConsulOption, Filter, HealthService, KvPair, Registration, Service,
+        ServiceInstance, Services, SessionCreated, SessionRequest,
+    },
+};
 
+
+#[derive(Clone)]
 pub struct Consul {
     option: ConsulOption,
     client: reqwest::Client,
 }
 
 impl Consul {
-    pub fn newDefault() -> Result<Self, reqwest::Error> {
+    pub fn newDefault() -> Result<Self, ConsulError> {
         return Consul::new(ConsulOption::default());
     }
+
+    /**
+     * 使用环境变量中的配置创建客户端，见ConsulOption::from_env
+     */
+    pub fn from_env() -> Result<Self, ConsulError> {
+        Consul::new(ConsulOption::from_env())
+    }
     
-    pub fn new(option: ConsulOption) -> Result<Self, reqwest::Error> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(option.timeout_sec))
-            .build()?;
+    pub fn new(option: ConsulOption) -> Result<Self, ConsulError> {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(option.timeout_sec));
+
+        if let Some(ca_cert) = &option.ca_cert {
+            let pem = read_pem(ca_cert)?;
+            let cert = Certificate::from_pem(&pem)
+                .map_err(|e| ConsulError::Config(format!("illegal ca cert {}: {}", ca_cert, e)))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        match (&option.client_cert, &option.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8_pem(&read_pem(cert)?, &read_pem(key)?)
+                    .map_err(|e| ConsulError::Config(format!("illegal client cert {}: {}", cert, e)))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConsulError::Config(
+                    "client_cert and client_key should be set together.".to_string(),
+                ))
+            }
+        }
+
+        let client = builder
+            .build()
+            .map_err(|e| ConsulError::Config(e.to_string()))?;
         Ok(Self { option, client })
     }
 
-    fn api_url(&self, api_name: &str) -> String {
+    /**
+     * 本地agent的接口，只在请求的agent上生效，不能指定数据中心
+     */
+    fn agent(&self, method: Method, api_name: &str) -> RequestBuilder {
+        self.authorized(self.client.request(method, self.v1_url(&format!("agent/{}", api_name))))
+    }
+
+    /**
+     * 集群的接口（health、kv、session等），配置了数据中心时查询指定的数据中心
+     */
+    fn cluster(&self, method: Method, path: &str) -> RequestBuilder {
+        self.cluster_in(method, path, self.option.datacenter.as_deref())
+    }
+
+    /**
+     * 查询指定数据中心的集群接口，datacenter为None时查询本地agent所在的数据中心
+     */
+    fn cluster_in(&self, method: Method, path: &str, datacenter: Option<&str>) -> RequestBuilder {
+        let request = self.authorized(self.client.request(method, self.v1_url(path)));
+        match datacenter {
+            Some(dc) => request.query(&[("dc", dc)]),
+            None => request,
+        }
+    }
+
+    /**
+     * 查询服务时按顺序查找的数据中心，第一个是本数据中心（None表示本地agent所在的数据中心），
+     * 之后是配置的故障转移数据中心
+     */
+    pub fn datacenters(&self) -> Vec<Option<String>> {
+        let mut datacenters = vec![self.option.datacenter.clone()];
+        datacenters.extend(self.option.failover_datacenters.iter().cloned().map(Some));
+        datacenters
+    }
+
+    /**
+     * 带上ACL token和命名空间
+     */
+    fn authorized(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(token) = &self.option.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        if let Some(ns) = &self.option.namespace {
+            request = request.query(&[("ns", ns)]);
+        }
+        request
+    }
+
+    /**
+     * 把请求变成阻塞查询。
+     * consul会在wait的基础上再加最多wait/16的随机时间，请求超时需要比它更长
+     */
+    fn blocking(&self, request: RequestBuilder, index: u64, wait: Duration) -> RequestBuilder {
+        request
+            .query(&[("index", index.to_string()), ("wait", format!("{}s", wait.as_secs()))])
+            .timeout(wait + wait / 16 + Duration::from_secs(self.option.timeout_sec))
+    }
+
+    fn v1_url(&self, path: &str) -> String {
         format!(
-            "{}://{}/v1/agent/{}",
-            &self.option.protocol, &self.option.addr, api_name
+            "{}://{}/v1/{}",
+            &self.option.protocol, &self.option.addr, path
         )
     }
     
     
-    pub async fn register(&self, registration: &Registration) -> Result<(), reqwest::Error> {
-        self.client
-            .put(self.api_url("service/register"))
-            .json(registration)
-            .send()
-            .await?;
+    pub async fn register(&self, registration: &Registration) -> Result<(), ConsulError> {
+        send(self.agent(Method::PUT, "service/register").json(registration)).await?;
         Ok(())
     }
     
     
-    pub async fn deregister(&self, service_id: &str) -> Result<(), reqwest::Error> {
-        let deregister_api = format!("service/deregister/{}", service_id);
-        self.client
-            .put(self.api_url(&deregister_api))
-            .json(&())
-            .send()
-            .await?;
+    pub async fn deregister(&self, service_id: &str) -> Result<(), ConsulError> {
+        let deregister_api = format!("service/deregister/{}", urlencoding::encode(service_id));
+        send(self.agent(Method::PUT, &deregister_api).json(&())).await?;
         Ok(())
     }
     
     
-    pub async fn services(&self) -> Result<Services, reqwest::Error> {
-        let list: Services = self
-            .client
-            .get(self.api_url("services"))
-            .send()
-            .await?
-            .json()
-            .await?;
-        Ok(list)
+    /**
+     * 查询本地agent上注册的服务实例，agent上没有这个实例时返回None
+     */
+    pub async fn agent_service(&self, service_id: &str) -> Result<Option<Service>, ConsulError> {
+        let service_api = format!("service/{}", urlencoding::encode(service_id));
+        match send(self.agent(Method::GET, &service_api)).await {
+            Ok(response) => Ok(Some(decode(response).await?)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * 开启实例的维护模式，consul会给实例加上一个critical的检查，调用方不再发现这个实例。
+     * reason 维护的原因，会显示在检查结果中
+     */
+    pub async fn enable_maintenance(&self, service_id: &str, reason: &str) -> Result<(), ConsulError> {
+        self.maintenance(service_id, &[("enable", "true"), ("reason", reason)]).await
+    }
+
+    /**
+     * 关闭实例的维护模式
+     */
+    pub async fn disable_maintenance(&self, service_id: &str) -> Result<(), ConsulError> {
+        self.maintenance(service_id, &[("enable", "false")]).await
+    }
+
+    async fn maintenance(&self, service_id: &str, query: &[(&str, &str)]) -> Result<(), ConsulError> {
+        let maintenance_api = format!("service/maintenance/{}", urlencoding::encode(service_id));
+        send(self.agent(Method::PUT, &maintenance_api).query(query)).await?;
+        Ok(())
+    }
+
+    /**
+     * 上报ttl检查的状态，output会显示在consul的检查结果中
+     */
+    pub async fn update_ttl_check(
+        &self,
+        check_id: &str,
+        status: CheckStatus,
+        output: &str,
+    ) -> Result<(), ConsulError> {
+        let update_api = format!("check/update/{}", urlencoding::encode(check_id));
+        let update = CheckUpdate {
+            status,
+            output: output.to_string(),
+        };
+        send(self.agent(Method::PUT, &update_api).json(&update)).await?;
+        Ok(())
+    }
+
+    pub async fn services(&self) -> Result<Services, ConsulError> {
+        let response = send(self.agent(Method::GET, "services")).await?;
+        decode(response).await
     }
     
     
-    pub async fn get_service(&self, filter: &Filter) -> Result<Option<Service>, reqwest::Error> {
+    pub async fn get_service(&self, filter: &Filter) -> Result<Option<Service>, ConsulError> {
         let list = self.services().await?;
         for (_, s) in list {
             let has = match &filter {
@@ -74,6 +209,259 @@ impl Consul {
         }
         Ok(None)
     }
+
+    /**
+     * 查询服务所有通过了健康检查的实例。
+     * 和get_service不同，这里查询的是整个集群的目录，而不只是本地agent上注册的服务。
+     * 本数据中心没有健康实例时，按datacenters()的顺序到故障转移数据中心中查找，
+     * 返回第一个有健康实例的数据中心中的实例，实例的datacenter字段就是它所在的数据中心。
+     * 本数据中心查询失败时直接返回错误，故障转移数据中心查询失败时只记录日志，继续查找下一个。
+     */
+    pub async fn healthy_instances(&self, service_name: &str) -> Result<Vec<ServiceInstance>, ConsulError> {
+        let mut datacenters = self.datacenters().into_iter();
+        let local = datacenters.next().flatten();
+        let instances = self.healthy_instances_in(service_name, local.as_deref()).await?;
+        if !instances.is_empty() {
+            return Ok(instances);
+        }
+
+        for dc in datacenters.flatten() {
+            match self.healthy_instances_in(service_name, Some(&dc)).await {
+                Ok(instances) if !instances.is_empty() => {
+                    warn!("no healthy instance of {} in local datacenter, fail over to {}.", service_name, dc);
+                    return Ok(instances);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("query {} in datacenter {} failed: {}", service_name, dc, e),
+            }
+        }
+        Ok(vec![])
+    }
+
+    /**
+     * 查询服务在指定数据中心中的健康实例，datacenter为None时查询本地agent所在的数据中心
+     */
+    pub async fn healthy_instances_in(
+        &self,
+        service_name: &str,
+        datacenter: Option<&str>,
+    ) -> Result<Vec<ServiceInstance>, ConsulError> {
+        let (_, instances) = self
+            .query_healthy_instances(service_name, datacenter, None)
+            .await?;
+        Ok(instances)
+    }
+
+    /**
+     * 阻塞查询服务在指定数据中心中的健康实例。
+     * index 上一次查询返回的索引，服务的实例从那之后没有变化时，consul会等待最多wait时间才返回
+     * 返回新的索引和实例列表
+     */
+    pub async fn watch_healthy_instances(
+        &self,
+        service_name: &str,
+        datacenter: Option<&str>,
+        index: u64,
+        wait: Duration,
+    ) -> Result<(u64, Vec<ServiceInstance>), ConsulError> {
+        self.query_healthy_instances(service_name, datacenter, Some((index, wait)))
+            .await
+    }
+
+    async fn query_healthy_instances(
+        &self,
+        service_name: &str,
+        datacenter: Option<&str>,
+        blocking: Option<(u64, Duration)>,
+    ) -> Result<(u64, Vec<ServiceInstance>), ConsulError> {
+        let health_api = format!("health/service/{}", urlencoding::encode(service_name));
+        let mut request = self
+            .cluster_in(Method::GET, &health_api, datacenter)
+            .query(&[("passing", "true")]);
+        if let Some((index, wait)) = blocking {
+            request = self.blocking(request, index, wait);
+        }
+
+        let response = send(request).await?;
+        let index = consul_index(response.headers());
+        let list: Vec<HealthService> = decode(response).await?;
+        let instances = list
+            .into_iter()
+            .map(ServiceInstance::from)
+            .map(|mut instance| {
+                //老版本consul的节点信息中可能没有数据中心
+                if let (true, Some(dc)) = (instance.datacenter.is_empty(), datacenter) {
+                    instance.datacenter = dc.to_string();
+                }
+                instance
+            })
+            .collect();
+        Ok((index, instances))
+    }
+
+    /**
+     * 读取KV中的值，key不存在时返回None
+     */
+    pub async fn kv_get(&self, key: &str) -> Result<Option<Vec<u8>>, ConsulError> {
+        let (_, value) = self.query_kv(key, None).await?;
+        Ok(value)
+    }
+
+    /**
+     * 阻塞读取KV中的值，用法和watch_healthy_instances相同
+     */
+    pub async fn watch_kv(
+        &self,
+        key: &str,
+        index: u64,
+        wait: Duration,
+    ) -> Result<(u64, Option<Vec<u8>>), ConsulError> {
+        self.query_kv(key, Some((index, wait))).await
+    }
+
+    /**
+     * 写入KV，返回consul是否写入成功
+     */
+    pub async fn kv_put(&self, key: &str, value: &[u8]) -> Result<bool, ConsulError> {
+        let request = self
+            .cluster(Method::PUT, &format!("kv/{}", key))
+            .body(value.to_vec());
+        decode(send(request).await?).await
+    }
+
+    pub async fn kv_delete(&self, key: &str) -> Result<(), ConsulError> {
+        send(self.cluster(Method::DELETE, &format!("kv/{}", key))).await?;
+        Ok(())
+    }
+
+    async fn query_kv(
+        &self,
+        key: &str,
+        blocking: Option<(u64, Duration)>,
+    ) -> Result<(u64, Option<Vec<u8>>), ConsulError> {
+        let mut request = self
+            .cluster(Method::GET, &format!("kv/{}", key))
+            .query(&[("raw", "true")]);
+        if let Some((index, wait)) = blocking {
+            request = self.blocking(request, index, wait);
+        }
+
+        //key不存在时consul返回404，同样带有索引，可以继续阻塞等待它被创建
+        let response = match send(request).await {
+            Err(e) if e.is_not_found() => return Ok((e.index(), None)),
+            result => result?,
+        };
+        let index = consul_index(response.headers());
+        let value = response.bytes().await.map_err(ConsulError::Transport)?;
+        Ok((index, Some(value.to_vec())))
+    }
+
+    /**
+     * 创建一个session，返回session id。
+     * session需要在ttl之内调用session_renew续期，否则会失效并释放它持有的锁
+     */
+    pub async fn session_create(&self, request: &SessionRequest) -> Result<String, ConsulError> {
+        let response = send(self.cluster(Method::PUT, "session/create").json(request)).await?;
+        let created: SessionCreated = decode(response).await?;
+        Ok(created.id)
+    }
+
+    /**
+     * 续期session，session已经失效时返回false
+     */
+    pub async fn session_renew(&self, session_id: &str) -> Result<bool, ConsulError> {
+        let request = self.cluster(Method::PUT, &format!("session/renew/{}", session_id));
+        match send(request).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * 销毁session，它持有的锁会被立即释放
+     */
+    pub async fn session_destroy(&self, session_id: &str) -> Result<(), ConsulError> {
+        send(self.cluster(Method::PUT, &format!("session/destroy/{}", session_id))).await?;
+        Ok(())
+    }
+
+    /**
+     * 使用session获取key上的锁，同时写入value，返回是否获取成功。
+     * 锁已经被其它session持有，或者还在lock_delay期间时返回false
+     */
+    pub async fn kv_acquire(&self, key: &str, session_id: &str, value: &[u8]) -> Result<bool, ConsulError> {
+        self.kv_lock_op(key, "acquire", session_id, value).await
+    }
+
+    /**
+     * 释放session持有的key上的锁，key本身不会被删除
+     */
+    pub async fn kv_release(&self, key: &str, session_id: &str) -> Result<bool, ConsulError> {
+        self.kv_lock_op(key, "release", session_id, &[]).await
+    }
+
+    async fn kv_lock_op(&self, key: &str, op: &str, session_id: &str, value: &[u8]) -> Result<bool, ConsulError> {
+        let request = self
+            .cluster(Method::PUT, &format!("kv/{}", key))
+            .query(&[(op, session_id)])
+            .body(value.to_vec());
+        decode(send(request).await?).await
+    }
+
+    /**
+     * 阻塞查询持有key上的锁的session，没有被锁住或者key不存在时为None
+     */
+    pub async fn watch_kv_session(
+        &self,
+        key: &str,
+        index: u64,
+        wait: Duration,
+    ) -> Result<(u64, Option<String>), ConsulError> {
+        let request = self.cluster(Method::GET, &format!("kv/{}", key));
+        let response = match send(self.blocking(request, index, wait)).await {
+            Err(e) if e.is_not_found() => return Ok((e.index(), None)),
+            result => result?,
+        };
+        let index = consul_index(response.headers());
+        let pairs: Vec<KvPair> = decode(response).await?;
+        Ok((index, pairs.into_iter().next().and_then(|pair| pair.session)))
+    }
+}
+
+/**
+ * 发送请求，consul返回的不是2xx时作为ConsulError::Status返回
+ */
+async fn send(request: RequestBuilder) -> Result<Response, ConsulError> {
+    let response = request.send().await.map_err(ConsulError::Transport)?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let index = consul_index(response.headers());
+    let body = response.text().await.unwrap_or_default();
+    Err(ConsulError::Status { status, index, body })
+}
+
+async fn decode<T: DeserializeOwned>(response: Response) -> Result<T, ConsulError> {
+    let bytes = response.bytes().await.map_err(ConsulError::Transport)?;
+    serde_json::from_slice(&bytes).map_err(|e| ConsulError::Decode(e.to_string()))
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, ConsulError> {
+    fs::read(path).map_err(|e| ConsulError::Config(format!("read {} failed: {}", path, e)))
+}
+
+/**
+ * 响应头X-Consul-Index中的索引，阻塞查询时作为下一次请求的index参数
+ */
+fn consul_index(headers: &HeaderMap) -> u64 {
+    headers
+        .get("X-Consul-Index")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
 }
 
 #[cfg(test)]
@@ -81,7 +469,66 @@ mod tests {
     use crate::model::{Registration, HealthCheck};
 
     use super::*;
+
+    /**
+     * 启动一个只处理一次请求的http服务，返回固定的响应，返回它的地址
+     */
+    async fn reply_once(response: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await;
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        addr
+    }
+
+    fn consul_at(addr: String) -> Consul {
+        Consul::new(ConsulOption {
+            addr,
+            ..ConsulOption::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_register_returns_status_error() {
+        let addr = reply_once("HTTP/1.1 403 Forbidden\r\ncontent-length: 17\r\n\r\nPermission denied").await;
+        let registration = Registration::simple_with_health_check(
+            "axum.rs",
+            "127.0.0.1",
+            12345,
+            HealthCheck::new("127.0.0.1:1111/health_check".to_string()),
+        );
+
+        let err = consul_at(addr).register(&registration).await.unwrap_err();
+        assert_eq!(err.status(), Some(reqwest::StatusCode::FORBIDDEN));
+        assert_eq!(err.to_string(), "consul returned 403 Forbidden: Permission denied");
+    }
+
     #[tokio::test]
+    async fn test_services_returns_decode_error() {
+        let addr = reply_once("HTTP/1.1 200 OK\r\ncontent-length: 8\r\n\r\nnot json").await;
+        let err = consul_at(addr).services().await.unwrap_err();
+        assert!(matches!(err, ConsulError::Decode(_)));
+    }
+
+    #[test]
+    fn test_client_cert_requires_key() {
+        let option = ConsulOption {
+            client_cert: Some("client.pem".to_string()),
+            ..ConsulOption::default()
+        };
+        assert!(matches!(Consul::new(option), Err(ConsulError::Config(_))));
+    }
+
+    // 以下测试需要本地运行consul agent（consul agent -dev），使用cargo test -- --ignored执行
+    #[tokio::test]
+    #[ignore = "requires a running consul agent"]
     async fn test_list_services() {
         let opt = ConsulOption::default();
         let cs = Consul::new(opt);
@@ -95,6 +542,7 @@ mod tests {
         }
     }
     #[tokio::test]
+    #[ignore = "requires a running consul agent"]
     async fn test_register_service() {
         let opt = ConsulOption::default();
         let cs = Consul::new(opt);
@@ -112,24 +560,31 @@ mod tests {
         
         let r = cs.register(&registration).await;
         assert!(r.is_ok());
+
+        let srv = cs.agent_service(&registration.id).await;
+        assert!(matches!(srv, Ok(Some(_))));
     }
     #[tokio::test]
+    #[ignore = "requires a running consul agent"]
     async fn test_deregister_service() {
         let opt = ConsulOption::default();
         let cs = Consul::new(opt);
         assert!(cs.is_ok());
         let cs = cs.unwrap();
 
-        let r = cs.deregister("axum.rs").await;
+        let filter = Filter::Service("axum.rs".to_string());
+        let srv = cs.get_service(&filter).await.unwrap().unwrap();
+        let r = cs.deregister(&srv.id).await;
         assert!(r.is_ok());
     }
     #[tokio::test]
+    #[ignore = "requires a running consul agent"]
     async fn test_get_services() {
         let opt = ConsulOption::default();
         let cs = Consul::new(opt);
         assert!(cs.is_ok());
         let cs = cs.unwrap();
-        let filter = Filter::ID("axum.rs".to_string());
+        let filter = Filter::Service("axum.rs".to_string());
         let srv = cs.get_service(&filter).await;
         assert!(srv.is_ok());
         let srv = srv.unwrap();
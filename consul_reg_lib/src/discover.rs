@@ -0,0 +1,360 @@
+/**
+ * 服务发现和客户端负载均衡。
+ *
+ * ServiceDiscover订阅ServiceDiscovery中服务实例的变化，和上一次的结果比较后产生tower::discover::Change，
+ * 可以直接交给tonic的Channel::balance_channel使用。
+ * balance_channel在此基础上提供轮询和p2c两种负载均衡方式，每个实例复用同一个http2连接，
+ * 不需要每次调用都重新建立连接。
+ */
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    fmt,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, Stream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tonic::{
+    body::BoxBody,
+    codegen::http,
+    transport::{Body, Channel, Endpoint},
+};
+use tower::{discover::Change, Service, ServiceExt};
+use tracing::{info, warn};
+
+use crate::discovery::{InstancesReceiver, ServiceDiscovery};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const CHANGE_CAPACITY: usize = 64;
+/**
+ * 实例单独登记了grpc地址时优先使用它
+ */
+const GRPC_TAG: &str = "grpc";
+
+/**
+ * 服务实例变化的来源，实现了tower::discover::Discover，key为实例id
+ */
+pub struct ServiceDiscover {
+    changes: mpsc::Receiver<Change<String, Endpoint>>,
+}
+
+impl ServiceDiscover {
+    /**
+     * service_name 要发现的服务名
+     * scheme 访问实例使用的协议，比如http
+     */
+    pub fn new(discovery: &dyn ServiceDiscovery, service_name: &str, scheme: &str) -> Self {
+        let (tx, rx) = mpsc::channel(CHANGE_CAPACITY);
+        tokio::spawn(watch_instances(
+            discovery.subscribe(service_name),
+            service_name.to_string(),
+            scheme.to_string(),
+            tx,
+        ));
+        Self { changes: rx }
+    }
+}
+
+impl Stream for ServiceDiscover {
+    type Item = Result<Change<String, Endpoint>, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.changes.poll_recv(cx).map(|change| change.map(Ok))
+    }
+}
+
+/**
+ * 实例列表每次变化时发送差异，直到接收方被丢弃。
+ * 使用consul时，consul不可用期间缓存中保留的是上一次的实例列表，不会因此把所有实例都移除。
+ */
+async fn watch_instances(
+    mut instances_rx: InstancesReceiver,
+    service_name: String,
+    scheme: String,
+    tx: mpsc::Sender<Change<String, Endpoint>>,
+) {
+    // 实例id -> 访问地址
+    let mut known: HashMap<String, String> = HashMap::new();
+
+    loop {
+        let instances = instances_rx.borrow_and_update().clone();
+        let current: HashMap<String, String> = instances
+            .iter()
+            .map(|instance| (instance.id.clone(), instance.tagged_endpoint(GRPC_TAG, &scheme)))
+            .collect();
+
+        for id in known.keys().filter(|id| !current.contains_key(*id)) {
+            info!("{} instance {} removed.", service_name, id);
+            if tx.send(Change::Remove(id.clone())).await.is_err() {
+                return;
+            }
+        }
+
+        for (id, uri) in current.iter().filter(|(id, uri)| known.get(*id) != Some(*uri)) {
+            let endpoint = match Endpoint::from_shared(uri.clone()) {
+                Ok(endpoint) => endpoint.connect_timeout(CONNECT_TIMEOUT),
+                Err(e) => {
+                    warn!("illegal endpoint {} of {}: {}", uri, id, e);
+                    continue;
+                }
+            };
+            let datacenter = instances
+                .iter()
+                .find(|instance| &instance.id == id)
+                .map(|instance| instance.datacenter.as_str())
+                .unwrap_or_default();
+            info!("{} instance {} at {} discovered in datacenter {}.", service_name, id, uri, datacenter);
+            if tx.send(Change::Insert(id.clone(), endpoint)).await.is_err() {
+                return;
+            }
+        }
+
+        known = current;
+
+        tokio::select! {
+            changed = instances_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            },
+            _ = tx.closed() => return,
+        }
+    }
+}
+
+/**
+ * 负载均衡方式
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadBalance {
+    /**
+     * 依次轮流调用每个实例
+     */
+    RoundRobin,
+    /**
+     * 随机选两个已就绪的实例，使用负载较低的那个（tonic内置的balance_channel）
+     */
+    PowerOfTwoChoices,
+}
+
+impl FromStr for LoadBalance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(LoadBalance::RoundRobin),
+            "p2c" => Ok(LoadBalance::PowerOfTwoChoices),
+            _ => Err(format!("unknown load balance {}.", s)),
+        }
+    }
+}
+
+/**
+ * 创建一个在服务所有健康实例之间做负载均衡的grpc channel，实例列表会随服务发现自动更新。
+ * 返回值可以像tonic的Channel一样用来创建grpc客户端，clone之后共享同一组连接。
+ */
+pub fn balance_channel(
+    discovery: &dyn ServiceDiscovery,
+    service_name: &str,
+    scheme: &str,
+    strategy: LoadBalance,
+) -> BalancedChannel {
+    let discover = ServiceDiscover::new(discovery, service_name, scheme);
+    match strategy {
+        LoadBalance::PowerOfTwoChoices => {
+            let (channel, tx) = Channel::balance_channel(CHANGE_CAPACITY);
+            tokio::spawn(forward_changes(discover, tx));
+            BalancedChannel::PowerOfTwoChoices(channel)
+        }
+        LoadBalance::RoundRobin => {
+            BalancedChannel::RoundRobin(RoundRobin::new(service_name, discover))
+        }
+    }
+}
+
+async fn forward_changes(
+    mut discover: ServiceDiscover,
+    tx: mpsc::Sender<Change<String, Endpoint>>,
+) {
+    loop {
+        let change = tokio::select! {
+            change = discover.next() => change,
+            _ = tx.closed() => return,
+        };
+        match change {
+            Some(Ok(change)) => {
+                if tx.send(change).await.is_err() {
+                    return;
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum BalancedChannel {
+    PowerOfTwoChoices(Channel),
+    RoundRobin(RoundRobin),
+}
+
+impl Service<http::Request<BoxBody>> for BalancedChannel {
+    type Response = http::Response<Body>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            BalancedChannel::PowerOfTwoChoices(channel) => channel.poll_ready(cx).map_err(Into::into),
+            //轮询时在call中才选择实例，由被选中的channel自己等待就绪
+            BalancedChannel::RoundRobin(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        match self {
+            BalancedChannel::PowerOfTwoChoices(channel) => {
+                let future = channel.call(req);
+                Box::pin(async move { future.await.map_err(Into::into) })
+            }
+            BalancedChannel::RoundRobin(rr) => {
+                let channel = rr.next_channel();
+                let service_name = rr.inner.service_name.clone();
+                Box::pin(async move {
+                    let channel = channel
+                        .ok_or_else(|| format!("no healthy instance of {}.", service_name))?;
+                    channel.oneshot(req).await.map_err(Into::into)
+                })
+            }
+        }
+    }
+}
+
+/**
+ * 轮询负载均衡，每个实例对应一个延迟连接的Channel
+ */
+#[derive(Clone)]
+pub struct RoundRobin {
+    inner: Arc<RoundRobinInner>,
+}
+
+struct RoundRobinInner {
+    service_name: String,
+    channels: RwLock<Vec<(String, Channel)>>,
+    next: AtomicUsize,
+    // 所有RoundRobin被丢弃时通知后台任务退出
+    _stop: oneshot::Sender<()>,
+}
+
+impl fmt::Debug for RoundRobin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoundRobin")
+            .field("service_name", &self.inner.service_name)
+            .field("instances", &self.inner.channels.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl RoundRobin {
+    fn new(service_name: &str, mut discover: ServiceDiscover) -> Self {
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let inner = Arc::new(RoundRobinInner {
+            service_name: service_name.to_string(),
+            channels: RwLock::new(vec![]),
+            next: AtomicUsize::new(0),
+            _stop: stop_tx,
+        });
+
+        let weak = Arc::downgrade(&inner);
+        tokio::spawn(async move {
+            loop {
+                let change = tokio::select! {
+                    change = discover.next() => change,
+                    _ = &mut stop_rx => return,
+                };
+                match (change, weak.upgrade()) {
+                    (Some(Ok(change)), Some(inner)) => inner.apply(change),
+                    _ => return,
+                }
+            }
+        });
+
+        Self { inner }
+    }
+
+    fn next_channel(&self) -> Option<Channel> {
+        let channels = self.inner.channels.read().unwrap();
+        if channels.is_empty() {
+            return None;
+        }
+        let index = self.inner.next.fetch_add(1, Ordering::Relaxed) % channels.len();
+        Some(channels[index].1.clone())
+    }
+}
+
+impl RoundRobinInner {
+    fn apply(&self, change: Change<String, Endpoint>) {
+        let mut channels = self.channels.write().unwrap();
+        match change {
+            Change::Insert(id, endpoint) => {
+                channels.retain(|(key, _)| key != &id);
+                channels.push((id, endpoint.connect_lazy()));
+            }
+            Change::Remove(id) => channels.retain(|(key, _)| key != &id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{discovery::MemoryDiscovery, model::ServiceInstance};
+
+    fn instance(id: &str, port: i32) -> ServiceInstance {
+        ServiceInstance {
+            id: id.to_string(),
+            name: "inventory-srv".to_string(),
+            address: "127.0.0.1".to_string(),
+            port,
+            tags: vec![],
+            meta: HashMap::new(),
+            tagged_addresses: HashMap::new(),
+            datacenter: String::new(),
+        }
+    }
+
+    async fn next_change(discover: &mut ServiceDiscover) -> (String, bool) {
+        match discover.next().await {
+            Some(Ok(Change::Insert(id, _))) => (id, true),
+            Some(Ok(Change::Remove(id))) => (id, false),
+            _ => panic!("discover closed."),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discover_diffs_instances() {
+        let discovery = MemoryDiscovery::new();
+        discovery.set_instances("inventory-srv", vec![instance("a", 3001)]);
+        let mut discover = ServiceDiscover::new(&discovery, "inventory-srv", "http");
+        assert_eq!(next_change(&mut discover).await, ("a".to_string(), true));
+
+        discovery.set_instances("inventory-srv", vec![instance("b", 3011)]);
+        assert_eq!(next_change(&mut discover).await, ("a".to_string(), false));
+        assert_eq!(next_change(&mut discover).await, ("b".to_string(), true));
+
+        //地址没有变化时不会重复产生Insert
+        discovery.set_instances("inventory-srv", vec![instance("b", 3011), instance("c", 3021)]);
+        assert_eq!(next_change(&mut discover).await, ("c".to_string(), true));
+    }
+}
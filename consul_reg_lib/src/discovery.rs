@@ -0,0 +1,347 @@
+/**
+ * 服务发现。
+ *
+ * 调用方只依赖ServiceDiscovery，不关心实例列表从哪里来：
+ * - ServiceCache 从consul的健康检查结果中发现，线上使用
+ * - StaticDiscovery 使用配置中写死的地址，本地开发时不需要启动consul
+ * - DnsDiscovery 定期查询DNS SRV记录，可以对接consul的DNS接口或者k8s的headless service
+ * - MemoryDiscovery 实例列表由代码直接设置，用于测试
+ *
+ * from_env根据环境变量SERVICE_DISCOVERY（consul、static或者dns，默认consul）选择使用哪一种。
+ */
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{stream::BoxStream, StreamExt};
+use hickory_resolver::TokioAsyncResolver;
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::{consul::Consul, model::ServiceInstance, watch::ServiceCache};
+
+pub type InstancesReceiver = watch::Receiver<Arc<Vec<ServiceInstance>>>;
+type InstancesSender = watch::Sender<Arc<Vec<ServiceInstance>>>;
+
+/**
+ * DNS SRV记录的默认刷新间隔
+ */
+const DNS_REFRESH: Duration = Duration::from_secs(10);
+
+pub trait ServiceDiscovery: Send + Sync {
+    /**
+     * 订阅服务实例的变化，返回的receiver中始终是最新的实例列表
+     */
+    fn subscribe(&self, service_name: &str) -> InstancesReceiver;
+
+    /**
+     * 服务当前的实例
+     */
+    fn instances(&self, service_name: &str) -> Arc<Vec<ServiceInstance>> {
+        self.subscribe(service_name).borrow().clone()
+    }
+}
+
+/**
+ * 根据环境变量创建服务发现
+ * SERVICE_DISCOVERY=consul 使用consul，默认值
+ * SERVICE_DISCOVERY=static 使用STATIC_SERVICES中配置的地址，格式见StaticDiscovery::parse
+ * SERVICE_DISCOVERY=dns 查询DNS SRV记录，域名后缀由DNS_SRV_DOMAIN配置，默认service.consul
+ */
+pub fn from_env(consul: Consul) -> Arc<dyn ServiceDiscovery> {
+    let kind = env::var("SERVICE_DISCOVERY").unwrap_or_else(|_| "consul".to_string());
+    match kind.as_str() {
+        "consul" => Arc::new(ServiceCache::new(consul)),
+        "static" => {
+            let config = env::var("STATIC_SERVICES").unwrap_or_default();
+            let discovery = StaticDiscovery::parse(&config)
+                .unwrap_or_else(|e| panic!("illegal STATIC_SERVICES: {}", e));
+            Arc::new(discovery)
+        }
+        "dns" => {
+            let domain = env::var("DNS_SRV_DOMAIN").unwrap_or_else(|_| "service.consul".to_string());
+            Arc::new(DnsDiscovery::new(&domain).expect("create dns resolver failed."))
+        }
+        _ => panic!("unknown SERVICE_DISCOVERY {}.", kind),
+    }
+}
+
+/**
+ * 每个服务一个实例列表的订阅，第一次订阅某个服务时才开始监听，之后一直在后台保持更新
+ */
+#[derive(Clone, Default)]
+pub(crate) struct Subscriptions {
+    services: Arc<Mutex<HashMap<String, InstancesReceiver>>>,
+}
+
+impl Subscriptions {
+    /**
+     * watch 开始监听服务，每次产生一个完整的实例列表
+     */
+    pub(crate) fn subscribe<F>(&self, service_name: &str, watch: F) -> InstancesReceiver
+    where
+        F: FnOnce() -> BoxStream<'static, Vec<ServiceInstance>>,
+    {
+        let mut services = self.services.lock().unwrap();
+        if let Some(rx) = services.get(service_name) {
+            return rx.clone();
+        }
+
+        let (tx, rx) = watch::channel(Arc::new(vec![]));
+        let mut changes = watch();
+        tokio::spawn(async move {
+            while let Some(instances) = changes.next().await {
+                tx.send_replace(Arc::new(instances));
+            }
+        });
+
+        services.insert(service_name.to_string(), rx.clone());
+        rx
+    }
+}
+
+/**
+ * 实例列表由代码直接设置，clone之后共享同一份数据
+ */
+#[derive(Clone, Default)]
+pub struct MemoryDiscovery {
+    services: Arc<Mutex<HashMap<String, InstancesSender>>>,
+}
+
+impl MemoryDiscovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * 替换服务的实例列表，所有订阅者都会收到变化
+     */
+    pub fn set_instances(&self, service_name: &str, instances: Vec<ServiceInstance>) {
+        let mut services = self.services.lock().unwrap();
+        match services.get(service_name) {
+            Some(tx) => {
+                tx.send_replace(Arc::new(instances));
+            }
+            None => {
+                let (tx, _) = watch::channel(Arc::new(instances));
+                services.insert(service_name.to_string(), tx);
+            }
+        }
+    }
+}
+
+impl ServiceDiscovery for MemoryDiscovery {
+    fn subscribe(&self, service_name: &str) -> InstancesReceiver {
+        let mut services = self.services.lock().unwrap();
+        services
+            .entry(service_name.to_string())
+            .or_insert_with(|| watch::channel(Arc::new(vec![])).0)
+            .subscribe()
+    }
+}
+
+/**
+ * 使用固定的地址，实例列表不会变化，也不做健康检查
+ */
+#[derive(Clone, Default)]
+pub struct StaticDiscovery {
+    inner: MemoryDiscovery,
+}
+
+impl StaticDiscovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * 添加一个服务，addrs为 地址:端口 列表
+     */
+    pub fn with_service(self, service_name: &str, addrs: &[&str]) -> Result<Self, String> {
+        let instances = addrs
+            .iter()
+            .map(|addr| static_instance(service_name, addr))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.inner.set_instances(service_name, instances);
+        Ok(self)
+    }
+
+    /**
+     * 解析 服务名=地址:端口,地址:端口;服务名=地址:端口 格式的配置，比如
+     * inventory-srv=127.0.0.1:3001,127.0.0.1:3011;goods-srv=127.0.0.1:3004
+     */
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut discovery = Self::new();
+        for service in config.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, addrs) = service
+                .split_once('=')
+                .ok_or_else(|| format!("missing '=' in {}.", service))?;
+            let addrs: Vec<&str> = addrs.split(',').map(str::trim).filter(|a| !a.is_empty()).collect();
+            discovery = discovery.with_service(name.trim(), &addrs)?;
+        }
+        Ok(discovery)
+    }
+}
+
+impl ServiceDiscovery for StaticDiscovery {
+    fn subscribe(&self, service_name: &str) -> InstancesReceiver {
+        self.inner.subscribe(service_name)
+    }
+}
+
+fn static_instance(service_name: &str, addr: &str) -> Result<ServiceInstance, String> {
+    let (address, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| format!("missing port in {}.", addr))?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("illegal port in {}.", addr))?;
+    Ok(ServiceInstance {
+        id: format!("{}-{}", service_name, addr),
+        name: service_name.to_string(),
+        address: address.trim_start_matches('[').trim_end_matches(']').to_string(),
+        port,
+        tags: vec![],
+        meta: HashMap::new(),
+        tagged_addresses: HashMap::new(),
+        datacenter: String::new(),
+    })
+}
+
+/**
+ * 定期查询 _服务名._tcp.域名 的SRV记录，
+ * 比如consul的DNS接口中 _inventory-srv._tcp.service.consul 只包含通过了健康检查的实例。
+ * 查询失败时保留上一次的实例列表。
+ */
+#[derive(Clone)]
+pub struct DnsDiscovery {
+    resolver: TokioAsyncResolver,
+    domain: String,
+    refresh: Duration,
+    subscriptions: Subscriptions,
+}
+
+impl DnsDiscovery {
+    /**
+     * 使用系统的DNS配置（/etc/resolv.conf）
+     */
+    pub fn new(domain: &str) -> Result<Self, String> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|e| e.to_string())?;
+        Ok(Self::with_resolver(resolver, domain))
+    }
+
+    pub fn with_resolver(resolver: TokioAsyncResolver, domain: &str) -> Self {
+        Self {
+            resolver,
+            domain: domain.trim_matches('.').to_string(),
+            refresh: DNS_REFRESH,
+            subscriptions: Subscriptions::default(),
+        }
+    }
+
+    pub fn refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    async fn lookup(&self, service_name: &str) -> Result<Vec<ServiceInstance>, String> {
+        let query = format!("_{}._tcp.{}.", service_name, self.domain);
+        let lookup = self.resolver.srv_lookup(query).await.map_err(|e| e.to_string())?;
+
+        let mut instances = vec![];
+        for srv in lookup.iter() {
+            let target = srv.target().to_utf8();
+            let target = target.trim_end_matches('.');
+            //SRV记录的目标是主机名，需要再解析成ip，解析失败时直接使用主机名
+            let address = match self.resolver.lookup_ip(target).await {
+                Ok(ips) => match ips.iter().next() {
+                    Some(ip) => ip.to_string(),
+                    None => target.to_string(),
+                },
+                Err(_) => target.to_string(),
+            };
+            instances.push(ServiceInstance {
+                id: format!("{}:{}", target, srv.port()),
+                name: service_name.to_string(),
+                address,
+                port: srv.port() as i32,
+                tags: vec![],
+                meta: HashMap::new(),
+                tagged_addresses: HashMap::new(),
+                datacenter: String::new(),
+            });
+        }
+        instances.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(instances)
+    }
+}
+
+impl ServiceDiscovery for DnsDiscovery {
+    fn subscribe(&self, service_name: &str) -> InstancesReceiver {
+        let discovery = self.clone();
+        let name = service_name.to_string();
+        self.subscriptions.subscribe(service_name, move || {
+            futures::stream::unfold(
+                (discovery, name, None::<Vec<ServiceInstance>>),
+                |(discovery, name, last)| async move {
+                    let mut last = last;
+                    loop {
+                        if last.is_some() {
+                            tokio::time::sleep(discovery.refresh).await;
+                        }
+                        match discovery.lookup(&name).await {
+                            Ok(instances) if last.as_ref() != Some(&instances) => {
+                                last = Some(instances.clone());
+                                return Some((instances, (discovery, name, last)));
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                warn!("lookup srv of {} failed: {}", name, e);
+                                if last.is_none() {
+                                    tokio::time::sleep(discovery.refresh).await;
+                                }
+                            }
+                        }
+                    }
+                },
+            )
+            .boxed()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_static_services() {
+        let discovery =
+            StaticDiscovery::parse("inventory-srv=127.0.0.1:3001, 127.0.0.1:3011; goods-srv=[::1]:3004").unwrap();
+
+        let inventory = discovery.inner.subscribe("inventory-srv").borrow().clone();
+        assert_eq!(inventory.len(), 2);
+        assert_eq!(inventory[1].endpoint("http"), "http://127.0.0.1:3011");
+
+        let goods = discovery.inner.subscribe("goods-srv").borrow().clone();
+        assert_eq!(goods[0].endpoint("http"), "http://[::1]:3004");
+
+        assert!(StaticDiscovery::parse("inventory-srv").is_err());
+        assert!(StaticDiscovery::parse("inventory-srv=127.0.0.1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_discovery_notifies_subscribers() {
+        let discovery = MemoryDiscovery::new();
+        let mut rx = discovery.subscribe("inventory-srv");
+        assert!(rx.borrow().is_empty());
+
+        let instance = static_instance("inventory-srv", "127.0.0.1:3001").unwrap();
+        discovery.set_instances("inventory-srv", vec![instance.clone()]);
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow_and_update().clone(), vec![instance]);
+        assert_eq!(discovery.instances("inventory-srv").len(), 1);
+    }
+}
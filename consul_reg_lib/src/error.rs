@@ -0,0 +1,73 @@
+/**
+ * 调用consul的错误。
+ */
+use std::fmt;
+
+use reqwest::StatusCode;
+
+#[derive(Debug)]
+pub enum ConsulError {
+    /**
+     * 配置错误，比如证书文件读取失败
+     */
+    Config(String),
+    /**
+     * 连接失败、超时、TLS握手失败等，请求没有拿到响应
+     */
+    Transport(reqwest::Error),
+    /**
+     * consul返回了非2xx的状态码，比如ACL token没有权限时的403
+     * index 响应中的X-Consul-Index，404时阻塞查询可以用它继续等待
+     */
+    Status {
+        status: StatusCode,
+        index: u64,
+        body: String,
+    },
+    /**
+     * 响应内容无法解析
+     */
+    Decode(String),
+}
+
+impl ConsulError {
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ConsulError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(StatusCode::NOT_FOUND)
+    }
+
+    pub(crate) fn index(&self) -> u64 {
+        match self {
+            ConsulError::Status { index, .. } => *index,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for ConsulError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsulError::Config(e) => write!(f, "consul config error: {}", e),
+            ConsulError::Transport(e) => write!(f, "consul transport error: {}", e),
+            ConsulError::Status { status, body, .. } => {
+                write!(f, "consul returned {}: {}", status, body.trim())
+            }
+            ConsulError::Decode(e) => write!(f, "decode consul response failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConsulError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConsulError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -1,5 +1,10 @@
 pub mod consul;
+pub mod discover;
+pub mod discovery;
+pub mod error;
+pub mod lock;
 pub mod model;
+pub mod watch;
 
 
 #[cfg(test)]
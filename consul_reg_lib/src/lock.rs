@@ -0,0 +1,271 @@
+/**
+ * 基于consul session的分布式锁，用于在多个副本中选出一个leader。
+ *
+ * 每个副本创建一个带TTL的session，用它去获取同一个key上的锁，获取成功的副本就是leader。
+ * leader在后台定期续期session，同时监听key上的锁是否还属于自己，
+ * 续期失败、session失效或者锁被别人拿走时都会通知leader停止工作。
+ * leader进程挂掉之后session不再续期，失效后锁被释放，等待中的副本在lock_delay之后接管。
+ */
+use std::time::{Duration, Instant};
+
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{consul::Consul, model::SessionRequest};
+
+const WATCH_WAIT: Duration = Duration::from_secs(30);
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/**
+ * ttl session的有效期，每ttl/2续期一次，超过ttl没有续期成功就认为已经失去锁
+ * lock_delay session失效后锁要等待多久才能被其它副本获取，防止旧leader还没停下来新leader就开始工作
+ * value 获取锁时写入key的值，一般写入实例的标识，方便查看当前的leader是谁
+ */
+#[derive(Clone, Debug)]
+pub struct LockOption {
+    pub ttl: Duration,
+    pub lock_delay: Duration,
+    pub value: String,
+}
+
+impl Default for LockOption {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(10),
+            lock_delay: Duration::from_secs(5),
+            value: String::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Lock {
+    consul: Consul,
+    key: String,
+    option: LockOption,
+}
+
+impl Lock {
+    /**
+     * key 锁对应的KV路径，竞争同一个leader的副本需要使用相同的key
+     */
+    pub fn new(consul: Consul, key: &str, option: LockOption) -> Self {
+        Self {
+            consul,
+            key: key.to_string(),
+            option,
+        }
+    }
+
+    /**
+     * 等待直到获取到锁，consul不可用时按指数退避一直重试。
+     * 需要放弃时直接丢弃这个future即可，已经创建的session不再续期，会在ttl之后自动失效。
+     */
+    pub async fn acquire(&self) -> Leadership {
+        let mut retry = RETRY_MIN;
+        loop {
+            match self.try_acquire().await {
+                Ok(leadership) => {
+                    info!("lock {} acquired with session {}.", self.key, leadership.session_id());
+                    return leadership;
+                }
+                Err(e) => {
+                    warn!("acquire lock {} failed, retry after {:?}: {}", self.key, retry, e);
+                    tokio::time::sleep(retry).await;
+                    retry = (retry * 2).min(RETRY_MAX);
+                }
+            }
+        }
+    }
+
+    async fn try_acquire(&self) -> Result<Leadership, String> {
+        let request = SessionRequest::new(&self.key, self.option.ttl, self.option.lock_delay);
+        let session = self
+            .consul
+            .session_create(&request)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match self.wait_for_lock(&session).await {
+            Ok(_) => Ok(Leadership::hold(self.clone(), session)),
+            Err(e) => {
+                let _ = self.consul.session_destroy(&session).await;
+                Err(e)
+            }
+        }
+    }
+
+    /**
+     * 锁被别人持有时阻塞等待它被释放，每次最多等待ttl/2，期间session同样需要续期
+     */
+    async fn wait_for_lock(&self, session: &str) -> Result<(), String> {
+        let wait = self.option.ttl / 2;
+        let mut index = 0;
+        loop {
+            let acquired = self
+                .consul
+                .kv_acquire(&self.key, session, self.option.value.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+            if acquired {
+                return Ok(());
+            }
+
+            let (new_index, _) = self
+                .consul
+                .watch_kv_session(&self.key, index, wait)
+                .await
+                .map_err(|e| e.to_string())?;
+            index = if new_index < index { 1 } else { new_index.max(1) };
+
+            let renewed = self
+                .consul
+                .session_renew(session)
+                .await
+                .map_err(|e| e.to_string())?;
+            if !renewed {
+                return Err(format!("session {} expired.", session));
+            }
+        }
+    }
+}
+
+/**
+ * 持有中的锁。
+ * 被丢弃或者调用release时释放锁，失去锁时lost()返回，之后需要重新调用Lock::acquire竞争。
+ */
+pub struct Leadership {
+    session: String,
+    lost: watch::Receiver<bool>,
+    release: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Leadership {
+    fn hold(lock: Lock, session: String) -> Self {
+        let (lost_tx, lost_rx) = watch::channel(false);
+        let (release_tx, release_rx) = oneshot::channel();
+        let task = tokio::spawn(hold(lock, session.clone(), lost_tx, release_rx));
+        Self {
+            session,
+            lost: lost_rx,
+            release: Some(release_tx),
+            task: Some(task),
+        }
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session
+    }
+
+    pub fn is_leader(&self) -> bool {
+        !*self.lost.borrow()
+    }
+
+    /**
+     * 等待直到失去锁
+     */
+    pub async fn lost(&mut self) {
+        while !*self.lost.borrow_and_update() {
+            if self.lost.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /**
+     * 主动释放锁并等待释放完成，其它副本可以立刻获取，不需要等待lock_delay
+     */
+    pub async fn release(mut self) {
+        if let Some(release) = self.release.take() {
+            let _ = release.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+/**
+ * 在后台保持锁，直到失去锁或者被要求释放
+ */
+async fn hold(
+    lock: Lock,
+    session: String,
+    lost_tx: watch::Sender<bool>,
+    mut release_rx: oneshot::Receiver<()>,
+) {
+    //Leadership被丢弃时release_rx同样会返回，按主动释放处理
+    let lost_reason = tokio::select! {
+        reason = keep_alive(&lock, &session) => Some(reason),
+        reason = watch_holder(&lock, &session) => Some(reason),
+        _ = &mut release_rx => None,
+    };
+    lost_tx.send_replace(true);
+
+    match lost_reason {
+        Some(reason) => warn!("lock {} lost: {}", lock.key, reason),
+        None => {
+            if let Err(e) = lock.consul.kv_release(&lock.key, &session).await {
+                warn!("release lock {} failed: {}", lock.key, e);
+            }
+            info!("lock {} released.", lock.key);
+        }
+    }
+    if let Err(e) = lock.consul.session_destroy(&session).await {
+        warn!("destroy session {} failed: {}", session, e);
+    }
+}
+
+/**
+ * 每ttl/2续期一次session，返回失去锁的原因。
+ * consul暂时不可用时会更快地重试，超过ttl都没有续期成功时认为锁已经失去，
+ * 此时consul那边的session可能还没有失效，但宁可让leader提前停下，也不能出现两个leader。
+ */
+async fn keep_alive(lock: &Lock, session: &str) -> String {
+    let mut last_renewed = Instant::now();
+    let mut interval = lock.option.ttl / 2;
+    loop {
+        tokio::time::sleep(interval).await;
+        match lock.consul.session_renew(session).await {
+            Ok(true) => {
+                last_renewed = Instant::now();
+                interval = lock.option.ttl / 2;
+            }
+            Ok(false) => return format!("session {} expired.", session),
+            Err(e) => {
+                if last_renewed.elapsed() >= lock.option.ttl {
+                    return format!("renew session {} failed: {}", session, e);
+                }
+                warn!("renew session {} failed, retry after {:?}: {}", session, RETRY_MIN, e);
+                interval = RETRY_MIN;
+            }
+        }
+    }
+}
+
+/**
+ * 监听key上的锁，锁不再属于session时返回原因
+ */
+async fn watch_holder(lock: &Lock, session: &str) -> String {
+    let mut index = 0;
+    loop {
+        match lock.consul.watch_kv_session(&lock.key, index, WATCH_WAIT).await {
+            Ok((new_index, holder)) => {
+                if holder.as_deref() != Some(session) {
+                    return format!("lock is held by {:?} now.", holder);
+                }
+                index = if new_index < index { 1 } else { new_index.max(1) };
+            }
+            //consul不可用的时间过长时由keep_alive处理
+            Err(e) => {
+                warn!("watch lock {} failed: {}", lock.key, e);
+                tokio::time::sleep(RETRY_MIN).await;
+            }
+        }
+    }
+}
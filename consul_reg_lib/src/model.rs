@@ -1,34 +1,148 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, env, fmt, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+/**
+ * protocol http或者https
+ * token ACL token，每个请求都通过X-Consul-Token请求头带上
+ * ca_cert 验证consul服务端证书的CA证书路径（PEM），不设置时使用系统的根证书
+ * client_cert、client_key consul开启了verify_incoming时使用的客户端证书和私钥路径（PEM，私钥为PKCS#8格式）
+ * namespace 命名空间（consul企业版）
+ * datacenter 查询服务、KV和session时使用的数据中心，不设置时使用本地agent所在的数据中心
+ * failover_datacenters 本数据中心没有健康实例时，按顺序依次到这些数据中心中查找服务
+ */
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ConsulOption {
     pub addr: String,
     pub timeout_sec: u64,
     pub protocol: String,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    #[serde(default)]
+    pub client_key: Option<String>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub datacenter: Option<String>,
+    #[serde(default)]
+    pub failover_datacenters: Vec<String>,
 }
 
-//TODO 默认直接使用本地8500的端口，真实项目中需要修改为环境变量或者配置文件配置
+//默认直接使用本地8500的端口，部署时通过ConsulOption::from_env配置
 impl Default for ConsulOption {
     fn default() -> Self {
         Self {
             addr: String::from("127.0.0.1:8500"),
             timeout_sec: 1u64,
             protocol: "http".to_string(),
+            token: None,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            namespace: None,
+            datacenter: None,
+            failover_datacenters: vec![],
+        }
+    }
+}
+
+impl ConsulOption {
+    /**
+     * 读取和consul命令行相同的环境变量，没有设置的使用默认值
+     * CONSUL_HTTP_ADDR 地址，可以带上协议，比如https://consul.local:8501
+     * CONSUL_HTTP_SSL 为true时使用https
+     * CONSUL_HTTP_TOKEN ACL token
+     * CONSUL_CACERT、CONSUL_CLIENT_CERT、CONSUL_CLIENT_KEY 证书路径
+     * CONSUL_NAMESPACE 命名空间
+     * CONSUL_DATACENTER 数据中心
+     * CONSUL_FAILOVER_DATACENTERS 故障转移的数据中心，按优先级用逗号分隔，比如dc2,dc3
+     */
+    pub fn from_env() -> Self {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let mut option = Self::default();
+
+        if let Some(addr) = var("CONSUL_HTTP_ADDR") {
+            match addr.split_once("://") {
+                Some((protocol, addr)) => {
+                    option.protocol = protocol.to_string();
+                    option.addr = addr.trim_end_matches('/').to_string();
+                }
+                None => option.addr = addr,
+            }
         }
+        if var("CONSUL_HTTP_SSL").as_deref() == Some("true") {
+            option.protocol = "https".to_string();
+        }
+        option.token = var("CONSUL_HTTP_TOKEN");
+        option.ca_cert = var("CONSUL_CACERT");
+        option.client_cert = var("CONSUL_CLIENT_CERT");
+        option.client_key = var("CONSUL_CLIENT_KEY");
+        option.namespace = var("CONSUL_NAMESPACE");
+        option.datacenter = var("CONSUL_DATACENTER");
+        option.failover_datacenters = var("CONSUL_FAILOVER_DATACENTERS")
+            .map(|dcs| {
+                dcs.split(',')
+                    .map(str::trim)
+                    .filter(|dc| !dc.is_empty())
+                    .map(|dc| dc.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        option
+    }
+}
+
+//token不能出现在日志中
+impl fmt::Debug for ConsulOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConsulOption")
+            .field("addr", &self.addr)
+            .field("timeout_sec", &self.timeout_sec)
+            .field("protocol", &self.protocol)
+            .field("token", &self.token.as_ref().map(|_| "******"))
+            .field("ca_cert", &self.ca_cert)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key)
+            .field("namespace", &self.namespace)
+            .field("datacenter", &self.datacenter)
+            .field("failover_datacenters", &self.failover_datacenters)
+            .finish()
     }
 }
 
 /**
+ * 健康检查，http、grpc、tcp、ttl四种方式中只能设置一种
+ * id 检查的id，一个服务有多个检查时需要各不相同，更新ttl检查时也使用这个id
+ * name 检查的名字，方便在consul中查看
  * deregister_critical_service_after 服务critical多久之后将会被注销
  * http 检查url
- * interval 检查间隔
+ * grpc 使用grpc.health.v1.Health协议检查，格式为 地址:端口/服务名
+ * tcp 检查能否建立tcp连接，格式为 地址:端口
+ * ttl 由服务自己定期上报状态，超过这个时间没有上报就变成critical
+ * interval 检查间隔，ttl检查不需要
  */
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct HealthCheck {
+    #[serde(rename = "CheckID", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "Name", default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub deregisterCriticalServiceAfter: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub http: String,
+    #[serde(rename = "GRPC", default, skip_serializing_if = "Option::is_none")]
+    pub grpc: Option<String>,
+    #[serde(rename = "GRPCUseTLS", default, skip_serializing_if = "Option::is_none")]
+    pub grpc_use_tls: Option<bool>,
+    #[serde(rename = "TCP", default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<String>,
+    #[serde(rename = "TTL", default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub interval: String,
 }
 
@@ -41,18 +155,105 @@ impl HealthCheck {
             deregisterCriticalServiceAfter: "30m".to_string(),
             http: http,
             interval: "20s".to_string(),
+            ..Default::default()
         };
     }
+
+    /**
+     * grpc健康检查，target为 地址:端口/服务名，服务名为空时检查整个服务器
+     */
+    pub fn grpc(target: String) -> Self {
+        Self {
+            deregisterCriticalServiceAfter: "30m".to_string(),
+            grpc: Some(target),
+            grpc_use_tls: Some(false),
+            interval: "20s".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * tcp健康检查，addr为 地址:端口
+     */
+    pub fn tcp(addr: String) -> Self {
+        Self {
+            deregisterCriticalServiceAfter: "30m".to_string(),
+            tcp: Some(addr),
+            interval: "20s".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * ttl健康检查，服务需要在ttl之内调用Consul::update_ttl_check上报状态
+     */
+    pub fn ttl(ttl: Duration) -> Self {
+        Self {
+            deregisterCriticalServiceAfter: "30m".to_string(),
+            ttl: Some(format!("{}s", ttl.as_secs())),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+/**
+ * ttl检查上报的状态
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Passing,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct CheckUpdate {
+    pub status: CheckStatus,
+    pub output: String,
 }
 
-#[derive(Default, Serialize, Deserialize)]
+/**
+ * 服务的其它访问地址，比如rest和grpc使用不同的地址时分别登记
+ */
+#[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct TaggedAddress {
+    pub address: String,
+    pub port: i32,
+}
+
+/**
+ * check和checks可以同时使用，consul会把它们合并
+ * meta 服务的元数据，会随服务实例一起返回给调用方
+ * tagged_addresses 服务的其它访问地址，key为地址的标签，比如grpc
+ */
+#[derive(Default, Serialize, Deserialize, Clone)]
 pub struct Registration {
     pub name: String,
     pub id: String,
     pub tags: Vec<String>,
     pub address: String,
     pub port: i32,
-    pub check: HealthCheck,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check: Option<HealthCheck>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<HealthCheck>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub meta: HashMap<String, String>,
+    #[serde(rename = "TaggedAddresses", default, skip_serializing_if = "HashMap::is_empty")]
+    pub tagged_addresses: HashMap<String, TaggedAddress>,
 }
 
 impl Registration {
@@ -70,9 +271,31 @@ impl Registration {
             tags: tags.iter().map(|t| t.to_string()).collect(),
             address: addr.to_string(),
             port: port,
-            check: health_check,
+            check: Some(health_check),
+            ..Default::default()
         }
     }
+
+    pub fn with_check(mut self, check: HealthCheck) -> Self {
+        self.checks.push(check);
+        self
+    }
+
+    pub fn with_meta(mut self, key: &str, value: &str) -> Self {
+        self.meta.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn with_tagged_address(mut self, tag: &str, address: &str, port: i32) -> Self {
+        self.tagged_addresses.insert(
+            tag.to_string(),
+            TaggedAddress {
+                address: address.to_string(),
+                port,
+            },
+        );
+        self
+    }
     // pub fn simple_with_tags(name: &str, tags: Vec<&str>, addr: &str, port: i32) -> Self {
     //     Self::new(name, name, tags, addr, port, None)
     // }
@@ -81,15 +304,27 @@ impl Registration {
     //     Self::simple_with_tags(name, vec![], addr, port)
     // }
 
+    /**
+     * 使用instance_id生成的唯一id注册
+     */
     pub fn simple_with_health_check(
         name: &str,
         addr: &str,
         port: i32,
         health_check: HealthCheck,
     ) -> Self {
-        Self::new(name, name, vec![], addr, port, health_check)
+        Self::new(name, &instance_id(name, addr, port), vec![], addr, port, health_check)
     }
 }
+/**
+ * 生成服务实例的唯一id：服务名-地址-端口-随机后缀。
+ * 同一个服务的多个副本各自注册，不会互相覆盖；随机后缀让重启后的实例和之前残留的注册信息也能区分开
+ */
+pub fn instance_id(name: &str, addr: &str, port: i32) -> String {
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    format!("{}-{}-{}-{}", name, addr, port, &suffix[..8])
+}
+
 #[derive(Default, Serialize, Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct Service {
@@ -99,14 +334,230 @@ pub struct Service {
     pub tags: Vec<String>,
     pub address: String,
     pub port: i32,
+    #[serde(default)]
+    pub meta: HashMap<String, String>,
+    #[serde(default)]
+    pub tagged_addresses: HashMap<String, TaggedAddress>,
+    #[serde(default)]
     pub datacenter: String,
 }
 
 pub type Services = HashMap<String, Service>;
 
+/**
+ * 服务实例所在的consul节点
+ */
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct Node {
+    pub node: String,
+    pub address: String,
+    pub datacenter: String,
+}
+
+/**
+ * /v1/health/service/:name 返回的一条记录
+ */
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct HealthService {
+    pub node: Node,
+    pub service: Service,
+}
+
+/**
+ * 一个可以调用的服务实例
+ * address 服务注册时没有填写地址的话使用所在节点的地址
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceInstance {
+    pub id: String,
+    pub name: String,
+    pub address: String,
+    pub port: i32,
+    pub tags: Vec<String>,
+    pub meta: HashMap<String, String>,
+    pub tagged_addresses: HashMap<String, TaggedAddress>,
+    pub datacenter: String,
+}
+
+impl ServiceInstance {
+    /**
+     * 拼接实例的访问地址，比如http://127.0.0.1:3001
+     */
+    pub fn endpoint(&self, scheme: &str) -> String {
+        format_endpoint(scheme, &self.address, self.port)
+    }
+
+    /**
+     * 拼接实例上某个标签地址的访问地址，比如grpc，实例没有登记这个标签时使用默认地址
+     */
+    pub fn tagged_endpoint(&self, tag: &str, scheme: &str) -> String {
+        match self.tagged_addresses.get(tag) {
+            Some(tagged) => format_endpoint(scheme, &tagged.address, tagged.port),
+            None => self.endpoint(scheme),
+        }
+    }
+}
+
+fn format_endpoint(scheme: &str, address: &str, port: i32) -> String {
+    if address.contains(':') {
+        format!("{}://[{}]:{}", scheme, address, port)
+    } else {
+        format!("{}://{}:{}", scheme, address, port)
+    }
+}
+
+impl From<HealthService> for ServiceInstance {
+    fn from(entry: HealthService) -> Self {
+        let srv = entry.service;
+        let address = if srv.address.is_empty() {
+            entry.node.address
+        } else {
+            srv.address
+        };
+        Self {
+            id: srv.id,
+            name: srv.service,
+            address,
+            port: srv.port,
+            tags: srv.tags,
+            meta: srv.meta,
+            tagged_addresses: srv.tagged_addresses,
+            datacenter: entry.node.datacenter,
+        }
+    }
+}
+
+/**
+ * 创建session的参数
+ * name session的名字，方便在consul中查看
+ * ttl session的有效期，比如10s，期间没有续期的话session失效
+ * lock_delay session失效后，它持有的锁要等待这么长时间才能被重新获取，比如5s
+ * behavior session失效时如何处理它持有的key，release释放锁，delete删除key
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionRequest {
+    pub name: String,
+    #[serde(rename = "TTL")]
+    pub ttl: String,
+    pub lock_delay: String,
+    pub behavior: String,
+}
+
+impl SessionRequest {
+    /**
+     * 新建一个session参数，失效时释放锁
+     */
+    pub fn new(name: &str, ttl: Duration, lock_delay: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            ttl: format!("{}s", ttl.as_secs()),
+            lock_delay: format!("{}s", lock_delay.as_secs()),
+            behavior: "release".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionCreated {
+    #[serde(rename = "ID")]
+    pub id: String,
+}
+
+/**
+ * /v1/kv/:key 返回的一条记录，只保留用到的字段
+ * session 持有这个key上的锁的session，没有被锁住时为None
+ */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct KvPair {
+    pub key: String,
+    #[serde(default)]
+    pub session: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub enum Filter {
     Service(String),
     ID(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_from_health_service() {
+        let json = r#"[{
+            "Node": {"Node": "node-1", "Address": "10.0.0.1", "Datacenter": "dc1"},
+            "Service": {"ID": "inventory-srv-1", "Service": "inventory-srv", "Tags": ["grpc"],
+                        "Address": "", "Port": 3001, "Meta": {"version": "1"},
+                        "TaggedAddresses": {"grpc": {"Address": "10.0.0.2", "Port": 3101}}},
+            "Checks": []
+        }]"#;
+        let entries: Vec<HealthService> = serde_json::from_str(json).unwrap();
+        let instance = ServiceInstance::from(entries.into_iter().next().unwrap());
+
+        assert_eq!(instance.address, "10.0.0.1");
+        assert_eq!(instance.datacenter, "dc1");
+        assert_eq!(instance.meta.get("version").map(|v| v.as_str()), Some("1"));
+        assert_eq!(instance.endpoint("http"), "http://10.0.0.1:3001");
+        assert_eq!(instance.tagged_endpoint("grpc", "http"), "http://10.0.0.2:3101");
+        assert_eq!(instance.tagged_endpoint("rest", "http"), "http://10.0.0.1:3001");
+
+        let v6 = ServiceInstance {
+            address: "::1".to_string(),
+            ..instance
+        };
+        assert_eq!(v6.endpoint("http"), "http://[::1]:3001");
+    }
+
+    #[test]
+    fn test_registration_json() {
+        let registration = Registration::simple_with_health_check(
+            "order-srv",
+            "127.0.0.1",
+            3002,
+            HealthCheck::new("http://127.0.0.1:3002/health/ready".to_string()),
+        )
+        .with_check(HealthCheck::ttl(Duration::from_secs(15)).with_id("order-srv:ttl"))
+        .with_meta("grpc_service", "order.OrderService")
+        .with_tagged_address("grpc", "127.0.0.1", 3002);
+        let json = serde_json::to_value(&registration).unwrap();
+
+        assert_eq!(json["checks"][0]["CheckID"], "order-srv:ttl");
+        assert_eq!(json["checks"][0]["TTL"], "15s");
+        assert!(json["checks"][0].get("interval").is_none());
+        assert!(json["check"].get("GRPC").is_none());
+        assert_eq!(json["meta"]["grpc_service"], "order.OrderService");
+        assert_eq!(json["TaggedAddresses"]["grpc"]["Port"], 3002);
+    }
+
+    #[test]
+    fn test_instance_id_is_unique() {
+        let id = instance_id("order-srv", "127.0.0.1", 3002);
+        assert!(id.starts_with("order-srv-127.0.0.1-3002-"));
+        assert_ne!(id, instance_id("order-srv", "127.0.0.1", 3002));
+    }
+
+    #[test]
+    fn test_option_debug_hides_token() {
+        let option = ConsulOption {
+            token: Some("secret-token".to_string()),
+            ..ConsulOption::default()
+        };
+        assert!(!format!("{:?}", option).contains("secret-token"));
+    }
+
+    #[test]
+    fn test_session_request_json() {
+        let request = SessionRequest::new("leader", Duration::from_secs(10), Duration::from_secs(5));
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["TTL"], "10s");
+        assert_eq!(json["LockDelay"], "5s");
+        assert_eq!(json["Behavior"], "release");
+    }
+}
@@ -0,0 +1,294 @@
+/**
+ * 服务变化监听和本地缓存。
+ *
+ * watch_service使用consul的阻塞查询（index + wait）监听服务的健康实例，watch_kv用同样的方式监听KV。
+ * 数据变化时consul会立刻返回，没有变化时请求一直挂起直到wait超时，
+ * 所以既能在几秒内感知到变化，又不会频繁请求consul。
+ * ServiceCache为每个服务维护一个这样的监听，查询实例变成了本地读取。
+ * consul不可用时保留最后一次拿到的实例列表，并按指数退避重试。
+ * 配置了故障转移数据中心时，同时监听每个数据中心，本数据中心没有健康实例时使用下一个数据中心的实例。
+ */
+use std::{future::Future, time::Duration};
+
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use tracing::{info, warn};
+
+use crate::{
+    consul::Consul,
+    discovery::{InstancesReceiver, ServiceDiscovery, Subscriptions},
+    error::ConsulError,
+    model::ServiceInstance,
+};
+
+/**
+ * 每次阻塞查询最多等待的时间
+ */
+const WATCH_WAIT: Duration = Duration::from_secs(30);
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+struct WatchState<T, F> {
+    name: String,
+    query: F,
+    index: u64,
+    last: Option<T>,
+    retry: Duration,
+}
+
+/**
+ * 用阻塞查询持续监听一个值，第一次查询成功以及之后每次值发生变化时产生新的值。
+ * query 使用给定的index发起一次阻塞查询，返回新的index和值
+ * 查询失败不会结束这个stream，只是在恢复之前不会产生新的值。
+ */
+fn watch_blocking<T, F, Fut>(name: String, query: F) -> BoxStream<'static, T>
+where
+    T: PartialEq + Clone + Send + 'static,
+    F: Fn(u64) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(u64, T), ConsulError>> + Send,
+{
+    let state = WatchState {
+        name,
+        query,
+        index: 0,
+        last: None,
+        retry: RETRY_MIN,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            let (index, value) = match (state.query)(state.index).await {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("watch {} failed, retry after {:?}: {}", state.name, state.retry, e);
+                    tokio::time::sleep(state.retry).await;
+                    state.retry = (state.retry * 2).min(RETRY_MAX);
+                    continue;
+                }
+            };
+            state.retry = RETRY_MIN;
+            // 索引变小说明consul的数据被重置了（比如重启），需要重新开始阻塞查询；
+            // 索引至少为1，否则每次查询都会立即返回
+            state.index = if index < state.index { 1 } else { index.max(1) };
+
+            if state.last.as_ref() != Some(&value) {
+                state.last = Some(value.clone());
+                return Some((value, state));
+            }
+        }
+    })
+    .boxed()
+}
+
+/**
+ * 监听服务的健康实例，每次产生一个完整的实例列表（按id排序）。
+ * 按Consul::datacenters()的顺序，使用第一个有健康实例的数据中心中的实例，
+ * 本数据中心恢复之后自动切回来。本数据中心返回第一次查询结果之前不会切换到备用数据中心。
+ */
+pub fn watch_service(consul: Consul, service_name: &str) -> BoxStream<'static, Vec<ServiceInstance>> {
+    let datacenters = consul.datacenters();
+    if datacenters.len() == 1 {
+        return watch_service_in(consul, service_name, datacenters[0].clone());
+    }
+
+    let watches = datacenters.iter().enumerate().map(|(n, dc)| {
+        watch_service_in(consul.clone(), service_name, dc.clone())
+            .map(move |instances| (n, instances))
+            .boxed()
+    });
+    let mut failover = Failover::new(service_name, datacenters.clone());
+    stream::select_all(watches)
+        .filter_map(move |(n, instances)| futures::future::ready(failover.update(n, instances)))
+        .boxed()
+}
+
+/**
+ * 多个数据中心之间的故障转移：记录每个数据中心最新的健康实例，选出要使用的数据中心。
+ * 本数据中心还没有返回第一次查询结果时不做选择，避免启动时先返回的备用数据中心抢走流量。
+ */
+struct Failover {
+    name: String,
+    datacenters: Vec<Option<String>>,
+    latest: Vec<Option<Vec<ServiceInstance>>>,
+    active: Option<usize>,
+}
+
+impl Failover {
+    fn new(name: &str, datacenters: Vec<Option<String>>) -> Self {
+        Self {
+            name: name.to_string(),
+            latest: vec![None; datacenters.len()],
+            datacenters,
+            active: None,
+        }
+    }
+
+    /**
+     * 第n个数据中心的实例发生了变化，返回需要通知给调用方的实例列表，不需要通知时返回None
+     */
+    fn update(&mut self, n: usize, instances: Vec<ServiceInstance>) -> Option<Vec<ServiceInstance>> {
+        self.latest[n] = Some(instances);
+        //本数据中心还没有返回结果时，不知道它有没有健康实例，先不做选择
+        self.latest[0].as_ref()?;
+
+        let previous = self.active;
+        let (current, instances) = match prefer_datacenter(&self.latest) {
+            Some((n, instances)) => (Some(n), instances.clone()),
+            None => (None, vec![]),
+        };
+        self.active = current;
+
+        match (previous, current) {
+            (Some(p), Some(0)) if p != 0 => info!("{} recovered in local datacenter.", self.name),
+            (p, Some(c)) if c != 0 && p != Some(c) => warn!(
+                "no healthy instance of {} in local datacenter, fail over to {}.",
+                self.name,
+                self.datacenters[c].as_deref().unwrap_or_default()
+            ),
+            (Some(_), None) => warn!("no healthy instance of {} in any datacenter.", self.name),
+            _ => {}
+        }
+        //选中的数据中心没有变化，并且变化的不是它的实例时不需要通知
+        let changed = current != previous || current.is_none_or(|c| c == n);
+        changed.then_some(instances)
+    }
+}
+
+/**
+ * 按顺序选出第一个有健康实例的数据中心
+ */
+fn prefer_datacenter(latest: &[Option<Vec<ServiceInstance>>]) -> Option<(usize, &Vec<ServiceInstance>)> {
+    latest
+        .iter()
+        .enumerate()
+        .find_map(|(n, instances)| instances.as_ref().filter(|i| !i.is_empty()).map(|i| (n, i)))
+}
+
+/**
+ * 监听服务在一个数据中心中的健康实例，datacenter为None时查询本地agent所在的数据中心
+ */
+fn watch_service_in(
+    consul: Consul,
+    service_name: &str,
+    datacenter: Option<String>,
+) -> BoxStream<'static, Vec<ServiceInstance>> {
+    let name = service_name.to_string();
+    let watch_name = match &datacenter {
+        Some(dc) => format!("service {} in {}", service_name, dc),
+        None => format!("service {}", service_name),
+    };
+    watch_blocking(watch_name, move |index| {
+        let consul = consul.clone();
+        let name = name.clone();
+        let datacenter = datacenter.clone();
+        async move {
+            let (index, mut instances) = consul
+                .watch_healthy_instances(&name, datacenter.as_deref(), index, WATCH_WAIT)
+                .await?;
+            instances.sort_by(|a, b| a.id.cmp(&b.id));
+            Ok((index, instances))
+        }
+    })
+}
+
+/**
+ * 监听KV中的一个key，key不存在或者被删除时产生None
+ */
+pub fn watch_kv(consul: Consul, key: &str) -> BoxStream<'static, Option<Vec<u8>>> {
+    let key = key.to_string();
+    watch_blocking(format!("kv {}", key), move |index| {
+        let consul = consul.clone();
+        let key = key.clone();
+        async move { consul.watch_kv(&key, index, WATCH_WAIT).await }
+    })
+}
+
+/**
+ * 服务实例的本地缓存，也是consul的服务发现实现，clone之后共享同一份缓存。
+ * 每个服务在第一次被查询时开始监听，之后一直在后台保持更新。
+ * 第一次查询某个服务时监听才刚开始，在consul返回之前得到的是空列表。
+ */
+#[derive(Clone)]
+pub struct ServiceCache {
+    consul: Consul,
+    subscriptions: Subscriptions,
+}
+
+impl ServiceCache {
+    pub fn new(consul: Consul) -> Self {
+        Self {
+            consul,
+            subscriptions: Subscriptions::default(),
+        }
+    }
+}
+
+impl ServiceDiscovery for ServiceCache {
+    fn subscribe(&self, service_name: &str) -> InstancesReceiver {
+        let consul = self.consul.clone();
+        self.subscriptions
+            .subscribe(service_name, || watch_service(consul, service_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn instance(id: &str, datacenter: &str) -> ServiceInstance {
+        ServiceInstance {
+            id: id.to_string(),
+            name: "inventory-srv".to_string(),
+            address: "127.0.0.1".to_string(),
+            port: 3001,
+            tags: vec![],
+            meta: HashMap::new(),
+            tagged_addresses: HashMap::new(),
+            datacenter: datacenter.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_prefer_first_datacenter_with_instances() {
+        let dc2 = vec![instance("b", "dc2")];
+        let dc3 = vec![instance("c", "dc3")];
+
+        let latest = vec![Some(vec![]), None, Some(dc3.clone())];
+        assert_eq!(prefer_datacenter(&latest), Some((2, &dc3)));
+
+        let latest = vec![Some(vec![]), Some(dc2.clone()), Some(dc3)];
+        assert_eq!(prefer_datacenter(&latest), Some((1, &dc2)));
+
+        let local = vec![instance("a", "dc1")];
+        let latest = vec![Some(local.clone()), Some(dc2)];
+        assert_eq!(prefer_datacenter(&latest), Some((0, &local)));
+
+        assert_eq!(prefer_datacenter(&[Some(vec![]), None]), None);
+    }
+
+    #[test]
+    fn test_wait_for_local_datacenter_before_failover() {
+        let datacenters = vec![None, Some("dc2".to_string())];
+        let local = vec![instance("a", "dc1")];
+        let dc2 = vec![instance("b", "dc2")];
+
+        //备用数据中心先返回，本数据中心还没有结果，不切换过去
+        let mut failover = Failover::new("inventory-srv", datacenters.clone());
+        assert_eq!(failover.update(1, dc2.clone()), None);
+        assert_eq!(failover.update(0, local.clone()), Some(local.clone()));
+        assert_eq!(failover.active, Some(0));
+        assert_eq!(failover.update(1, vec![]), None);
+
+        //本数据中心返回空列表之后才切换到备用数据中心，恢复后切回来
+        let mut failover = Failover::new("inventory-srv", datacenters);
+        assert_eq!(failover.update(1, dc2.clone()), None);
+        assert_eq!(failover.update(0, vec![]), Some(dc2));
+        assert_eq!(failover.active, Some(1));
+        assert_eq!(failover.update(0, local.clone()), Some(local));
+        assert_eq!(failover.active, Some(0));
+    }
+}
@@ -0,0 +1,14 @@
+/**
+ * 生成gRPC的文件，商品服务只提供goods.proto中的服务，并调用库存服务
+ */
+fn main() {
+    let proto_path = "../proto";
+    let proto_files = ["goods.proto", "inventory.proto"].map(|f| format!("{}/{}", proto_path, f));
+
+    tonic_build::configure()
+        .compile(
+            &proto_files,   // 欲生成的 proto 文件列表
+            &[proto_path],  // proto 依赖所在的根目录
+        )
+        .unwrap();
+}
@@ -1,24 +1,131 @@
+drop table if exists goods_sku;
+drop table if exists goods_image;
+drop table if exists goods_category;
+drop table if exists category;
+drop table if exists goods_detail;
 drop table if exists goods_summary;
+
+-- 搜索提示按名称的三元组相似度匹配拼错的商品名
+create extension if not exists pg_trgm;
+
+-- archived 下架的商品不再出现在列表中，详情仍然可以查询
+-- version 乐观锁版本号，每次修改加1，修改时需要带上读取到的版本号
+-- create_time 上架时间，搜索时按最新排序使用
 create table goods_summary (
        id serial primary key,
        name varchar(140),
-       image varchar(140)
+       image varchar(140),
+       archived boolean not null default false,
+       version int not null default 1,
+       create_time timestamp not null default now(),
+       update_time timestamp default now()
 );
 
+create index goods_summary_create_time_idx on goods_summary (create_time);
+-- 搜索提示：前缀匹配走btree，拼错时走三元组索引，只提示没有下架的商品
+create index goods_summary_name_prefix_idx on goods_summary (lower(name) text_pattern_ops) where not archived;
+create index goods_summary_name_trgm_idx on goods_summary using gin (name gin_trgm_ops) where not archived;
+
 insert into goods_summary (name , image) values('book1','');
 insert into goods_summary (name , image) values('book2','');
 insert into goods_summary (name , image) values('book3','');
 
 
-drop table if exists goods_detail;
+-- 商品详情和商品概要一一对应，id相同，二者由管理接口在同一个事务中修改
+-- search_vector 商品搜索使用的全文索引，名称的权重高于描述，由数据库自动维护
 create table goods_detail (
-       id serial primary key,
+       id int primary key references goods_summary (id) on delete cascade,
        name varchar(140),
        image varchar(140),
        des varchar(140),
-       unit_price INT not NULL
+       unit_price INT not NULL,
+       search_vector tsvector generated always as (
+              setweight(to_tsvector('simple', coalesce(name, '')), 'A') ||
+              setweight(to_tsvector('simple', coalesce(des, '')), 'B')
+       ) stored
 );
 
-insert into goods_detail (name ,image, des, unit_price) values('book1','', 'This is test des.' ,200);
-insert into goods_detail (name ,image, des, unit_price) values('book2','', 'This is test des.' ,200);
-insert into goods_detail (name ,image, des, unit_price) values('book3','', 'This is test des.' ,200);
+create index goods_detail_search_idx on goods_detail using gin (search_vector);
+create index goods_detail_unit_price_idx on goods_detail (unit_price);
+
+insert into goods_detail (id, name ,image, des, unit_price) values(1, 'book1','', 'This is test des.' ,200);
+insert into goods_detail (id, name ,image, des, unit_price) values(2, 'book2','', 'This is test des.' ,200);
+insert into goods_detail (id, name ,image, des, unit_price) values(3, 'book3','', 'This is test des.' ,200);
+
+
+-- 商品分类树，parent_id为空的是顶级分类，同一个父分类下名称不能重复
+-- sort_order 同级分类的展示顺序，从小到大
+-- version 乐观锁版本号，和商品一样修改时需要带上
+create table category (
+       id serial primary key,
+       parent_id int references category (id),
+       name varchar(64) not null,
+       sort_order int not null default 0,
+       version int not null default 1,
+       update_time timestamp default now()
+);
+
+create unique index category_parent_name_idx on category (coalesce(parent_id, 0), name);
+create index category_parent_idx on category (parent_id);
+
+-- 商品和分类多对多，商品可以挂在多个分类下
+create table goods_category (
+       goods_id int references goods_summary (id) on delete cascade,
+       category_id int references category (id) on delete cascade,
+       primary key (goods_id, category_id)
+);
+
+create index goods_category_category_idx on goods_category (category_id);
+
+insert into category (id, parent_id, name) values (1, null, 'books');
+insert into category (id, parent_id, name) values (2, 1, 'programming');
+select setval('category_id_seq', 2);
+
+insert into goods_category (goods_id, category_id) values (1, 2), (2, 2), (3, 1);
+
+-- 商品图片，一个商品可以有多张图片，sort_order最小的是主图
+-- image_key 图片在对象存储中的目录，原图为 image_key/original.扩展名，缩略图为 image_key/尺寸.thumbnail_ext
+create table goods_image (
+       id serial primary key,
+       goods_id int not null references goods_summary (id) on delete cascade,
+       image_key varchar(140) not null,
+       content_type varchar(32) not null,
+       extension varchar(8) not null,
+       thumbnail_ext varchar(8) not null,
+       width int not null,
+       height int not null,
+       sort_order int not null default 0,
+       create_time timestamp not null default now()
+);
+
+create index goods_image_goods_idx on goods_image (goods_id, sort_order);
+-- 读取图片时按key找到图片记录
+create unique index goods_image_key_idx on goods_image (image_key);
+
+-- 商品的SKU（规格），每个SKU有自己的价格、规格属性、图片和库存，下单时指定SKU
+-- sku_code 商家编码，全局唯一
+-- attributes 规格属性，比如 {"color": "red", "size": "M"}。同一个商品的SKU属性名相同，属性值的组合不能重复；
+--            没有规格的商品只有一个属性为空的SKU
+-- image 规格图片的地址，为空时使用商品的主图
+-- inventory_id 库存服务中的库存id，为空时库存未知。商品和库存的对应关系由商品服务维护，
+--              一条库存只能属于一个SKU，下单时订单服务通过grpc查询SKU对应的库存id扣减库存
+-- sort_order 展示顺序，规格矩阵中的属性值按SKU的顺序排列
+create table goods_sku (
+       id serial primary key,
+       goods_id int not null references goods_summary (id) on delete cascade,
+       sku_code varchar(64) not null unique,
+       attributes jsonb not null default '{}',
+       unit_price int not null,
+       image varchar(140) not null default '',
+       inventory_id int,
+       sort_order int not null default 0,
+       version int not null default 1,
+       create_time timestamp not null default now()
+);
+
+create unique index goods_sku_attributes_idx on goods_sku (goods_id, attributes);
+create unique index goods_sku_inventory_key on goods_sku (inventory_id) where inventory_id is not null;
+
+insert into goods_sku (goods_id, sku_code, unit_price, inventory_id) values (1, 'book1', 200, 1);
+insert into goods_sku (goods_id, sku_code, unit_price, inventory_id) values (2, 'book2', 200, 2);
+insert into goods_sku (goods_id, sku_code, unit_price, inventory_id) values (3, 'book3', 200, 3);
@@ -0,0 +1,25 @@
+-- 商品管理：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 需要在001之前执行，可以重复执行。
+
+alter table goods_summary add column if not exists archived boolean not null default false;
+alter table goods_summary add column if not exists version int not null default 1;
+alter table goods_summary add column if not exists update_time timestamp default now();
+
+-- 之前商品概要和详情各自生成id，只有详情没有概要的商品补上概要，之后二者id一一对应
+insert into goods_summary (id, name, image)
+select d.id, d.name, d.image from goods_detail d
+where not exists (select 1 from goods_summary s where s.id = d.id);
+select setval(pg_get_serial_sequence('goods_summary', 'id'), (select coalesce(max(id), 0) + 1 from goods_summary), false);
+
+-- 详情的id由管理接口在新增商品时写入，和概要相同
+alter table goods_detail alter column id drop default;
+drop sequence if exists goods_detail_id_seq;
+
+do $$
+begin
+       if not exists (select 1 from pg_constraint where conname = 'goods_detail_id_fkey') then
+              alter table goods_detail add constraint goods_detail_id_fkey
+                     foreign key (id) references goods_summary (id) on delete cascade;
+       end if;
+end
+$$;
@@ -0,0 +1,13 @@
+-- 商品搜索：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。
+
+alter table goods_summary add column if not exists create_time timestamp not null default now();
+
+alter table goods_detail add column if not exists search_vector tsvector generated always as (
+       setweight(to_tsvector('simple', coalesce(name, '')), 'A') ||
+       setweight(to_tsvector('simple', coalesce(des, '')), 'B')
+) stored;
+
+create index if not exists goods_summary_create_time_idx on goods_summary (create_time);
+create index if not exists goods_detail_search_idx on goods_detail using gin (search_vector);
+create index if not exists goods_detail_unit_price_idx on goods_detail (unit_price);
@@ -0,0 +1,7 @@
+-- 商品名的搜索提示：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。pg_trgm是受信任的扩展，有数据库的CREATE权限就可以安装。
+
+create extension if not exists pg_trgm;
+
+create index if not exists goods_summary_name_prefix_idx on goods_summary (lower(name) text_pattern_ops) where not archived;
+create index if not exists goods_summary_name_trgm_idx on goods_summary using gin (name gin_trgm_ops) where not archived;
@@ -0,0 +1,22 @@
+-- 商品分类：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。
+
+create table if not exists category (
+       id serial primary key,
+       parent_id int references category (id),
+       name varchar(64) not null,
+       sort_order int not null default 0,
+       version int not null default 1,
+       update_time timestamp default now()
+);
+
+create unique index if not exists category_parent_name_idx on category (coalesce(parent_id, 0), name);
+create index if not exists category_parent_idx on category (parent_id);
+
+create table if not exists goods_category (
+       goods_id int references goods_summary (id) on delete cascade,
+       category_id int references category (id) on delete cascade,
+       primary key (goods_id, category_id)
+);
+
+create index if not exists goods_category_category_idx on goods_category (category_id);
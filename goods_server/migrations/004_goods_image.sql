@@ -0,0 +1,18 @@
+-- 商品图片：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。
+
+create table if not exists goods_image (
+       id serial primary key,
+       goods_id int not null references goods_summary (id) on delete cascade,
+       image_key varchar(140) not null,
+       content_type varchar(32) not null,
+       extension varchar(8) not null,
+       thumbnail_ext varchar(8) not null,
+       width int not null,
+       height int not null,
+       sort_order int not null default 0,
+       create_time timestamp not null default now()
+);
+
+create index if not exists goods_image_goods_idx on goods_image (goods_id, sort_order);
+create unique index if not exists goods_image_key_idx on goods_image (image_key);
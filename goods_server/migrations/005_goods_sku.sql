@@ -0,0 +1,23 @@
+-- 商品SKU：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。
+
+create table if not exists goods_sku (
+       id serial primary key,
+       goods_id int not null references goods_summary (id) on delete cascade,
+       sku_code varchar(64) not null unique,
+       attributes jsonb not null default '{}',
+       unit_price int not null,
+       image varchar(140) not null default '',
+       inventory_id int,
+       sort_order int not null default 0,
+       version int not null default 1,
+       create_time timestamp not null default now()
+);
+
+create unique index if not exists goods_sku_attributes_idx on goods_sku (goods_id, attributes);
+create index if not exists goods_sku_inventory_idx on goods_sku (inventory_id);
+
+-- 已有的商品各生成一个没有规格的SKU。之前库存id和商品id相同，这里沿用。
+insert into goods_sku (goods_id, sku_code, unit_price, inventory_id)
+select d.id, 'goods-' || d.id, d.unit_price, d.id from goods_detail d
+where not exists (select 1 from goods_sku k where k.goods_id = d.id);
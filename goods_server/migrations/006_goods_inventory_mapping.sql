@@ -0,0 +1,5 @@
+-- 商品和库存的对应关系：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。一条库存只能属于一个SKU，有多个SKU关联同一条库存时需要先修正数据。
+
+drop index if exists goods_sku_inventory_idx;
+create unique index if not exists goods_sku_inventory_key on goods_sku (inventory_id) where inventory_id is not null;
@@ -1,14 +1,46 @@
-use std::env;
-
 // #[macro_use]
 // extern crate lazy_static;
 
-use axum::{Router, routing::{get, post}};
-use sqlx::postgres::PgPoolOptions;
-use dotenv::dotenv;
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
+    Router,
+};
+use common_lib::{
+    admin::AdminRole,
+    bootstrap::ServiceBuilder,
+    health,
+    maintenance,
+    metrics::{metrics_handler, track_http, GrpcMetrics},
+    multiplex::MultiplexService,
+    request_id::{request_id_http, GrpcRequestId},
+    telemetry::{trace_http, GrpcTracing},
+};
+use consul_reg_lib::{
+    consul::Consul,
+    discover::{balance_channel, LoadBalance},
+    discovery,
+};
 use tower_http::cors::CorsLayer;
 
-use crate::{models::state::AppState, handlers::rest::{health_handler, get_goods_summary, get_goods_detail}};
+use crate::{
+    db_access::repo::{check_inventory_health, StockCache},
+    handlers::{
+        category::{
+            add_category, get_breadcrumbs, get_category_goods, get_category_tree,
+            modify_category, modify_goods_categories, remove_category,
+        },
+        grpc::{get_grpc_router, GrpcServer},
+        image::{get_goods_images, get_image, remove_goods_image, upload_goods_images, MAX_UPLOAD_BYTES},
+        rest::{
+            add_goods, archive_goods_handler, get_goods_detail, get_goods_summary, modify_goods,
+            remove_goods, search_goods_handler, suggest_goods_handler,
+        },
+        sku::{add_sku, get_inventory_mappings, modify_sku, modify_sku_inventory, remove_sku},
+    },
+    models::{admin::GoodsAdminRole, state::AppState},
+};
 
 #[path = "../models/mod.rs"]
 mod models;
@@ -19,53 +51,91 @@ mod handlers;
 #[path = "../db_access/mod.rs"]
 mod db_access;
 
+#[path = "../storage/mod.rs"]
+mod storage;
 
-fn main() {
-    
-    tokio::runtime::Runtime::new().unwrap().block_on(async {
-        println!("start web_server in main.");
-        web_server().await;
-    });
-}
 
-async fn web_server() {
-    dotenv().ok();
+#[tokio::main]
+async fn main() {
+    //订单服务下单时通过grpc查询SKU对应的库存，所以需要注册到consul
+    let mut builder = ServiceBuilder::new("goods-srv", "127.0.0.1:3004")
+        .init_logging()
+        .register_consul()
+        .meta("version", env!("CARGO_PKG_VERSION"));
 
-    //初始化tracing
-    let file_appender = tracing_appender::rolling::hourly("./axum_log", "prefix.log");
-    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-    // tracing_subscriber::registry().with(fmt::layer()).init();
-    tracing_subscriber::fmt().with_writer(non_blocking).init();
+    //管理接口只允许GOODS_ADMIN_IDS中的用户调用，配置不合法时直接退出
+    GoodsAdminRole::ids();
 
     //set database pool
-    //设置数据库连接池。restful和grpc服务各用一个，不用考虑生命周期标注会比较简单。
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL should be set.");
-    let db_pool = PgPoolOptions::new().connect(&database_url).await.unwrap();
+    let db_pool = builder.pg_pool("DATABASE_URL").await;
+
+    //商品的库存从库存服务查询，库存服务的实例通过服务发现获取
+    let inventory_srv_name = "inventory-srv".to_string();
+    let consul = Consul::from_env().unwrap_or_else(|e| panic!("create consul client failed: {}", e));
+    let service_discovery = discovery::from_env(consul);
+    let inventory_channel = balance_channel(
+        service_discovery.as_ref(),
+        &inventory_srv_name,
+        "http",
+        LoadBalance::PowerOfTwoChoices,
+    );
+
+    //库存服务不可用时商品接口仍然可用，只是库存显示为unknown，服务降级
+    builder.readiness().add_optional_check("inventory", move || {
+        check_inventory_health(service_discovery.clone(), inventory_srv_name.clone())
+    });
 
-    // let app_state = AppState {
-    //     pool: db_pool,
-    // };
+    let grpc_health = builder.grpc_health::<GrpcServer>();
 
-    let health_check_path = "/health_check";
+    let app_state = AppState {
+        pool: db_pool.clone(),
+        stock: StockCache::new(inventory_channel),
+        //商品图片保存在本地目录或者S3兼容的对象存储中
+        store: storage::from_env(),
+    };
 
     // build our application with a route
     let rest = Router::new()
-        .route(health_check_path, get(health_handler))
+        .merge(health::router(builder.readiness()))
         .route("/goods_list", post(get_goods_summary).get(get_goods_summary))
         .route("/goods_detail", post(get_goods_detail).get(get_goods_detail))
+        .route("/goods/search", get(search_goods_handler))
+        .route("/goods/suggest", get(suggest_goods_handler))
+        .route("/goods", post(add_goods))
+        .route("/goods/:id", put(modify_goods).delete(remove_goods))
+        .route("/goods/:id/archive", post(archive_goods_handler))
+        .route("/goods/:id/categories", put(modify_goods_categories))
+        .route("/goods/:id/skus", post(add_sku))
+        .route("/goods/:id/skus/:sku_id", put(modify_sku).delete(remove_sku))
+        .route("/goods/:id/skus/:sku_id/inventory", put(modify_sku_inventory))
+        .route("/goods/:id/inventory", get(get_inventory_mappings))
+        .route(
+            "/goods/:id/images",
+            get(get_goods_images)
+                .post(upload_goods_images)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
+        .route("/goods/:id/images/:image_id", delete(remove_goods_image))
+        .route("/images/*key", get(get_image))
+        .route("/categories", get(get_category_tree).post(add_category))
+        .route("/categories/:id", put(modify_category).delete(remove_category))
+        .route("/categories/:id/goods", get(get_category_goods))
+        .route("/categories/:id/breadcrumbs", get(get_breadcrumbs))
+        .route_layer(middleware::from_fn(track_http))
+        .route("/metrics", get(metrics_handler))
+        .merge(maintenance::router(builder.maintenance()))
+        .layer(middleware::from_fn(trace_http))
+        .layer(middleware::from_fn(request_id_http))
         .layer(CorsLayer::permissive())
-        .with_state(db_pool);
+        .with_state(app_state);
 
-    // run it
-    let addr = "127.0.0.1:3004";
-    println!("listening on {}", addr);
+    let grpc = Router::new()
+        .route_service("/grpc.health.v1.Health/*rpc", grpc_health)
+        .fallback_service(get_grpc_router(db_pool));
+    let grpc = GrpcRequestId::new(GrpcTracing::new(GrpcMetrics::new(grpc)));
 
-    //向consul中心注册自己
-    // tokio::spawn(register_consul(&addr, health_check_path));
+    // 将rest和grpc两种路由合并到一起
+    let service = MultiplexService::new(rest, grpc);
 
-    axum::Server::bind(&addr.parse().unwrap())
-        .serve(rest.into_make_service())
-        .await
-        .unwrap();
-    
-}
\ No newline at end of file
+    builder.serve(service).await;
+}
@@ -0,0 +1,357 @@
+use axum::http::StatusCode;
+use common_lib::{
+    internal_error,
+    pagination::{Page, PageRequest},
+};
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    Transaction,
+};
+use tracing::instrument;
+
+use crate::{
+    db_access::db::goods_not_found,
+    models::{
+        category::{Category, CategoryPayload, UpdateCategory},
+        goods::{GoodsSummary, StockState},
+    },
+};
+
+/**
+ * 面包屑最多向上找这么多层，防止数据出错形成环时无限递归
+ */
+const MAX_CATEGORY_DEPTH: i32 = 32;
+
+/**
+ * 所有分类，由调用方组装成树
+ */
+#[instrument(skip(pool))]
+pub async fn query_categories(pool: &PgPool) -> Result<Vec<Category>, (StatusCode, String)> {
+    sqlx::query_as!(
+        Category,
+        "SELECT id, parent_id, name, sort_order, version FROM category"
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)
+}
+
+/**
+ * 从顶级分类到当前分类的路径，分类不存在时返回404
+ */
+#[instrument(skip(pool))]
+pub async fn query_breadcrumbs(
+    pool: &PgPool,
+    category_id: i32,
+) -> Result<Vec<Category>, (StatusCode, String)> {
+    let path = sqlx::query_as!(
+        Category,
+        r#"WITH RECURSIVE path AS (
+            SELECT id, parent_id, name, sort_order, version, 0 AS depth FROM category WHERE id = $1
+            UNION ALL
+            SELECT c.id, c.parent_id, c.name, c.sort_order, c.version, path.depth + 1
+            FROM category c JOIN path ON c.id = path.parent_id
+            WHERE path.depth < $2
+        )
+        SELECT id as "id!", parent_id, name as "name!", sort_order as "sort_order!", version as "version!"
+        FROM path ORDER BY depth DESC"#,
+        category_id,
+        MAX_CATEGORY_DEPTH
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)?;
+
+    if path.is_empty() {
+        return Err(category_not_found(category_id));
+    }
+    Ok(path)
+}
+
+/**
+ * 分类及其所有子分类下的商品，不包括已经下架的商品，按商品id分页
+ */
+#[instrument(skip(pool))]
+pub async fn query_category_goods(
+    pool: &PgPool,
+    category_id: i32,
+    page: &PageRequest,
+) -> Result<Page<GoodsSummary>, (StatusCode, String)> {
+    let after = page.after()?.unwrap_or(0);
+
+    let exists = sqlx::query_scalar!("SELECT id FROM category WHERE id = $1", category_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal_error)?;
+    if exists.is_none() {
+        return Err(category_not_found(category_id));
+    }
+
+    //商品可能同时挂在父分类和子分类下，用EXISTS去重
+    let goods = sqlx::query!(
+        r#"WITH RECURSIVE sub AS (
+            SELECT id FROM category WHERE id = $1
+            UNION
+            SELECT c.id FROM category c JOIN sub ON c.parent_id = sub.id
+        )
+        SELECT s.id, s.name, s.image FROM goods_summary s
+        WHERE NOT s.archived AND s.id > $2::int8
+          AND EXISTS (SELECT 1 FROM goods_category gc JOIN sub ON sub.id = gc.category_id WHERE gc.goods_id = s.id)
+        ORDER BY s.id LIMIT $3"#,
+        category_id,
+        after,
+        page.fetch_limit()
+    )
+    .map(|row| GoodsSummary {
+        id: row.id,
+        goods_name: row.name.unwrap_or_default(),
+        goods_image: row.image.unwrap_or_default(),
+        inventory_count: None,
+        stock_state: StockState::Unknown,
+    })
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)?;
+
+    let total = if page.with_total {
+        let count = sqlx::query_scalar!(
+            r#"WITH RECURSIVE sub AS (
+                SELECT id FROM category WHERE id = $1
+                UNION
+                SELECT c.id FROM category c JOIN sub ON c.parent_id = sub.id
+            )
+            SELECT count(*) as "count!" FROM goods_summary s
+            WHERE NOT s.archived
+              AND EXISTS (SELECT 1 FROM goods_category gc JOIN sub ON sub.id = gc.category_id WHERE gc.goods_id = s.id)"#,
+            category_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(internal_error)?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Page::from_rows(goods, page, |g| g.id as i64).with_total(total))
+}
+
+/**
+ * 新增分类
+ */
+#[instrument(skip(pool))]
+pub async fn create_category(
+    pool: &PgPool,
+    category: &CategoryPayload,
+) -> Result<Category, (StatusCode, String)> {
+    sqlx::query_as!(
+        Category,
+        "INSERT INTO category (parent_id, name, sort_order) VALUES ($1, $2, $3)
+         RETURNING id, parent_id, name, sort_order, version",
+        category.parent_id,
+        category.name,
+        category.sort_order
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(constraint_error)
+}
+
+/**
+ * 修改分类，版本号不一致时返回409。
+ * 移动分类时不能移动到自己或者自己的子分类下，否则分类树会形成环。
+ */
+#[instrument(skip(pool))]
+pub async fn update_category(
+    pool: &PgPool,
+    category_id: i32,
+    update: &UpdateCategory,
+) -> Result<Category, (StatusCode, String)> {
+    let category = &update.category;
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    if let Some(parent_id) = category.parent_id {
+        //两个管理员同时把A移到B下、B移到A下时各自的检查都能通过，所以移动分类时串行执行
+        sqlx::query!("LOCK TABLE category IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut tx)
+            .await
+            .map_err(internal_error)?;
+
+        let cyclic = sqlx::query_scalar!(
+            r#"WITH RECURSIVE sub AS (
+                SELECT id FROM category WHERE id = $1
+                UNION
+                SELECT c.id FROM category c JOIN sub ON c.parent_id = sub.id
+            )
+            SELECT EXISTS (SELECT 1 FROM sub WHERE id = $2) as "cyclic!""#,
+            category_id,
+            parent_id
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(internal_error)?;
+        if cyclic {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "category {} cannot be moved under itself or its descendant {}.",
+                    category_id, parent_id
+                ),
+            ));
+        }
+    }
+
+    let updated = sqlx::query_as!(
+        Category,
+        "UPDATE category SET parent_id = $1, name = $2, sort_order = $3, version = version + 1, update_time = now()
+         WHERE id = $4 AND version = $5 RETURNING id, parent_id, name, sort_order, version",
+        category.parent_id,
+        category.name,
+        category.sort_order,
+        category_id,
+        update.version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(constraint_error)?;
+    let updated = match updated {
+        Some(updated) => updated,
+        None => return Err(version_conflict(&mut tx, category_id, update.version).await),
+    };
+
+    tx.commit().await.map_err(internal_error)?;
+    Ok(updated)
+}
+
+/**
+ * 删除分类，还有子分类时返回409，分类下的商品只解除关联
+ */
+#[instrument(skip(pool))]
+pub async fn delete_category(
+    pool: &PgPool,
+    category_id: i32,
+    version: i32,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let deleted = sqlx::query!(
+        "DELETE FROM category WHERE id = $1 AND version = $2 RETURNING id",
+        category_id,
+        version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(|e| {
+        if foreign_key_violation(&e) {
+            (
+                StatusCode::CONFLICT,
+                format!("category {} has children, delete or move them first.", category_id),
+            )
+        } else {
+            internal_error(e)
+        }
+    })?;
+    if deleted.is_none() {
+        return Err(version_conflict(&mut tx, category_id, version).await);
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+    Ok(())
+}
+
+/**
+ * 替换商品所属的分类，返回商品现在所属的分类
+ */
+#[instrument(skip(pool))]
+pub async fn set_goods_categories(
+    pool: &PgPool,
+    goods_id: i32,
+    category_ids: &[i32],
+) -> Result<Vec<Category>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    //锁住商品，避免和删除商品并发
+    let goods = sqlx::query_scalar!("SELECT id FROM goods_summary WHERE id = $1 FOR UPDATE", goods_id)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(internal_error)?;
+    if goods.is_none() {
+        return Err(goods_not_found(goods_id));
+    }
+
+    sqlx::query!("DELETE FROM goods_category WHERE goods_id = $1", goods_id)
+        .execute(&mut tx)
+        .await
+        .map_err(internal_error)?;
+    sqlx::query!(
+        "INSERT INTO goods_category (goods_id, category_id)
+         SELECT $1, category_id FROM unnest($2::int[]) AS category_id ON CONFLICT DO NOTHING",
+        goods_id,
+        category_ids
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(constraint_error)?;
+
+    let categories = sqlx::query_as!(
+        Category,
+        "SELECT c.id, c.parent_id, c.name, c.sort_order, c.version FROM category c
+         JOIN goods_category gc ON gc.category_id = c.id WHERE gc.goods_id = $1 ORDER BY c.id",
+        goods_id
+    )
+    .fetch_all(&mut tx)
+    .await
+    .map_err(internal_error)?;
+
+    tx.commit().await.map_err(internal_error)?;
+    Ok(categories)
+}
+
+async fn version_conflict(
+    tx: &mut Transaction<'_, Postgres>,
+    category_id: i32,
+    version: i32,
+) -> (StatusCode, String) {
+    let current = sqlx::query_scalar!("SELECT version FROM category WHERE id = $1", category_id)
+        .fetch_optional(&mut *tx)
+        .await;
+    match current {
+        Ok(Some(current)) => (
+            StatusCode::CONFLICT,
+            format!(
+                "category {} has been modified, version {} is expected but current version is {}.",
+                category_id, version, current
+            ),
+        ),
+        Ok(None) => category_not_found(category_id),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn category_not_found(category_id: i32) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("category {} not found.", category_id))
+}
+
+/**
+ * 引用了不存在的分类返回400，同一个父分类下重名返回409
+ */
+fn constraint_error(err: sqlx::Error) -> (StatusCode, String) {
+    if foreign_key_violation(&err) {
+        return (StatusCode::BAD_REQUEST, "category not found.".to_string());
+    }
+    match &err {
+        //23505 unique_violation
+        sqlx::Error::Database(e) if e.code().as_deref() == Some("23505") => (
+            StatusCode::CONFLICT,
+            "category with the same name already exists under the parent.".to_string(),
+        ),
+        _ => internal_error(err),
+    }
+}
+
+/**
+ * 23503 foreign_key_violation
+ */
+fn foreign_key_violation(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::Database(e) if e.code().as_deref() == Some("23503"))
+}
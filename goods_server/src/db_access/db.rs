@@ -1,29 +1,64 @@
-use std::f32::consts::E;
+use std::time::{Duration, Instant};
 
 use axum::http::StatusCode;
-use common_lib::{internal_error, internal_error_dyn};
-use sqlx::postgres::PgPool;
-use tracing::info;
+use common_lib::{
+    internal_error,
+    pagination::{Page, PageRequest},
+};
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    Transaction,
+};
+use tracing::{info, instrument, warn};
 
-use crate::models::goods::{self, GoodsDetail, GoodsSummary};
+use crate::{
+    db_access::sku::{create_default_sku, query_goods_skus, sync_goods_price},
+    models::{
+        goods::{
+            GoodsDetail, GoodsPayload, GoodsSearchHit, GoodsSuggestion, GoodsSummary,
+            SearchCursor, SearchGoodsRequest, StockState, Suggestions, UpdateGoods,
+        },
+        image::ImageRecord,
+        sku::variant_options,
+    },
+};
 
+/**
+ * 搜索提示的总耗时上限，超过之后只返回已经查到的结果
+ */
+const SUGGEST_BUDGET: Duration = Duration::from_millis(150);
+/**
+ * 输入少于这么多个字符时只做前缀匹配，太短的输入三元组匹配不出有意义的结果
+ */
+const FUZZY_MIN_CHARS: usize = 3;
+/**
+ * 模糊匹配的相似度下限，比pg_trgm默认的0.6低，让拼错几个字母的输入也能匹配上
+ */
+const FUZZY_THRESHOLD: &str = "0.3";
+/**
+ * 超过数据库语句超时之后再等这么久才放弃，正常情况下由数据库先取消语句
+ */
+const SUGGEST_GRACE: Duration = Duration::from_millis(50);
+
+#[instrument(skip(pool))]
 pub async fn query_goods_summary_list(
     pool: &PgPool,
-    page: i64,
-    page_size: i64,
-) -> Result<Vec<GoodsSummary>, (StatusCode, String)> {
-    let offset = page_size * page;
+    page: &PageRequest,
+) -> Result<Page<GoodsSummary>, (StatusCode, String)> {
+    let after = page.after()?.unwrap_or(0);
 
     let goods = sqlx::query!(
-        "SELECT * FROM goods_summary LIMIT $1 OFFSET $2",
-        page_size,
-        offset
+        "SELECT * FROM goods_summary WHERE id > $1::int8 AND NOT archived ORDER BY id LIMIT $2",
+        after,
+        page.fetch_limit()
     )
     .map({
         |row| GoodsSummary {
             id: row.id,
             goods_name: row.name.unwrap_or_default(),
             goods_image: row.image.unwrap_or_default(),
+            inventory_count: None,
+            stock_state: StockState::Unknown,
         }
     })
     .fetch_all(pool)
@@ -32,32 +67,524 @@ pub async fn query_goods_summary_list(
 
     // info!("get_user size: {}", users);
 
-    Ok(goods)
+    let total = if page.with_total {
+        let count = sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM goods_summary WHERE NOT archived"#)
+            .fetch_one(pool)
+            .await
+            .map_err(internal_error)?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Page::from_rows(goods, page, |g| g.id as i64).with_total(total))
 }
 
+#[instrument(skip(pool))]
 pub async fn query_goods_detail(
     pool: &PgPool,
     goods_id: i32,
 ) -> Result<GoodsDetail, (StatusCode, String)> {
-    println!("query_goods_detail id: {}", goods_id);
-
-    let goods_detail = sqlx::query!("SELECT * FROM goods_detail where id = $1", goods_id)
-        .map({
-            |row| {
-                let price = row.unit_price;
-                GoodsDetail {
-                    id: row.id,
-                    goods_name: row.name.unwrap_or_default(),
-                    goods_image: row.image.unwrap_or_default(),
-                    unit_price: price,
-                    goods_des: row.des.unwrap_or_default(),
-                    inventory_count: 0,
-                }
+    info!("query_goods_detail id: {}", goods_id);
+
+    let mut goods_detail = sqlx::query!(
+        "SELECT d.id, d.name, d.image, d.des, d.unit_price, s.archived, s.version FROM goods_detail d
+         JOIN goods_summary s ON s.id = d.id WHERE d.id = $1",
+        goods_id
+    )
+    .map({
+        |row| {
+            let price = row.unit_price;
+            GoodsDetail {
+                id: row.id,
+                goods_name: row.name.unwrap_or_default(),
+                goods_image: row.image.unwrap_or_default(),
+                unit_price: price,
+                goods_des: row.des.unwrap_or_default(),
+                inventory_count: None,
+                stock_state: StockState::Unknown,
+                archived: row.archived,
+                version: row.version,
+                options: vec![],
+                skus: vec![],
             }
-        })
-        .fetch_one(pool)
+        }
+    })
+    .fetch_optional(pool)
+    .await
+    .map_err(internal_error)?
+    .ok_or_else(|| goods_not_found(goods_id))?;
+
+    goods_detail.skus = query_goods_skus(pool, goods_id).await?;
+    goods_detail.options = variant_options(&goods_detail.skus);
+    Ok(goods_detail)
+}
+
+/**
+ * 全文搜索商品，不包括已经下架的商品。
+ * 每种排序方式都换算成一个排序值，结果按 (排序值降序, id) 排列，after是上一页最后一条结果，
+ * 下一页只查它之后的结果（keyset分页），翻页的代价不随页数增加，翻页期间数据变化也不会重复或者漏掉。
+ * 高亮片段比较耗时，只对本页的结果生成。
+ */
+#[instrument(skip(pool))]
+pub async fn search_goods(
+    pool: &PgPool,
+    search: &SearchGoodsRequest,
+    after: Option<SearchCursor>,
+    limit: i64,
+) -> Result<Vec<GoodsSearchHit>, (StatusCode, String)> {
+    let hits = sqlx::query!(
+        r#"WITH query AS (SELECT websearch_to_tsquery('simple', $1) AS q),
+        matched AS (
+            SELECT d.id, d.name, d.image, d.des, d.unit_price,
+                   ts_rank_cd(d.search_vector, query.q) AS rank,
+                   CASE $4
+                       WHEN 'price_asc' THEN -d.unit_price::float8
+                       WHEN 'price_desc' THEN d.unit_price::float8
+                       WHEN 'newest' THEN (extract(epoch FROM s.create_time) * 1000000)::float8
+                       ELSE ts_rank_cd(d.search_vector, query.q)::float8
+                   END AS sort_key
+            FROM goods_detail d JOIN goods_summary s ON s.id = d.id, query
+            WHERE d.search_vector @@ query.q AND NOT s.archived
+              AND ($2::int IS NULL OR d.unit_price >= $2)
+              AND ($3::int IS NULL OR d.unit_price <= $3)
+        ),
+        page AS (
+            SELECT * FROM matched
+            WHERE $5::float8 IS NULL OR sort_key < $5 OR (sort_key = $5 AND id > $6)
+            ORDER BY sort_key DESC, id LIMIT $7
+        )
+        SELECT page.id as "id!", page.name, page.image, page.unit_price as "unit_price!",
+               page.rank as "rank!", page.sort_key as "sort_key!",
+               ts_headline('simple', coalesce(page.name, ''), query.q,
+                   'StartSel=<em>, StopSel=</em>, HighlightAll=true') as "highlighted_name!",
+               ts_headline('simple', coalesce(page.des, ''), query.q,
+                   'StartSel=<em>, StopSel=</em>, MinWords=5, MaxWords=20') as "snippet!"
+        FROM page, query ORDER BY page.sort_key DESC, page.id"#,
+        search.q,
+        search.min_price,
+        search.max_price,
+        search.sort.as_str(),
+        after.map(|cursor| cursor.sort_key),
+        after.map(|cursor| cursor.id).unwrap_or(0),
+        limit
+    )
+    .map(|row| GoodsSearchHit {
+        id: row.id,
+        goods_name: row.name.unwrap_or_default(),
+        goods_image: row.image.unwrap_or_default(),
+        unit_price: row.unit_price,
+        highlighted_name: row.highlighted_name,
+        snippet: row.snippet,
+        rank: row.rank,
+        inventory_count: None,
+        stock_state: StockState::Unknown,
+        sort_key: row.sort_key,
+    })
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)?;
+
+    Ok(hits)
+}
+
+/**
+ * 搜索结果的总数，不考虑库存
+ */
+#[instrument(skip(pool))]
+pub async fn count_search_goods(
+    pool: &PgPool,
+    search: &SearchGoodsRequest,
+) -> Result<i64, (StatusCode, String)> {
+    sqlx::query_scalar!(
+        r#"SELECT count(*) as "count!"
+        FROM goods_detail d JOIN goods_summary s ON s.id = d.id
+        WHERE d.search_vector @@ websearch_to_tsquery('simple', $1) AND NOT s.archived
+          AND ($2::int IS NULL OR d.unit_price >= $2)
+          AND ($3::int IS NULL OR d.unit_price <= $3)"#,
+        search.q,
+        search.min_price,
+        search.max_price
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(internal_error)
+}
+
+/**
+ * 搜索提示：先按名称前缀匹配，前缀匹配的结果不够时再用pg_trgm按相似度模糊匹配拼错的名称。
+ * 每条语句都设置了数据库的语句超时，整体不超过SUGGEST_BUDGET；
+ * 模糊匹配超时只返回前缀匹配的结果，前缀匹配超时返回空结果，都标记为degraded。
+ */
+#[instrument(skip(pool))]
+pub async fn suggest_goods(
+    pool: &PgPool,
+    q: &str,
+    limit: i64,
+) -> Result<Suggestions, (StatusCode, String)> {
+    let deadline = Instant::now() + SUGGEST_BUDGET;
+    let prefix = q.trim().to_lowercase();
+    if prefix.is_empty() {
+        return Ok(Suggestions { items: vec![], degraded: false });
+    }
+
+    let mut items = match within(deadline, suggest_by_prefix(pool, &prefix, limit, deadline)).await {
+        Ok(items) => items,
+        Err(e) => return degraded(e, vec![]),
+    };
+    if items.len() as i64 >= limit || prefix.chars().count() < FUZZY_MIN_CHARS {
+        return Ok(Suggestions { items, degraded: false });
+    }
+
+    let exclude: Vec<i32> = items.iter().map(|s| s.id).collect();
+    let fuzzy = suggest_by_similarity(pool, &prefix, limit - items.len() as i64, &exclude, deadline);
+    match within(deadline, fuzzy).await {
+        Ok(fuzzy) => {
+            items.extend(fuzzy);
+            Ok(Suggestions { items, degraded: false })
+        }
+        Err(e) => degraded(e, items),
+    }
+}
+
+async fn suggest_by_prefix(
+    pool: &PgPool,
+    prefix: &str,
+    limit: i64,
+    deadline: Instant,
+) -> Result<Vec<GoodsSuggestion>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    set_statement_timeout(&mut tx, deadline).await?;
+
+    //text_pattern_ops索引上的范围查询，相当于 LIKE 'prefix%'，
+    //LIKE的参数在通用执行计划中不能走索引，范围查询可以
+    let items = sqlx::query!(
+        r#"SELECT id, name as "name!" FROM goods_summary
+        WHERE NOT archived AND lower(name) ~>=~ $1 AND ($2::text IS NULL OR lower(name) ~<~ $2)
+        ORDER BY length(name), name LIMIT $3"#,
+        prefix,
+        prefix_upper_bound(prefix),
+        limit
+    )
+    .map(|row| GoodsSuggestion {
+        id: row.id,
+        goods_name: row.name,
+        score: 1.0,
+    })
+    .fetch_all(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(items)
+}
+
+async fn suggest_by_similarity(
+    pool: &PgPool,
+    q: &str,
+    limit: i64,
+    exclude: &[i32],
+    deadline: Instant,
+) -> Result<Vec<GoodsSuggestion>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    set_statement_timeout(&mut tx, deadline).await?;
+    sqlx::query_scalar!(
+        "SELECT set_config('pg_trgm.word_similarity_threshold', $1, true)",
+        FUZZY_THRESHOLD
+    )
+    .fetch_one(&mut tx)
+    .await?;
+
+    //<% 使用三元组索引过滤，word_similarity按输入和商品名中最相近的一段计算，适合只输入了一部分的情况
+    let items = sqlx::query!(
+        r#"SELECT id, name as "name!", word_similarity($1, name) as "score!" FROM goods_summary
+        WHERE NOT archived AND $1 <% name AND NOT (id = ANY($3))
+        ORDER BY word_similarity($1, name) DESC, length(name), id LIMIT $2"#,
+        q,
+        limit,
+        exclude
+    )
+    .map(|row| GoodsSuggestion {
+        id: row.id,
+        goods_name: row.name,
+        score: row.score,
+    })
+    .fetch_all(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(items)
+}
+
+/**
+ * 把事务中语句的超时时间设置为距离deadline剩余的时间
+ */
+async fn set_statement_timeout(
+    tx: &mut Transaction<'_, Postgres>,
+    deadline: Instant,
+) -> Result<(), sqlx::Error> {
+    let remaining = deadline.saturating_duration_since(Instant::now()).as_millis().max(1);
+    sqlx::query_scalar!(
+        "SELECT set_config('statement_timeout', $1, true)",
+        remaining.to_string()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/**
+ * 以prefix开头的字符串都小于返回值：把最后一个字符加1。
+ * 按字节比较时utf8的顺序和码点的顺序一致，所以对text_pattern_ops成立。
+ */
+fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut upper: Vec<char> = prefix.chars().collect();
+    while let Some(last) = upper.pop() {
+        //跳过代理区
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            upper.push(next);
+            return Some(upper.into_iter().collect());
+        }
+    }
+    None
+}
+
+/**
+ * 等待连接池等数据库超时管不到的地方由这里兜底
+ */
+async fn within<T>(
+    deadline: Instant,
+    future: impl std::future::Future<Output = Result<T, sqlx::Error>>,
+) -> Result<T, sqlx::Error> {
+    let deadline = tokio::time::Instant::from_std(deadline + SUGGEST_GRACE);
+    tokio::time::timeout_at(deadline, future).await.unwrap_or_else(|_| {
+        Err(sqlx::Error::Io(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "suggest budget exceeded.",
+        )))
+    })
+}
+
+/**
+ * 超时的时候返回已经查到的结果，其它错误照常返回
+ */
+fn degraded(err: sqlx::Error, items: Vec<GoodsSuggestion>) -> Result<Suggestions, (StatusCode, String)> {
+    let timeout = match &err {
+        sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Io(e) => e.kind() == std::io::ErrorKind::TimedOut,
+        //57014 query_canceled，语句超时
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("57014"),
+        _ => false,
+    };
+    if !timeout {
+        return Err(internal_error(err));
+    }
+    warn!("suggest goods exceeded {:?}, return {} suggestions: {}", SUGGEST_BUDGET, items.len(), err);
+    Ok(Suggestions { items, degraded: true })
+}
+
+/**
+ * 新增商品，概要、详情和默认的SKU在同一个事务中写入，返回新商品的详情
+ */
+#[instrument(skip(pool))]
+pub async fn create_goods(
+    pool: &PgPool,
+    goods: &GoodsPayload,
+) -> Result<GoodsDetail, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let summary = sqlx::query!(
+        "INSERT INTO goods_summary (name, image) VALUES ($1, $2) RETURNING id, version",
+        goods.goods_name,
+        goods.goods_image
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(internal_error)?;
+
+    sqlx::query!(
+        "INSERT INTO goods_detail (id, name, image, des, unit_price) VALUES ($1, $2, $3, $4, $5)",
+        summary.id,
+        goods.goods_name,
+        goods.goods_image,
+        goods.goods_des,
+        goods.unit_price
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(internal_error)?;
+
+    create_default_sku(&mut tx, summary.id, goods.unit_price).await?;
+
+    tx.commit().await.map_err(internal_error)?;
+
+    query_goods_detail(pool, summary.id).await
+}
+
+/**
+ * 修改商品，版本号不一致时返回409，概要和详情在同一个事务中修改，单价的修改见sync_goods_price。
+ * 不修改图片，主图由上传的图片决定，见refresh_main_image
+ */
+#[instrument(skip(pool))]
+pub async fn update_goods(
+    pool: &PgPool,
+    goods_id: i32,
+    update: &UpdateGoods,
+) -> Result<GoodsDetail, (StatusCode, String)> {
+    let goods = &update.goods;
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let summary = sqlx::query!(
+        "UPDATE goods_summary SET name = $1, version = version + 1, update_time = now()
+         WHERE id = $2 AND version = $3 RETURNING id",
+        goods.goods_name,
+        goods_id,
+        update.version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if summary.is_none() {
+        return Err(version_conflict(&mut tx, goods_id, update.version).await);
+    }
+
+    let current_price = sqlx::query_scalar!("SELECT unit_price FROM goods_detail WHERE id = $1", goods_id)
+        .fetch_one(&mut tx)
         .await
         .map_err(internal_error)?;
+    sync_goods_price(&mut tx, goods_id, current_price, goods.unit_price).await?;
 
-    Ok(goods_detail)
+    sqlx::query!(
+        "UPDATE goods_detail SET name = $1, des = $2, unit_price = $3 WHERE id = $4",
+        goods.goods_name,
+        goods.goods_des,
+        goods.unit_price,
+        goods_id
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(internal_error)?;
+
+    tx.commit().await.map_err(internal_error)?;
+
+    query_goods_detail(pool, goods_id).await
+}
+
+/**
+ * 下架商品，下架后不再出现在商品列表中，已经下架的商品重复下架只增加版本号
+ */
+#[instrument(skip(pool))]
+pub async fn archive_goods(
+    pool: &PgPool,
+    goods_id: i32,
+    version: i32,
+) -> Result<GoodsDetail, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let archived = sqlx::query!(
+        "UPDATE goods_summary SET archived = true, version = version + 1, update_time = now()
+         WHERE id = $1 AND version = $2 RETURNING id",
+        goods_id,
+        version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if archived.is_none() {
+        return Err(version_conflict(&mut tx, goods_id, version).await);
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+
+    query_goods_detail(pool, goods_id).await
+}
+
+/**
+ * 删除商品，详情、图片记录随概要一起删除，返回删除的图片，由调用方删除对象存储中的文件
+ */
+#[instrument(skip(pool))]
+pub async fn delete_goods(
+    pool: &PgPool,
+    goods_id: i32,
+    version: i32,
+) -> Result<Vec<ImageRecord>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let images = sqlx::query_as!(
+        ImageRecord,
+        "SELECT id, goods_id, image_key, content_type, extension, thumbnail_ext, width, height, sort_order
+         FROM goods_image WHERE goods_id = $1",
+        goods_id
+    )
+    .fetch_all(&mut tx)
+    .await
+    .map_err(internal_error)?;
+
+    let deleted = sqlx::query!(
+        "DELETE FROM goods_summary WHERE id = $1 AND version = $2 RETURNING id",
+        goods_id,
+        version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if deleted.is_none() {
+        return Err(version_conflict(&mut tx, goods_id, version).await);
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+    Ok(images)
+}
+
+/**
+ * 按版本号修改失败时区分商品不存在（404）和版本号不一致（409）
+ */
+async fn version_conflict(
+    tx: &mut Transaction<'_, Postgres>,
+    goods_id: i32,
+    version: i32,
+) -> (StatusCode, String) {
+    let current = sqlx::query_scalar!("SELECT version FROM goods_summary WHERE id = $1", goods_id)
+        .fetch_optional(&mut *tx)
+        .await;
+    match current {
+        Ok(Some(current)) => (
+            StatusCode::CONFLICT,
+            format!(
+                "goods {} has been modified, version {} is expected but current version is {}.",
+                goods_id, version, current
+            ),
+        ),
+        Ok(None) => goods_not_found(goods_id),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub fn goods_not_found(goods_id: i32) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("goods {} not found.", goods_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_upper_bound() {
+        assert_eq!(prefix_upper_bound("abc").as_deref(), Some("abd"));
+        assert_eq!(prefix_upper_bound("").as_deref(), None);
+
+        //最后一个字符已经是最大的码点时去掉它，把前一个字符加1
+        assert_eq!(prefix_upper_bound("a\u{10FFFF}").as_deref(), Some("b"));
+        assert_eq!(prefix_upper_bound("\u{10FFFF}\u{10FFFF}"), None);
+        //跳过代理区
+        assert_eq!(prefix_upper_bound("a\u{D7FF}").as_deref(), Some("a\u{E000}"));
+
+        //多字节的中文按字符加1，不能在字节中间截断
+        let upper = prefix_upper_bound("书").unwrap();
+        assert_eq!(upper, "\u{4E67}");
+        for name in ["书", "书籍", "书\u{10FFFF}"] {
+            assert!(name.as_bytes() < upper.as_bytes());
+        }
+        assert!("乧".as_bytes() >= upper.as_bytes());
+    }
 }
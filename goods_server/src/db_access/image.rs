@@ -0,0 +1,225 @@
+use axum::http::StatusCode;
+use common_lib::internal_error;
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    Transaction,
+};
+use tracing::instrument;
+
+use crate::{
+    db_access::db::goods_not_found,
+    models::image::ImageRecord,
+    storage::{image_url, thumbnail::LIST_THUMBNAIL_SIZE},
+};
+
+/**
+ * 每个商品最多的图片数量
+ */
+pub const MAX_IMAGES_PER_GOODS: i64 = 10;
+
+/**
+ * 新上传的图片，已经写入对象存储
+ */
+#[derive(Debug, Clone)]
+pub struct NewImage {
+    pub image_key: String,
+    pub content_type: String,
+    pub extension: String,
+    pub thumbnail_ext: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+/**
+ * 商品的所有图片，按sort_order排序，商品不存在时返回404
+ */
+#[instrument(skip(pool))]
+pub async fn query_goods_images(
+    pool: &PgPool,
+    goods_id: i32,
+) -> Result<Vec<ImageRecord>, (StatusCode, String)> {
+    let exists = sqlx::query_scalar!("SELECT id FROM goods_summary WHERE id = $1", goods_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal_error)?;
+    if exists.is_none() {
+        return Err(goods_not_found(goods_id));
+    }
+
+    sqlx::query_as!(
+        ImageRecord,
+        "SELECT id, goods_id, image_key, content_type, extension, thumbnail_ext, width, height, sort_order
+         FROM goods_image WHERE goods_id = $1 ORDER BY sort_order, id",
+        goods_id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)
+}
+
+/**
+ * 按对象存储中的目录找到图片记录，图片已经删除时返回None
+ */
+#[instrument(skip(pool))]
+pub async fn find_image_by_key(pool: &PgPool, image_key: &str) -> Result<Option<ImageRecord>, (StatusCode, String)> {
+    sqlx::query_as!(
+        ImageRecord,
+        "SELECT id, goods_id, image_key, content_type, extension, thumbnail_ext, width, height, sort_order
+         FROM goods_image WHERE image_key = $1",
+        image_key
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(internal_error)
+}
+
+/**
+ * 检查商品还能再上传多少张图片，处理图片之前先检查一次，避免白白生成缩略图
+ */
+#[instrument(skip(pool))]
+pub async fn remaining_image_slots(pool: &PgPool, goods_id: i32) -> Result<i64, (StatusCode, String)> {
+    let count = query_goods_images(pool, goods_id).await?.len() as i64;
+    Ok((MAX_IMAGES_PER_GOODS - count).max(0))
+}
+
+/**
+ * 保存新上传的图片，排在已有图片的后面，商品还没有图片时第一张作为主图
+ */
+#[instrument(skip(pool))]
+pub async fn insert_goods_images(
+    pool: &PgPool,
+    goods_id: i32,
+    images: &[NewImage],
+) -> Result<Vec<ImageRecord>, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    //锁住商品，并发上传时图片数量的检查和排序号不会出错
+    let goods = sqlx::query_scalar!("SELECT id FROM goods_summary WHERE id = $1 FOR UPDATE", goods_id)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(internal_error)?;
+    if goods.is_none() {
+        return Err(goods_not_found(goods_id));
+    }
+
+    let current = sqlx::query!(
+        r#"SELECT count(*) as "count!", coalesce(max(sort_order), -1) as "max_sort_order!"
+        FROM goods_image WHERE goods_id = $1"#,
+        goods_id
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if current.count + images.len() as i64 > MAX_IMAGES_PER_GOODS {
+        return Err(too_many_images(goods_id));
+    }
+
+    let mut records = vec![];
+    for (i, image) in images.iter().enumerate() {
+        let record = sqlx::query_as!(
+            ImageRecord,
+            "INSERT INTO goods_image (goods_id, image_key, content_type, extension, thumbnail_ext, width, height, sort_order)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             RETURNING id, goods_id, image_key, content_type, extension, thumbnail_ext, width, height, sort_order",
+            goods_id,
+            image.image_key,
+            image.content_type,
+            image.extension,
+            image.thumbnail_ext,
+            image.width,
+            image.height,
+            current.max_sort_order + 1 + i as i32
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(internal_error)?;
+        records.push(record);
+    }
+
+    refresh_main_image(&mut tx, goods_id).await?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok(records)
+}
+
+/**
+ * 删除图片记录，返回删除的记录，由调用方删除对象存储中的文件
+ */
+#[instrument(skip(pool))]
+pub async fn delete_goods_image(
+    pool: &PgPool,
+    goods_id: i32,
+    image_id: i32,
+) -> Result<ImageRecord, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let record = sqlx::query_as!(
+        ImageRecord,
+        "DELETE FROM goods_image WHERE id = $1 AND goods_id = $2
+         RETURNING id, goods_id, image_key, content_type, extension, thumbnail_ext, width, height, sort_order",
+        image_id,
+        goods_id
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(internal_error)?
+    .ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("image {} of goods {} not found.", image_id, goods_id),
+        )
+    })?;
+
+    refresh_main_image(&mut tx, goods_id).await?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok(record)
+}
+
+/**
+ * 把商品的图片字段更新为主图：列表中使用缩略图，详情中使用原图。没有图片时清空。
+ */
+async fn refresh_main_image(
+    tx: &mut Transaction<'_, Postgres>,
+    goods_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    let main = sqlx::query_as!(
+        ImageRecord,
+        "SELECT id, goods_id, image_key, content_type, extension, thumbnail_ext, width, height, sort_order
+         FROM goods_image WHERE goods_id = $1 ORDER BY sort_order, id LIMIT 1",
+        goods_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+
+    let (summary_image, detail_image) = match &main {
+        Some(main) => (
+            image_url(&main.thumbnail_key(LIST_THUMBNAIL_SIZE)),
+            image_url(&main.original_key()),
+        ),
+        None => (String::new(), String::new()),
+    };
+    sqlx::query!(
+        "UPDATE goods_summary SET image = $1, update_time = now() WHERE id = $2",
+        summary_image,
+        goods_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    sqlx::query!(
+        "UPDATE goods_detail SET image = $1 WHERE id = $2",
+        detail_image,
+        goods_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    Ok(())
+}
+
+pub fn too_many_images(goods_id: i32) -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        format!("goods {} can have at most {} images.", goods_id, MAX_IMAGES_PER_GOODS),
+    )
+}
@@ -1 +1,5 @@
-pub mod db;
\ No newline at end of file
+pub mod category;
+pub mod db;
+pub mod image;
+pub mod repo;
+pub mod sku;
\ No newline at end of file
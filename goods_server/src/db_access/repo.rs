@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common_lib::{health::check_srv_health, telemetry::inject_trace_context};
+use consul_reg_lib::{discover::BalancedChannel, discovery::ServiceDiscovery};
+use futures::future::{join_all, BoxFuture};
+use tokio::time::Instant;
+use tracing::{instrument, warn};
+
+use self::inventory_proto::{inventory_service_client::InventoryServiceClient, QueryStockRequest};
+
+mod inventory_proto {
+    tonic::include_proto!("inventory");
+}
+
+/**
+ * 库存服务的grpc服务全名
+ */
+const INVENTORY_GRPC_SERVICE: &str = "inventory.InventoryService";
+/**
+ * 查询库存的超时时间，库存只用于展示，宁可显示unknown也不能拖慢商品接口
+ */
+const STOCK_TIMEOUT: Duration = Duration::from_millis(300);
+/**
+ * 库存在本地缓存的时间
+ */
+const STOCK_CACHE_TTL: Duration = Duration::from_secs(5);
+/**
+ * 查询失败之后这段时间内不再请求库存服务，直接返回unknown
+ */
+const FAILURE_BACKOFF: Duration = Duration::from_secs(2);
+/**
+ * 一次请求最多查询的库存数量，和库存服务的上限一致，超过时分成多次并发请求
+ */
+const MAX_STOCK_QUERY: usize = 200;
+/**
+ * 缓存超过这个数量时清理过期的库存
+ */
+const MAX_CACHED_STOCKS: usize = 10_000;
+
+/**
+ * 就绪检查：库存服务是否可用。
+ * 库存服务不可用时商品接口仍然可以使用，只是库存显示为unknown，所以作为可选的检查项。
+ */
+pub async fn check_inventory_health(
+    discovery: Arc<dyn ServiceDiscovery>,
+    inventory_srv_name: String,
+) -> Result<(), String> {
+    check_srv_health(discovery, &inventory_srv_name, INVENTORY_GRPC_SERVICE).await
+}
+
+/**
+ * 批量查询库存，返回 库存id -> 数量
+ */
+type StockQuery = Arc<dyn Fn(Vec<i32>) -> BoxFuture<'static, Result<HashMap<i32, i32>, String>> + Send + Sync>;
+
+#[derive(Default)]
+struct CachedStocks {
+    stocks: HashMap<i32, (Instant, i32)>,
+    failed_at: Option<Instant>,
+}
+
+/**
+ * 带缓存的库存查询，clone之后共享同一份缓存。
+ * 库存id是SKU关联的库存服务中的id。
+ */
+#[derive(Clone)]
+pub struct StockCache {
+    query: StockQuery,
+    cached: Arc<Mutex<CachedStocks>>,
+}
+
+impl StockCache {
+    pub fn new(inventory_channel: BalancedChannel) -> Self {
+        Self::with_query(move |inventory_ids| {
+            let inventory_channel = inventory_channel.clone();
+            Box::pin(async move { query_stock_call(inventory_channel, &inventory_ids).await })
+        })
+    }
+
+    fn with_query<F>(query: F) -> Self
+    where
+        F: Fn(Vec<i32>) -> BoxFuture<'static, Result<HashMap<i32, i32>, String>> + Send + Sync + 'static,
+    {
+        Self {
+            query: Arc::new(query),
+            cached: Arc::new(Mutex::new(CachedStocks::default())),
+        }
+    }
+
+    /**
+     * 查询库存服务，超过STOCK_TIMEOUT没有返回时按失败处理
+     */
+    async fn query(&self, inventory_ids: Vec<i32>) -> Result<HashMap<i32, i32>, String> {
+        tokio::time::timeout(STOCK_TIMEOUT, (self.query)(inventory_ids))
+            .await
+            .map_err(|_| format!("query stock timeout after {:?}.", STOCK_TIMEOUT))?
+    }
+
+    /**
+     * 查询库存，返回 库存id -> 数量。
+     * 缓存中没有的库存按MAX_STOCK_QUERY分批并发查询，库存服务中不存在的按0处理；
+     * 库存服务不可用或者超时时，查询失败的那些库存不会出现在结果中。
+     */
+    #[instrument(skip(self))]
+    pub async fn stocks(&self, inventory_ids: &[i32]) -> HashMap<i32, i32> {
+        let (mut stocks, missing) = {
+            let cached = self.cached.lock().unwrap();
+            let mut stocks = HashMap::new();
+            let mut missing = vec![];
+            for id in inventory_ids {
+                if stocks.contains_key(id) || missing.contains(id) {
+                    continue;
+                }
+                match cached.stocks.get(id) {
+                    Some((at, count)) if at.elapsed() < STOCK_CACHE_TTL => {
+                        stocks.insert(*id, *count);
+                    }
+                    _ => missing.push(*id),
+                }
+            }
+            if cached.failed_at.is_some_and(|at| at.elapsed() < FAILURE_BACKOFF) {
+                return stocks;
+            }
+            (stocks, missing)
+        };
+        if missing.is_empty() {
+            return stocks;
+        }
+
+        let chunks: Vec<Vec<i32>> = missing.chunks(MAX_STOCK_QUERY).map(<[i32]>::to_vec).collect();
+        let results = join_all(chunks.iter().map(|chunk| self.query(chunk.clone()))).await;
+
+        let mut cached = self.cached.lock().unwrap();
+        if cached.stocks.len() > MAX_CACHED_STOCKS {
+            cached.stocks.retain(|_, (at, _)| at.elapsed() < STOCK_CACHE_TTL);
+        }
+        let now = Instant::now();
+        let mut failed = None;
+        for (chunk, result) in chunks.into_iter().zip(results) {
+            match result {
+                Ok(found) => {
+                    for id in chunk {
+                        let count = found.get(&id).copied().unwrap_or(0);
+                        cached.stocks.insert(id, (now, count));
+                        stocks.insert(id, count);
+                    }
+                }
+                Err(e) => failed = Some(e),
+            }
+        }
+        match failed {
+            Some(e) => {
+                warn!("query stock failed, stock is unknown for {:?}: {}", FAILURE_BACKOFF, e);
+                cached.failed_at = Some(now);
+            }
+            None => cached.failed_at = None,
+        }
+        stocks
+    }
+
+    /**
+     * 库存服务中是否有这条库存记录，不使用缓存，关联SKU和库存之前检查
+     */
+    #[instrument(skip(self))]
+    pub async fn inventory_exists(&self, inventory_id: i32) -> Result<bool, String> {
+        let found = self.query(vec![inventory_id]).await?;
+        Ok(found.contains_key(&inventory_id))
+    }
+}
+
+/**
+ * 批量查询库存call
+ */
+#[instrument(skip(inventory_channel))]
+async fn query_stock_call(
+    inventory_channel: BalancedChannel,
+    inventory_ids: &[i32],
+) -> Result<HashMap<i32, i32>, String> {
+    //拦截器把当前的追踪上下文写入grpc metadata，让库存服务的span挂到同一条链路上
+    let mut client = InventoryServiceClient::with_interceptor(inventory_channel, inject_trace_context);
+
+    let mut req = tonic::Request::new(QueryStockRequest {
+        inventory_ids: inventory_ids.to_vec(),
+    });
+    req.set_timeout(STOCK_TIMEOUT);
+
+    let response = client
+        .query_stock(req)
+        .await
+        .map_err(|err| err.to_string())?
+        .into_inner();
+
+    Ok(response
+        .stocks
+        .into_iter()
+        .map(|stock| (stock.inventory_id, stock.count))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /**
+     * 假的库存服务：每个库存的数量等于id，记录被调用的次数，delay为响应时间，fail时返回错误。
+     * 和库存服务一样，一次查询超过MAX_STOCK_QUERY个库存时返回错误
+     */
+    fn fake_cache(delay: Duration, fail: bool) -> (StockCache, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let cache = StockCache::with_query(move |ids| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                if fail {
+                    return Err("inventory unavailable".to_string());
+                }
+                if ids.len() > MAX_STOCK_QUERY {
+                    return Err(format!("too many inventory ids {}.", ids.len()));
+                }
+                //库存服务中不存在的库存不返回
+                Ok(ids.into_iter().filter(|id| *id != 404).map(|id| (id, id)).collect())
+            })
+        });
+        (cache, calls)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stocks_are_cached_until_ttl() {
+        let (cache, calls) = fake_cache(Duration::from_millis(10), false);
+
+        let stocks = cache.stocks(&[1, 2, 404]).await;
+        assert_eq!(stocks, HashMap::from([(1, 1), (2, 2), (404, 0)]));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        //缓存中已有的不再查询，只查询新的
+        tokio::time::advance(STOCK_CACHE_TTL - Duration::from_secs(1)).await;
+        assert_eq!(cache.stocks(&[1, 2]).await.len(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stocks(&[1, 3]).await, HashMap::from([(1, 1), (3, 3)]));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        cache.stocks(&[1]).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_backs_off() {
+        let (cache, calls) = fake_cache(STOCK_TIMEOUT + Duration::from_millis(100), false);
+
+        assert!(cache.stocks(&[1]).await.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache.inventory_exists(1).await.is_err());
+
+        //失败之后的一段时间内直接返回unknown，不再请求库存服务
+        calls.store(0, Ordering::SeqCst);
+        tokio::time::advance(FAILURE_BACKOFF - Duration::from_millis(500)).await;
+        assert!(cache.stocks(&[1]).await.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        cache.stocks(&[1]).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failure_keeps_cached_stocks() {
+        let (cache, _) = fake_cache(Duration::ZERO, false);
+        cache.stocks(&[1]).await;
+
+        let (failing, calls) = fake_cache(Duration::ZERO, true);
+        let cache = StockCache {
+            query: failing.query,
+            cached: cache.cached,
+        };
+        //缓存中的库存照常返回，查询失败的库存不出现在结果中
+        assert_eq!(cache.stocks(&[1, 2]).await, HashMap::from([(1, 1)]));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stocks(&[1, 2]).await, HashMap::from([(1, 1)]));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_large_query_is_split_into_chunks() {
+        let (cache, calls) = fake_cache(Duration::from_millis(10), false);
+
+        //一页100个商品，每个3个SKU，超过库存服务一次查询的上限
+        let ids: Vec<i32> = (1..=300).chain(1..=10).collect();
+        let stocks = cache.stocks(&ids).await;
+        assert_eq!(stocks.len(), 300);
+        assert!(stocks.iter().all(|(id, count)| id == count));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        //没有失败，之后的查询不会被退避
+        let more: Vec<i32> = (301..=750).collect();
+        assert_eq!(cache.stocks(&more).await.len(), 450);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+}
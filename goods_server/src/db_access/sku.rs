@@ -0,0 +1,529 @@
+use std::collections::BTreeMap;
+
+use axum::http::StatusCode;
+use common_lib::internal_error;
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    types::Json,
+    PgExecutor, Transaction,
+};
+use tracing::instrument;
+
+use crate::{
+    db_access::db::goods_not_found,
+    models::{
+        goods::StockState,
+        sku::{InventoryMapping, ResolvedSku, SetInventory, Sku, SkuPayload, UpdateSku},
+    },
+};
+
+/**
+ * 每个商品最多的SKU数量
+ */
+pub const MAX_SKUS_PER_GOODS: i64 = 100;
+
+/**
+ * 商品的所有SKU，按sort_order、id排序
+ */
+#[instrument(skip(executor))]
+pub async fn query_goods_skus(
+    executor: impl PgExecutor<'_>,
+    goods_id: i32,
+) -> Result<Vec<Sku>, (StatusCode, String)> {
+    sqlx::query!(
+        r#"SELECT id, goods_id, sku_code, attributes as "attributes: Json<BTreeMap<String, String>>",
+               unit_price, image, inventory_id, sort_order, version
+        FROM goods_sku WHERE goods_id = $1 ORDER BY sort_order, id"#,
+        goods_id
+    )
+    .map(|row| Sku {
+        id: row.id,
+        goods_id: row.goods_id,
+        sku_code: row.sku_code,
+        attributes: row.attributes.0,
+        unit_price: row.unit_price,
+        image: row.image,
+        inventory_id: row.inventory_id,
+        sort_order: row.sort_order,
+        inventory_count: None,
+        stock_state: StockState::Unknown,
+        version: row.version,
+    })
+    .fetch_all(executor)
+    .await
+    .map_err(internal_error)
+}
+
+/**
+ * 商品关联的库存id，返回 (商品id, 库存id)，没有关联库存的SKU不返回
+ */
+#[instrument(skip(pool))]
+pub async fn query_goods_inventory_ids(
+    pool: &PgPool,
+    goods_ids: &[i32],
+) -> Result<Vec<(i32, i32)>, sqlx::Error> {
+    sqlx::query!(
+        r#"SELECT goods_id, inventory_id as "inventory_id!" FROM goods_sku
+        WHERE goods_id = ANY($1) AND inventory_id IS NOT NULL"#,
+        goods_ids
+    )
+    .map(|row| (row.goods_id, row.inventory_id))
+    .fetch_all(pool)
+    .await
+}
+
+/**
+ * 商品所有SKU和库存的对应关系，商品不存在时返回404
+ */
+#[instrument(skip(pool))]
+pub async fn query_inventory_mappings(
+    pool: &PgPool,
+    goods_id: i32,
+) -> Result<Vec<InventoryMapping>, (StatusCode, String)> {
+    let exists = sqlx::query_scalar!("SELECT id FROM goods_summary WHERE id = $1", goods_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal_error)?;
+    if exists.is_none() {
+        return Err(goods_not_found(goods_id));
+    }
+
+    Ok(query_goods_skus(pool, goods_id)
+        .await?
+        .into_iter()
+        .map(|sku| InventoryMapping {
+            sku_id: sku.id,
+            sku_code: sku.sku_code,
+            attributes: sku.attributes,
+            inventory_id: sku.inventory_id,
+            version: sku.version,
+        })
+        .collect())
+}
+
+/**
+ * 下单时查询SKU对应的库存id和单价。
+ * SKU不属于这个商品时返回404；商品已经下架，或者SKU还没有关联库存时不能下单，返回409。
+ */
+#[instrument(skip(pool))]
+pub async fn resolve_inventory(
+    pool: &PgPool,
+    goods_id: i32,
+    sku_id: i32,
+) -> Result<ResolvedSku, (StatusCode, String)> {
+    let row = sqlx::query_as!(
+        ResolveRow,
+        "SELECT k.goods_id, k.inventory_id, k.unit_price, s.archived FROM goods_sku k
+         JOIN goods_summary s ON s.id = k.goods_id WHERE k.id = $1",
+        sku_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(internal_error)?;
+    check_resolvable(goods_id, sku_id, row)
+}
+
+/**
+ * 下单时查询到的SKU
+ */
+struct ResolveRow {
+    goods_id: i32,
+    inventory_id: Option<i32>,
+    unit_price: i32,
+    archived: bool,
+}
+
+/**
+ * 根据查询到的SKU判断能否下单，row为按sku_id查询的结果
+ */
+fn check_resolvable(goods_id: i32, sku_id: i32, row: Option<ResolveRow>) -> Result<ResolvedSku, (StatusCode, String)> {
+    let row = row
+        .filter(|row| row.goods_id == goods_id)
+        .ok_or_else(|| sku_not_found(goods_id, sku_id))?;
+    if row.archived {
+        return Err((StatusCode::CONFLICT, format!("goods {} has been archived.", goods_id)));
+    }
+    let inventory_id = row.inventory_id.ok_or_else(|| {
+        (
+            StatusCode::CONFLICT,
+            format!("sku {} of goods {} is not linked to any inventory.", sku_id, goods_id),
+        )
+    })?;
+    Ok(ResolvedSku {
+        inventory_id,
+        unit_price: row.unit_price,
+    })
+}
+
+/**
+ * 修改SKU关联的库存，版本号不一致时返回409，库存已经关联到别的SKU时也返回409
+ */
+#[instrument(skip(pool))]
+pub async fn set_sku_inventory(
+    pool: &PgPool,
+    goods_id: i32,
+    sku_id: i32,
+    set: &SetInventory,
+) -> Result<Sku, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+
+    let updated = sqlx::query_scalar!(
+        "UPDATE goods_sku SET inventory_id = $1, version = version + 1
+         WHERE id = $2 AND goods_id = $3 AND version = $4 RETURNING id",
+        set.inventory_id,
+        sku_id,
+        goods_id,
+        set.version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(constraint_error)?;
+    if updated.is_none() {
+        return Err(version_conflict(&mut tx, goods_id, sku_id, set.version).await);
+    }
+
+    let updated = find_sku(&mut tx, goods_id, sku_id).await?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok(updated)
+}
+
+/**
+ * 新增商品时生成的默认SKU：没有规格，价格和商品相同，还没有关联库存
+ */
+pub async fn create_default_sku(
+    tx: &mut Transaction<'_, Postgres>,
+    goods_id: i32,
+    unit_price: i32,
+) -> Result<(), (StatusCode, String)> {
+    sqlx::query!(
+        "INSERT INTO goods_sku (goods_id, sku_code, unit_price) VALUES ($1, $2, $3)",
+        goods_id,
+        format!("goods-{}", goods_id),
+        unit_price
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(constraint_error)?;
+    Ok(())
+}
+
+/**
+ * 修改商品的单价。下单时以SKU的价格为准：商品只有一个没有规格的SKU时同步修改它的价格；
+ * 有多个SKU或者带有规格时，商品的单价不能再直接修改，需要通过SKU接口改价，单价变化时返回400。
+ * 调用方已经通过修改商品锁住了这一行，同一个商品的SKU修改会等这个事务结束。
+ */
+pub async fn sync_goods_price(
+    tx: &mut Transaction<'_, Postgres>,
+    goods_id: i32,
+    current_price: i32,
+    unit_price: i32,
+) -> Result<(), (StatusCode, String)> {
+    let skus = query_goods_skus(&mut *tx, goods_id).await?;
+    match skus.as_slice() {
+        [sku] if sku.attributes.is_empty() => {
+            if sku.unit_price != unit_price {
+                sqlx::query!(
+                    "UPDATE goods_sku SET unit_price = $1, version = version + 1 WHERE id = $2",
+                    unit_price,
+                    sku.id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(internal_error)?;
+            }
+            Ok(())
+        }
+        _ if current_price != unit_price => Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "goods {} has {} skus, change their prices through the sku endpoints.",
+                goods_id,
+                skus.len()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * 新增SKU，规格属性名和商品已有的SKU不一致时返回400，商品编码或者规格重复时返回409
+ */
+#[instrument(skip(pool))]
+pub async fn create_sku(
+    pool: &PgPool,
+    goods_id: i32,
+    sku: &SkuPayload,
+) -> Result<Sku, (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    check_attribute_names(&mut tx, goods_id, None, sku).await?;
+
+    let count = sqlx::query_scalar!(
+        r#"SELECT count(*) as "count!" FROM goods_sku WHERE goods_id = $1"#,
+        goods_id
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if count >= MAX_SKUS_PER_GOODS {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("goods {} can have at most {} skus.", goods_id, MAX_SKUS_PER_GOODS),
+        ));
+    }
+
+    let id = sqlx::query_scalar!(
+        "INSERT INTO goods_sku (goods_id, sku_code, attributes, unit_price, image, inventory_id, sort_order)
+         VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        goods_id,
+        sku.sku_code,
+        Json(&sku.attributes) as _,
+        sku.unit_price,
+        sku.image,
+        sku.inventory_id,
+        sku.sort_order
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(constraint_error)?;
+
+    let created = find_sku(&mut tx, goods_id, id).await?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok(created)
+}
+
+/**
+ * 修改SKU，版本号不一致时返回409
+ */
+#[instrument(skip(pool))]
+pub async fn update_sku(
+    pool: &PgPool,
+    goods_id: i32,
+    sku_id: i32,
+    update: &UpdateSku,
+) -> Result<Sku, (StatusCode, String)> {
+    let sku = &update.sku;
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    check_attribute_names(&mut tx, goods_id, Some(sku_id), sku).await?;
+
+    let updated = sqlx::query_scalar!(
+        "UPDATE goods_sku SET sku_code = $1, attributes = $2, unit_price = $3, image = $4,
+         inventory_id = $5, sort_order = $6, version = version + 1
+         WHERE id = $7 AND goods_id = $8 AND version = $9 RETURNING id",
+        sku.sku_code,
+        Json(&sku.attributes) as _,
+        sku.unit_price,
+        sku.image,
+        sku.inventory_id,
+        sku.sort_order,
+        sku_id,
+        goods_id,
+        update.version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(constraint_error)?;
+    if updated.is_none() {
+        return Err(version_conflict(&mut tx, goods_id, sku_id, update.version).await);
+    }
+
+    let updated = find_sku(&mut tx, goods_id, sku_id).await?;
+    tx.commit().await.map_err(internal_error)?;
+    Ok(updated)
+}
+
+/**
+ * 删除SKU，商品至少要保留一个SKU，删除最后一个时返回409
+ */
+#[instrument(skip(pool))]
+pub async fn delete_sku(
+    pool: &PgPool,
+    goods_id: i32,
+    sku_id: i32,
+    version: i32,
+) -> Result<(), (StatusCode, String)> {
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    lock_goods(&mut tx, goods_id).await?;
+
+    let deleted = sqlx::query_scalar!(
+        "DELETE FROM goods_sku WHERE id = $1 AND goods_id = $2 AND version = $3 RETURNING id",
+        sku_id,
+        goods_id,
+        version
+    )
+    .fetch_optional(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if deleted.is_none() {
+        return Err(version_conflict(&mut tx, goods_id, sku_id, version).await);
+    }
+
+    let remaining = sqlx::query_scalar!(
+        r#"SELECT count(*) as "count!" FROM goods_sku WHERE goods_id = $1"#,
+        goods_id
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if remaining == 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("sku {} is the last sku of goods {}.", sku_id, goods_id),
+        ));
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+    Ok(())
+}
+
+/**
+ * 锁住商品，同一个商品的SKU修改串行执行，规格属性名的检查不会被并发的修改绕过
+ */
+async fn lock_goods(tx: &mut Transaction<'_, Postgres>, goods_id: i32) -> Result<(), (StatusCode, String)> {
+    let goods = sqlx::query_scalar!("SELECT id FROM goods_summary WHERE id = $1 FOR UPDATE", goods_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+    match goods {
+        Some(_) => Ok(()),
+        None => Err(goods_not_found(goods_id)),
+    }
+}
+
+/**
+ * 同一个商品的SKU规格属性名必须相同，规格矩阵才是完整的。
+ * 商品只有这一个SKU时可以任意修改，比如给没有规格的商品加上规格。
+ */
+async fn check_attribute_names(
+    tx: &mut Transaction<'_, Postgres>,
+    goods_id: i32,
+    sku_id: Option<i32>,
+    sku: &SkuPayload,
+) -> Result<(), (StatusCode, String)> {
+    lock_goods(tx, goods_id).await?;
+
+    let other = sqlx::query_scalar!(
+        r#"SELECT attributes as "attributes: Json<BTreeMap<String, String>>" FROM goods_sku
+        WHERE goods_id = $1 AND ($2::int IS NULL OR id <> $2) LIMIT 1"#,
+        goods_id,
+        sku_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+
+    if let Some(Json(other)) = other {
+        if !other.keys().eq(sku.attributes.keys()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "skus of goods {} have attributes {:?}.",
+                    goods_id,
+                    other.keys().collect::<Vec<_>>()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * 商品的一个SKU，不存在时返回404
+ */
+pub async fn find_sku(
+    executor: impl PgExecutor<'_>,
+    goods_id: i32,
+    sku_id: i32,
+) -> Result<Sku, (StatusCode, String)> {
+    query_goods_skus(executor, goods_id)
+        .await?
+        .into_iter()
+        .find(|sku| sku.id == sku_id)
+        .ok_or_else(|| sku_not_found(goods_id, sku_id))
+}
+
+/**
+ * 按版本号修改失败时区分SKU不存在（404）和版本号不一致（409）
+ */
+async fn version_conflict(
+    tx: &mut Transaction<'_, Postgres>,
+    goods_id: i32,
+    sku_id: i32,
+    version: i32,
+) -> (StatusCode, String) {
+    let current = sqlx::query_scalar!(
+        "SELECT version FROM goods_sku WHERE id = $1 AND goods_id = $2",
+        sku_id,
+        goods_id
+    )
+    .fetch_optional(&mut *tx)
+    .await;
+    match current {
+        Ok(Some(current)) => (
+            StatusCode::CONFLICT,
+            format!(
+                "sku {} has been modified, version {} is expected but current version is {}.",
+                sku_id, version, current
+            ),
+        ),
+        Ok(None) => sku_not_found(goods_id, sku_id),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn sku_not_found(goods_id: i32, sku_id: i32) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("sku {} of goods {} not found.", sku_id, goods_id),
+    )
+}
+
+/**
+ * 23505 unique_violation：商品编码重复，同一个商品下规格重复，或者库存已经关联到别的SKU
+ */
+fn constraint_error(err: sqlx::Error) -> (StatusCode, String) {
+    match &err {
+        sqlx::Error::Database(e) if e.code().as_deref() == Some("23505") => {
+            let message = match e.constraint() {
+                Some("goods_sku_attributes_idx") => "sku with the same attributes already exists.",
+                Some("goods_sku_inventory_key") => "inventory is already linked to another sku.",
+                _ => "sku code already exists.",
+            };
+            (StatusCode::CONFLICT, message.to_string())
+        }
+        _ => internal_error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(goods_id: i32, inventory_id: Option<i32>, archived: bool) -> Option<ResolveRow> {
+        Some(ResolveRow {
+            goods_id,
+            inventory_id,
+            unit_price: 250,
+            archived,
+        })
+    }
+
+    #[test]
+    fn test_check_resolvable() {
+        assert_eq!(
+            check_resolvable(1, 2, row(1, Some(4), false)).unwrap(),
+            ResolvedSku {
+                inventory_id: 4,
+                unit_price: 250,
+            }
+        );
+
+        let code = |goods_id: i32, row: Option<ResolveRow>| check_resolvable(goods_id, 2, row).unwrap_err().0;
+        //SKU不存在，或者属于别的商品
+        assert_eq!(code(1, None), StatusCode::NOT_FOUND);
+        assert_eq!(code(3, row(1, Some(4), false)), StatusCode::NOT_FOUND);
+        //已经下架，或者还没有关联库存
+        assert_eq!(code(1, row(1, Some(4), true)), StatusCode::CONFLICT);
+        assert_eq!(code(1, row(1, None, false)), StatusCode::CONFLICT);
+        assert_eq!(code(1, row(1, None, true)), StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,126 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use common_lib::{
+    pagination::{Page, PageRequest},
+    validate_payload,
+};
+use tracing::{info, instrument};
+
+use crate::{
+    db_access::category::{
+        create_category, delete_category, query_breadcrumbs, query_categories,
+        query_category_goods, set_goods_categories, update_category,
+    },
+    handlers::rest::{bad_request, fill_stock, map_ok_result},
+    models::{
+        admin::Admin,
+        category::{build_tree, Category, CategoryNode, CategoryPayload, GoodsCategories, UpdateCategory},
+        goods::{GoodsSummary, GoodsVersion},
+        state::AppState,
+    },
+};
+
+/**
+ * 整棵分类树
+ */
+#[instrument(skip(state))]
+pub async fn get_category_tree(
+    State(state): State<AppState>,
+) -> Result<axum::Json<Vec<CategoryNode>>, (StatusCode, String)> {
+    let categories = query_categories(&state.pool).await?;
+    Ok(map_ok_result(build_tree(categories)))
+}
+
+/**
+ * 分类及其所有子分类下的商品，分页方式和商品列表相同
+ */
+#[instrument(skip(state))]
+pub async fn get_category_goods(
+    State(state): State<AppState>,
+    Path(category_id): Path<i32>,
+    Query(query_params): Query<PageRequest>,
+) -> Result<axum::Json<Page<GoodsSummary>>, (StatusCode, String)> {
+    let mut page = query_category_goods(&state.pool, category_id, &query_params).await?;
+    fill_stock(&state, &mut page.items).await;
+    Ok(map_ok_result(page))
+}
+
+/**
+ * 面包屑，从顶级分类到当前分类
+ */
+#[instrument(skip(state))]
+pub async fn get_breadcrumbs(
+    State(state): State<AppState>,
+    Path(category_id): Path<i32>,
+) -> Result<axum::Json<Vec<Category>>, (StatusCode, String)> {
+    let path = query_breadcrumbs(&state.pool, category_id).await?;
+    Ok(map_ok_result(path))
+}
+
+/**
+ * 新增分类，只有管理员可以调用
+ */
+#[instrument(skip(state))]
+pub async fn add_category(
+    admin: Admin,
+    State(state): State<AppState>,
+    Json(category): Json<CategoryPayload>,
+) -> Result<(StatusCode, axum::Json<Category>), (StatusCode, String)> {
+    validate_payload(&category).map_err(bad_request)?;
+    let category = create_category(&state.pool, &category).await?;
+    info!("admin {} created category {}.", admin.id, category.id);
+    Ok((StatusCode::CREATED, map_ok_result(category)))
+}
+
+/**
+ * 修改或者移动分类，请求中需要带上version
+ */
+#[instrument(skip(state))]
+pub async fn modify_category(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(category_id): Path<i32>,
+    Json(update): Json<UpdateCategory>,
+) -> Result<axum::Json<Category>, (StatusCode, String)> {
+    validate_payload(&update).map_err(bad_request)?;
+    let category = update_category(&state.pool, category_id, &update).await?;
+    info!(
+        "admin {} updated category {} to version {}.",
+        admin.id, category_id, category.version
+    );
+    Ok(map_ok_result(category))
+}
+
+/**
+ * 删除分类，版本号通过query参数传入：DELETE /categories/1?version=2
+ */
+#[instrument(skip(state))]
+pub async fn remove_category(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(category_id): Path<i32>,
+    Query(version): Query<GoodsVersion>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    delete_category(&state.pool, category_id, version.version).await?;
+    info!("admin {} deleted category {}.", admin.id, category_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/**
+ * 设置商品所属的分类，整体替换
+ */
+#[instrument(skip(state))]
+pub async fn modify_goods_categories(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+    Json(categories): Json<GoodsCategories>,
+) -> Result<axum::Json<Vec<Category>>, (StatusCode, String)> {
+    validate_payload(&categories).map_err(bad_request)?;
+    let categories = set_goods_categories(&state.pool, goods_id, &categories.category_ids).await?;
+    info!("admin {} set categories of goods {}.", admin.id, goods_id);
+    Ok(map_ok_result(categories))
+}
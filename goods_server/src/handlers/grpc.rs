@@ -0,0 +1,82 @@
+use axum::http::StatusCode;
+use sqlx::PgPool;
+use tracing::debug;
+
+use crate::db_access::sku::resolve_inventory;
+
+use self::goods_proto::goods_service_server::{GoodsService, GoodsServiceServer};
+
+mod goods_proto {
+    tonic::include_proto!("goods");
+}
+
+pub struct GrpcServiceImpl {
+    pool: PgPool,
+}
+
+#[tonic::async_trait]
+impl GoodsService for GrpcServiceImpl {
+    /**
+     * 订单服务下单时查询SKU对应的库存id
+     */
+    async fn resolve_inventory(
+        &self,
+        request: tonic::Request<goods_proto::ResolveInventoryRequest>,
+    ) -> Result<tonic::Response<goods_proto::ResolveInventoryRespone>, tonic::Status> {
+        let request_data = request.into_inner();
+        let resolved = resolve_inventory(&self.pool, request_data.goods_id, request_data.sku_id)
+            .await
+            .map_err(grpc_status)?;
+
+        debug!(
+            "GrpcServiceImpl resolve_inventory sku {} of goods {} -> {:?}",
+            request_data.sku_id, request_data.goods_id, resolved
+        );
+        Ok(tonic::Response::new(goods_proto::ResolveInventoryRespone {
+            inventory_id: resolved.inventory_id,
+            unit_price: resolved.unit_price,
+        }))
+    }
+}
+
+/**
+ * REST接口使用的错误转换成grpc状态：404为NOT_FOUND，409为FAILED_PRECONDITION，其他的都是INTERNAL
+ */
+fn grpc_status((code, msg): (StatusCode, String)) -> tonic::Status {
+    match code {
+        StatusCode::NOT_FOUND => tonic::Status::not_found(msg),
+        StatusCode::CONFLICT => tonic::Status::failed_precondition(msg),
+        _ => tonic::Status::internal(msg),
+    }
+}
+
+/**
+ * 服务器上提供的grpc服务类型，用于设置它的grpc健康状态
+ */
+pub type GrpcServer = GoodsServiceServer<GrpcServiceImpl>;
+
+pub fn get_grpc_router(pool: PgPool) -> GrpcServer {
+    GoodsServiceServer::new(GrpcServiceImpl { pool })
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn test_grpc_status() {
+        let status = grpc_status((StatusCode::NOT_FOUND, "sku 2 of goods 1 not found.".to_string()));
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "sku 2 of goods 1 not found.");
+        assert_eq!(
+            grpc_status((StatusCode::CONFLICT, String::new())).code(),
+            Code::FailedPrecondition
+        );
+        assert_eq!(
+            grpc_status((StatusCode::INTERNAL_SERVER_ERROR, String::new())).code(),
+            Code::Internal
+        );
+    }
+}
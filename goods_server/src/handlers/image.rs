@@ -0,0 +1,241 @@
+use axum::{
+    body::Full,
+    extract::{Multipart, Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::{Bytes, BytesMut};
+use common_lib::internal_error;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    db_access::image::{
+        delete_goods_image, find_image_by_key, insert_goods_images, query_goods_images,
+        remaining_image_slots, too_many_images, NewImage,
+    },
+    handlers::rest::{bad_request, map_ok_result},
+    models::{
+        admin::Admin,
+        image::{original_key, thumbnail_key, GoodsImage},
+        state::AppState,
+    },
+    storage::{
+        content_type_of, is_valid_key,
+        thumbnail::{self, ProcessedImage, MAX_IMAGE_BYTES},
+        ObjectStore,
+    },
+};
+
+/**
+ * 一次最多上传的图片数量
+ */
+pub const MAX_IMAGES_PER_REQUEST: usize = 5;
+/**
+ * 上传接口的请求体大小上限
+ */
+pub const MAX_UPLOAD_BYTES: usize = MAX_IMAGES_PER_REQUEST * MAX_IMAGE_BYTES + 64 * 1024;
+/**
+ * 图片的key不会重复使用，内容不会变化，浏览器和CDN可以一直缓存
+ */
+const IMAGE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/**
+ * 商品的所有图片，第一张是主图
+ */
+#[instrument(skip(state))]
+pub async fn get_goods_images(
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+) -> Result<axum::Json<Vec<GoodsImage>>, (StatusCode, String)> {
+    let records = query_goods_images(&state.pool, goods_id).await?;
+    Ok(map_ok_result(records.iter().map(|r| r.to_goods_image()).collect()))
+}
+
+/**
+ * 上传商品图片，multipart/form-data，每张图片一个名为image的字段，只有管理员可以调用。
+ * 每张图片生成几个尺寸的缩略图，和原图一起写入对象存储之后再保存记录。
+ */
+#[instrument(skip(state, multipart))]
+pub async fn upload_goods_images(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let slots = remaining_image_slots(&state.pool, goods_id).await?;
+
+    let mut processed = vec![];
+    while let Some(mut field) = multipart.next_field().await.map_err(bad_request)? {
+        if field.name() != Some("image") {
+            continue;
+        }
+        if processed.len() >= MAX_IMAGES_PER_REQUEST {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("at most {} images can be uploaded at once.", MAX_IMAGES_PER_REQUEST),
+            ));
+        }
+        if processed.len() as i64 >= slots {
+            return Err(too_many_images(goods_id));
+        }
+
+        let content_type = field.content_type().unwrap_or_default().to_string();
+        //边读边检查大小，超过上限时不用等整张图片读完
+        let mut body = BytesMut::new();
+        while let Some(chunk) = field.chunk().await.map_err(bad_request)? {
+            if body.len() + chunk.len() > MAX_IMAGE_BYTES {
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("image is larger than {} bytes.", MAX_IMAGE_BYTES),
+                ));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let body = body.freeze();
+        let image = tokio::task::spawn_blocking(move || thumbnail::process(&content_type, body))
+            .await
+            .map_err(internal_error)??;
+        processed.push(image);
+    }
+    if processed.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "no image field in the request.".to_string()));
+    }
+
+    let mut images = vec![];
+    let mut uploaded = vec![];
+    for image in processed {
+        let image_key = format!("goods/{}/{}", goods_id, Uuid::new_v4().simple());
+        let result = put_image(state.store.as_ref(), &image_key, &image, &mut uploaded).await;
+        if let Err(e) = result {
+            delete_objects(state.store.as_ref(), &uploaded).await;
+            return Err((StatusCode::BAD_GATEWAY, format!("save image failed: {}", e)));
+        }
+        images.push(NewImage {
+            image_key,
+            content_type: image.content_type.to_string(),
+            extension: image.extension.to_string(),
+            thumbnail_ext: image.thumbnail_extension.to_string(),
+            width: image.width as i32,
+            height: image.height as i32,
+        });
+    }
+
+    let records = match insert_goods_images(&state.pool, goods_id, &images).await {
+        Ok(records) => records,
+        Err(e) => {
+            delete_objects(state.store.as_ref(), &uploaded).await;
+            return Err(e);
+        }
+    };
+    info!("admin {} uploaded {} images of goods {}.", admin.id, records.len(), goods_id);
+    Ok((
+        StatusCode::CREATED,
+        map_ok_result(records.iter().map(|r| r.to_goods_image()).collect::<Vec<_>>()),
+    ))
+}
+
+/**
+ * 删除商品图片，只有管理员可以调用
+ */
+#[instrument(skip(state))]
+pub async fn remove_goods_image(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path((goods_id, image_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let record = delete_goods_image(&state.pool, goods_id, image_id).await?;
+    delete_objects(state.store.as_ref(), &record.keys()).await;
+    info!("admin {} deleted image {} of goods {}.", admin.id, image_id, goods_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/**
+ * 返回对象存储中的图片，GET /images/goods/1/xxx/256.jpg
+ */
+#[instrument(skip(state, headers))]
+pub async fn get_image(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, format!("image {} not found.", key));
+    if !is_valid_key(&key) {
+        return Err(not_found());
+    }
+    //图片删除之后对象存储中可能还有没删掉的文件，以图片记录为准，先确认图片存在再比较etag
+    let image_key = key.rsplit_once('/').map(|(dir, _)| dir).unwrap_or_default();
+    let record = find_image_by_key(&state.pool, image_key).await?;
+    if !record.is_some_and(|r| r.keys().contains(&key)) {
+        return Err(not_found());
+    }
+
+    //内容不会变化，key本身就可以作为etag
+    let etag = format!("\"{}\"", key);
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|v| v.as_bytes() == etag.as_bytes())
+    {
+        return Ok(StatusCode::NOT_MODIFIED.into_response());
+    }
+
+    let object = state
+        .store
+        .get(&key)
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("read image failed: {}", e)))?
+        .ok_or_else(not_found)?;
+
+    let content_type = match object.content_type.as_str() {
+        "" | "application/octet-stream" => content_type_of(&key).to_string(),
+        content_type => content_type.to_string(),
+    };
+    let mut response = Full::new(object.body).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::CONTENT_TYPE, header_value(&content_type));
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(IMAGE_CACHE_CONTROL));
+    response_headers.insert(header::ETAG, header_value(&etag));
+    Ok(response)
+}
+
+/**
+ * 写入原图和缩略图，写入成功的key记录在uploaded中，失败时由调用方清理
+ */
+async fn put_image(
+    store: &dyn ObjectStore,
+    image_key: &str,
+    image: &ProcessedImage,
+    uploaded: &mut Vec<String>,
+) -> Result<(), String> {
+    let original_key = original_key(image_key, image.extension);
+    store
+        .put(&original_key, image.content_type, image.original.clone())
+        .await?;
+    uploaded.push(original_key);
+
+    for (size, body) in image.thumbnails.iter() {
+        let key = thumbnail_key(image_key, *size, image.thumbnail_extension);
+        store
+            .put(&key, content_type_of(&key), Bytes::clone(body))
+            .await?;
+        uploaded.push(key);
+    }
+    Ok(())
+}
+
+/**
+ * 删除对象存储中的文件，失败时只记录日志，最多留下一些没有引用的文件
+ */
+pub async fn delete_objects(store: &dyn ObjectStore, keys: &[String]) {
+    for key in keys {
+        if let Err(e) = store.delete(key).await {
+            warn!("delete object {} failed: {}", key, e);
+        }
+    }
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream"))
+}
+
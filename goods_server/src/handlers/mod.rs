@@ -1 +1,5 @@
-pub mod rest;
\ No newline at end of file
+pub mod category;
+pub mod grpc;
+pub mod image;
+pub mod rest;
+pub mod sku;
\ No newline at end of file
@@ -1,50 +1,258 @@
-use std::f32::consts::E;
+use std::collections::HashMap;
 
 use axum::{
-    extract::{Query, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    response::Html,
     Json,
 };
 
-use common_lib::{internal_error, internal_error_dyn, validate_payload};
+use common_lib::{
+    pagination::{Page, PageRequest},
+    validate_payload,
+};
 
-use sqlx::PgPool;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
-    db_access::db::{query_goods_summary_list, query_goods_detail},
-    models::goods::{GoodsDetail, GoodsSummary, QueryRequest, QueryDetailRequest},
+    db_access::db::{
+        archive_goods, count_search_goods, create_goods, delete_goods, query_goods_detail,
+        query_goods_summary_list, search_goods, suggest_goods, update_goods,
+    },
+    db_access::sku::query_goods_inventory_ids,
+    handlers::image::delete_objects,
+    models::{
+        admin::Admin,
+        goods::{
+            GoodsDetail, GoodsPayload, GoodsSearchHit, GoodsSummary, GoodsVersion,
+            QueryDetailRequest, SearchCursor, SearchGoodsRequest, StockState, SuggestRequest,
+            Suggestions,
+            UpdateGoods,
+        },
+        state::AppState,
+    },
 };
 
-#[instrument]
-pub async fn health_handler() -> Html<&'static str> {
-    println!("some one call health check api.");
-    Html("<h1>Goods server health ok.</h1>")
+/**
+ * 商品列表，库存从库存服务批量查询
+ */
+#[instrument(skip(state))]
+pub async fn get_goods_summary(
+    State(state): State<AppState>,
+    Query(query_params): Query<PageRequest>,
+) -> Result<axum::Json<Page<GoodsSummary>>, (StatusCode, String)> {
+    let mut page = query_goods_summary_list(&state.pool, &query_params).await?;
+    fill_stock(&state, &mut page.items).await;
+    Ok(map_ok_result(page))
 }
 
-#[instrument]
-pub async fn get_goods_summary(
-    State(pool): State<PgPool>,
-    Query(query_params): Query<QueryRequest>,
-) -> Result<axum::Json<Vec<GoodsSummary>>, (StatusCode, String)> {
-    return query_goods_summary_list(&pool, query_params.page, query_params.page_size)
-        .await
-        .map(map_ok_result);
+/**
+ * 从库存服务批量查询商品列表中的库存
+ */
+pub async fn fill_stock(state: &AppState, goods: &mut [GoodsSummary]) {
+    let ids: Vec<i32> = goods.iter().map(|g| g.id).collect();
+    let stocks = goods_stocks(state, &ids).await;
+    for goods in goods.iter_mut() {
+        goods.inventory_count = stocks.get(&goods.id).copied();
+        goods.stock_state = StockState::of(goods.inventory_count);
+    }
+}
+
+/**
+ * 商品的库存，返回 商品id -> 数量，数量是商品关联了库存的SKU的库存之和。
+ * 没有关联库存，或者有SKU的库存查询不到的商品不出现在结果中。
+ */
+pub async fn goods_stocks(state: &AppState, goods_ids: &[i32]) -> HashMap<i32, i32> {
+    let links = match query_goods_inventory_ids(&state.pool, goods_ids).await {
+        Ok(links) => links,
+        Err(e) => {
+            warn!("query inventory ids of goods failed, stock is unknown: {}", e);
+            return HashMap::new();
+        }
+    };
+    let inventory_ids: Vec<i32> = links.iter().map(|(_, inventory_id)| *inventory_id).collect();
+    let stocks = state.stock.stocks(&inventory_ids).await;
+
+    let mut totals: HashMap<i32, Option<i32>> = HashMap::new();
+    for (goods_id, inventory_id) in links {
+        let total = totals.entry(goods_id).or_insert(Some(0));
+        *total = total.zip(stocks.get(&inventory_id).copied()).map(|(a, b)| a + b);
+    }
+    totals
+        .into_iter()
+        .filter_map(|(goods_id, total)| total.map(|total| (goods_id, total)))
+        .collect()
 }
 
 /**
- *
+ * 商品详情，包括规格矩阵和每个SKU的库存，库存服务不可用时库存状态为unknown
  */
-#[instrument]
+#[instrument(skip(state))]
 pub async fn get_goods_detail(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     Query(query_params): Query<QueryDetailRequest>,
 ) -> Result<axum::Json<GoodsDetail>, (StatusCode, String)> {
-    return query_goods_detail(&pool, query_params.goods_id)
-        .await
-        .map(map_ok_result);
+    let mut detail = query_goods_detail(&state.pool, query_params.goods_id).await?;
+
+    let inventory_ids: Vec<i32> = detail.skus.iter().filter_map(|sku| sku.inventory_id).collect();
+    let stocks = state.stock.stocks(&inventory_ids).await;
+    for sku in detail.skus.iter_mut() {
+        sku.inventory_count = sku.inventory_id.and_then(|id| stocks.get(&id).copied());
+        sku.stock_state = StockState::of(sku.inventory_count);
+    }
+    //和商品列表一致，只统计关联了库存的SKU
+    detail.inventory_count = if inventory_ids.is_empty() {
+        None
+    } else {
+        detail
+            .skus
+            .iter()
+            .filter(|sku| sku.inventory_id.is_some())
+            .map(|sku| sku.inventory_count)
+            .sum()
+    };
+    detail.stock_state = StockState::of(detail.inventory_count);
+    Ok(map_ok_result(detail))
+}
+
+/**
+ * 只看有货的商品时，一页中的结果被过滤掉之后最多再往后查这么多次
+ */
+const MAX_SEARCH_BATCHES: usize = 5;
+
+/**
+ * 搜索商品，GET /goods/search?q=book&sort=price_asc&min_price=100&in_stock=true
+ * 库存和商品列表一样从库存服务批量查询；只看有货的商品时在查到库存之后过滤，
+ * 过滤后不够一页就继续往后查，最多查MAX_SEARCH_BATCHES次，这时返回的结果可能不满一页，但仍然带有next_cursor。
+ * in_stock=true时即使with_total=true也不返回total。
+ */
+#[instrument(skip(state))]
+pub async fn search_goods_handler(
+    State(state): State<AppState>,
+    Query(search): Query<SearchGoodsRequest>,
+) -> Result<axum::Json<Page<GoodsSearchHit>>, (StatusCode, String)> {
+    validate_payload(&search).map_err(bad_request)?;
+    let page = search.page();
+    let limit = page.limit() as usize;
+
+    let mut after = search.after()?;
+    let mut hits = vec![];
+    let mut exhausted = false;
+    for _ in 0..MAX_SEARCH_BATCHES {
+        let mut batch = search_goods(&state.pool, &search, after, page.fetch_limit()).await?;
+        exhausted = batch.len() < page.fetch_limit() as usize;
+        after = match batch.last() {
+            Some(last) => Some(SearchCursor::of(search.sort, last)),
+            None => break,
+        };
+
+        let ids: Vec<i32> = batch.iter().map(|hit| hit.id).collect();
+        let stocks = goods_stocks(&state, &ids).await;
+        for hit in batch.iter_mut() {
+            hit.inventory_count = stocks.get(&hit.id).copied();
+            hit.stock_state = StockState::of(hit.inventory_count);
+        }
+        hits.extend(
+            batch
+                .into_iter()
+                .filter(|hit| !search.in_stock || hit.stock_state != StockState::OutOfStock),
+        );
+        if exhausted || hits.len() > limit {
+            break;
+        }
+    }
+
+    let mut result = Page::from_rows_by(hits, &page, |hit| SearchCursor::of(search.sort, hit).encode());
+    if result.next_cursor.is_none() && !exhausted {
+        result.next_cursor = after.map(|cursor| cursor.encode());
+    }
+    //库存过滤在查询之后进行，数据库统计不出过滤后的总数，只看有货的商品时不返回total
+    let total = if page.with_total && !search.in_stock {
+        Some(count_search_goods(&state.pool, &search).await?)
+    } else {
+        None
+    };
+    Ok(map_ok_result(result.with_total(total)))
+}
+
+/**
+ * 搜索提示，GET /goods/suggest?q=rsut，用户输入时调用，允许拼错
+ */
+#[instrument(skip(state))]
+pub async fn suggest_goods_handler(
+    State(state): State<AppState>,
+    Query(request): Query<SuggestRequest>,
+) -> Result<axum::Json<Suggestions>, (StatusCode, String)> {
+    validate_payload(&request).map_err(bad_request)?;
+    let suggestions = suggest_goods(&state.pool, &request.q, request.limit()).await?;
+    Ok(map_ok_result(suggestions))
+}
+
+/**
+ * 新增商品，只有管理员可以调用
+ */
+#[instrument(skip(state))]
+pub async fn add_goods(
+    admin: Admin,
+    State(state): State<AppState>,
+    Json(goods): Json<GoodsPayload>,
+) -> Result<(StatusCode, axum::Json<GoodsDetail>), (StatusCode, String)> {
+    validate_payload(&goods).map_err(bad_request)?;
+    let detail = create_goods(&state.pool, &goods).await?;
+    info!("admin {} created goods {}.", admin.id, detail.id);
+    Ok((StatusCode::CREATED, map_ok_result(detail)))
+}
+
+/**
+ * 修改商品，请求中需要带上读取商品时拿到的version
+ */
+#[instrument(skip(state))]
+pub async fn modify_goods(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+    Json(update): Json<UpdateGoods>,
+) -> Result<axum::Json<GoodsDetail>, (StatusCode, String)> {
+    validate_payload(&update).map_err(bad_request)?;
+    let detail = update_goods(&state.pool, goods_id, &update).await?;
+    info!("admin {} updated goods {} to version {}.", admin.id, goods_id, detail.version);
+    Ok(map_ok_result(detail))
+}
+
+/**
+ * 下架商品
+ */
+#[instrument(skip(state))]
+pub async fn archive_goods_handler(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+    Json(version): Json<GoodsVersion>,
+) -> Result<axum::Json<GoodsDetail>, (StatusCode, String)> {
+    let detail = archive_goods(&state.pool, goods_id, version.version).await?;
+    info!("admin {} archived goods {}.", admin.id, goods_id);
+    Ok(map_ok_result(detail))
+}
+
+/**
+ * 删除商品，版本号通过query参数传入：DELETE /goods/1?version=2
+ */
+#[instrument(skip(state))]
+pub async fn remove_goods(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+    Query(version): Query<GoodsVersion>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let images = delete_goods(&state.pool, goods_id, version.version).await?;
+    let keys: Vec<String> = images.iter().flat_map(|image| image.keys()).collect();
+    delete_objects(state.store.as_ref(), &keys).await;
+    info!("admin {} deleted goods {}.", admin.id, goods_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn bad_request<E: std::error::Error>(err: E) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, err.to_string())
 }
 
 pub fn map_ok_result<T>(r: T) -> axum::Json<T> {
@@ -0,0 +1,126 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use common_lib::validate_payload;
+use tracing::{info, instrument};
+
+use crate::{
+    db_access::sku::{
+        create_sku, delete_sku, find_sku, query_inventory_mappings, set_sku_inventory, update_sku,
+    },
+    handlers::rest::{bad_request, map_ok_result},
+    models::{
+        admin::Admin,
+        goods::GoodsVersion,
+        sku::{InventoryMapping, SetInventory, Sku, SkuPayload, UpdateSku},
+        state::AppState,
+    },
+};
+
+/**
+ * 新增SKU，只有管理员可以调用
+ */
+#[instrument(skip(state))]
+pub async fn add_sku(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+    Json(sku): Json<SkuPayload>,
+) -> Result<(StatusCode, axum::Json<Sku>), (StatusCode, String)> {
+    validate_payload(&sku).map_err(bad_request)?;
+    check_inventory(&state, sku.inventory_id).await?;
+    let sku = create_sku(&state.pool, goods_id, &sku).await?;
+    info!("admin {} created sku {} of goods {}.", admin.id, sku.id, goods_id);
+    Ok((StatusCode::CREATED, map_ok_result(sku)))
+}
+
+/**
+ * 修改SKU，请求中需要带上version。关联的库存变化时才到库存服务确认库存存在。
+ */
+#[instrument(skip(state))]
+pub async fn modify_sku(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path((goods_id, sku_id)): Path<(i32, i32)>,
+    Json(update): Json<UpdateSku>,
+) -> Result<axum::Json<Sku>, (StatusCode, String)> {
+    validate_payload(&update).map_err(bad_request)?;
+    //只有版本号一致时才能修改成功，那时读到的就是要修改的版本，库存没变时不需要再确认
+    let current = find_sku(&state.pool, goods_id, sku_id).await?;
+    if current.inventory_id != update.sku.inventory_id {
+        check_inventory(&state, update.sku.inventory_id).await?;
+    }
+    let sku = update_sku(&state.pool, goods_id, sku_id, &update).await?;
+    info!(
+        "admin {} updated sku {} of goods {} to version {}.",
+        admin.id, sku_id, goods_id, sku.version
+    );
+    Ok(map_ok_result(sku))
+}
+
+/**
+ * 删除SKU，版本号通过query参数传入：DELETE /goods/1/skus/2?version=1
+ */
+#[instrument(skip(state))]
+pub async fn remove_sku(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path((goods_id, sku_id)): Path<(i32, i32)>,
+    Query(version): Query<GoodsVersion>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    delete_sku(&state.pool, goods_id, sku_id, version.version).await?;
+    info!("admin {} deleted sku {} of goods {}.", admin.id, sku_id, goods_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/**
+ * 商品所有SKU关联的库存，只有管理员可以调用
+ */
+#[instrument(skip(state))]
+pub async fn get_inventory_mappings(
+    _admin: Admin,
+    State(state): State<AppState>,
+    Path(goods_id): Path<i32>,
+) -> Result<axum::Json<Vec<InventoryMapping>>, (StatusCode, String)> {
+    let mappings = query_inventory_mappings(&state.pool, goods_id).await?;
+    Ok(map_ok_result(mappings))
+}
+
+/**
+ * 修改SKU关联的库存，{"inventory_id": 4, "version": 1}，inventory_id为null时取消关联
+ */
+#[instrument(skip(state))]
+pub async fn modify_sku_inventory(
+    admin: Admin,
+    State(state): State<AppState>,
+    Path((goods_id, sku_id)): Path<(i32, i32)>,
+    Json(set): Json<SetInventory>,
+) -> Result<axum::Json<Sku>, (StatusCode, String)> {
+    check_inventory(&state, set.inventory_id).await?;
+    let sku = set_sku_inventory(&state.pool, goods_id, sku_id, &set).await?;
+    info!(
+        "admin {} linked sku {} of goods {} to inventory {:?}.",
+        admin.id, sku_id, goods_id, sku.inventory_id
+    );
+    Ok(map_ok_result(sku))
+}
+
+/**
+ * 关联的库存必须在库存服务中存在，库存服务不可用时无法确认，返回502
+ */
+async fn check_inventory(state: &AppState, inventory_id: Option<i32>) -> Result<(), (StatusCode, String)> {
+    let inventory_id = match inventory_id {
+        Some(inventory_id) => inventory_id,
+        None => return Ok(()),
+    };
+    match state.stock.inventory_exists(inventory_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("inventory {} not found.", inventory_id),
+        )),
+        Err(e) => Err((StatusCode::BAD_GATEWAY, format!("query inventory failed: {}", e))),
+    }
+}
@@ -0,0 +1,15 @@
+use common_lib::admin::AdminRole;
+
+/**
+ * 商品管理员，从环境变量GOODS_ADMIN_IDS（逗号分隔的用户id）中读取，
+ * 请求头中没有合法的jwt时返回401，jwt中的用户不是管理员时返回403。
+ */
+#[derive(Debug, Clone)]
+pub struct GoodsAdminRole;
+
+impl AdminRole for GoodsAdminRole {
+    const ENV: &'static str = "GOODS_ADMIN_IDS";
+    const NAME: &'static str = "goods admin";
+}
+
+pub type Admin = common_lib::admin::Admin<GoodsAdminRole>;
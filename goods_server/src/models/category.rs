@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+/**
+ * 商品分类
+ * parent_id 父分类，顶级分类为null
+ * sort_order 同级分类的展示顺序，从小到大
+ * version 乐观锁版本号，修改分类时需要带上
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Category {
+    pub id: i32,
+    pub parent_id: Option<i32>,
+    pub name: String,
+    pub sort_order: i32,
+    pub version: i32,
+}
+
+/**
+ * 分类树中的节点
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CategoryNode {
+    pub id: i32,
+    pub name: String,
+    pub sort_order: i32,
+    pub version: i32,
+    pub children: Vec<CategoryNode>,
+}
+
+/**
+ * 新增分类，parent_id不传时为顶级分类
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct CategoryPayload {
+    #[validate(length(min = 1, max = 64))]
+    pub name: String,
+    pub parent_id: Option<i32>,
+    #[serde(default)]
+    pub sort_order: i32,
+}
+
+/**
+ * 修改分类，修改parent_id可以把分类连同子分类移动到别的分类下
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct UpdateCategory {
+    #[serde(flatten)]
+    #[validate]
+    pub category: CategoryPayload,
+    pub version: i32,
+}
+
+/**
+ * 商品所属的分类，整体替换
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct GoodsCategories {
+    #[validate(length(max = 20))]
+    pub category_ids: Vec<i32>,
+}
+
+/**
+ * 把所有分类组装成树，同级分类按sort_order、id排序
+ */
+pub fn build_tree(categories: Vec<Category>) -> Vec<CategoryNode> {
+    let mut children: HashMap<Option<i32>, Vec<Category>> = HashMap::new();
+    for category in categories {
+        children.entry(category.parent_id).or_default().push(category);
+    }
+    build_children(&mut children, None)
+}
+
+fn build_children(
+    children: &mut HashMap<Option<i32>, Vec<Category>>,
+    parent_id: Option<i32>,
+) -> Vec<CategoryNode> {
+    let mut nodes = children.remove(&parent_id).unwrap_or_default();
+    nodes.sort_by_key(|c| (c.sort_order, c.id));
+    nodes
+        .into_iter()
+        .map(|c| CategoryNode {
+            children: build_children(children, Some(c.id)),
+            id: c.id,
+            name: c.name,
+            sort_order: c.sort_order,
+            version: c.version,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(id: i32, parent_id: Option<i32>, sort_order: i32) -> Category {
+        Category {
+            id,
+            parent_id,
+            name: format!("category{}", id),
+            sort_order,
+            version: 1,
+        }
+    }
+
+    /**
+     * 把树展开成 (id, 层级) 的列表，方便比较结构和顺序
+     */
+    fn flatten(nodes: &[CategoryNode], depth: usize, out: &mut Vec<(i32, usize)>) {
+        for node in nodes {
+            out.push((node.id, depth));
+            flatten(&node.children, depth + 1, out);
+        }
+    }
+
+    #[test]
+    fn test_build_tree_nesting_and_order() {
+        let tree = build_tree(vec![
+            category(4, Some(1), 0),
+            category(1, None, 2),
+            category(2, None, 1),
+            category(5, Some(4), 0),
+            category(3, Some(1), 0),
+            category(6, Some(1), -1),
+        ]);
+
+        let mut flat = vec![];
+        flatten(&tree, 0, &mut flat);
+        //同级按sort_order排序，相同时按id
+        assert_eq!(flat, vec![(2, 0), (1, 0), (6, 1), (3, 1), (4, 1), (5, 2)]);
+        assert_eq!(tree[1].children[2].children[0].name, "category5");
+        assert!(build_tree(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_build_tree_skips_orphans() {
+        //父分类不存在的分类连同它的子分类都不出现在树中
+        let tree = build_tree(vec![
+            category(1, None, 0),
+            category(2, Some(99), 0),
+            category(3, Some(2), 0),
+        ]);
+
+        let mut flat = vec![];
+        flatten(&tree, 0, &mut flat);
+        assert_eq!(flat, vec![(1, 0)]);
+    }
+}
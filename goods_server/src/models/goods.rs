@@ -1,21 +1,42 @@
 use axum::{async_trait, extract::FromRequest};
+use axum::http::StatusCode;
+use common_lib::pagination::{decode_keyset_cursor, encode_keyset_cursor, PageRequest};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
-// use validator::Validate;
+use validator::{Validate, ValidationError};
 
 use axum::{extract::FromRequestParts, http::HeaderMap};
 
+use super::sku::{Sku, VariantOption};
+
+/**
+ * 库存状态，库存服务不可用时为unknown，此时库存数量为null
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StockState {
+    InStock,
+    OutOfStock,
+    Unknown,
+}
+
+impl StockState {
+    pub fn of(count: Option<i32>) -> Self {
+        match count {
+            Some(count) if count > 0 => StockState::InStock,
+            Some(_) => StockState::OutOfStock,
+            None => StockState::Unknown,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct GoodsSummary {
     pub id: i32,
     pub goods_name: String,
     pub goods_image: String,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct QueryRequest {
-    pub page_size: i64,
-    pub page: i64,
+    pub inventory_count: Option<i32>,
+    pub stock_state: StockState,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -24,7 +45,12 @@ pub struct QueryDetailRequest {
 }
 
 /**
- * unit_price 单价。单位分。
+ * unit_price 单价。单位分。商品列表和搜索中展示的价格，下单时使用SKU的价格。
+ * inventory_count 库存数量，所有SKU的库存之和，有SKU的库存查询不到时为null
+ * archived 是否已经下架
+ * version 乐观锁版本号，修改商品时需要带上
+ * options 规格矩阵，没有规格的商品为空
+ * skus 商品的所有SKU
  */
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct GoodsDetail {
@@ -33,5 +59,264 @@ pub struct GoodsDetail {
     pub goods_image: String,
     pub unit_price: i32,
     pub goods_des: String,
-    pub inventory_count: i32,
+    pub inventory_count: Option<i32>,
+    pub stock_state: StockState,
+    pub archived: bool,
+    pub version: i32,
+    pub options: Vec<VariantOption>,
+    pub skus: Vec<Sku>,
+}
+
+/**
+ * 新增商品时的商品信息，概要和详情共用名称和图片
+ * unit_price 单价。单位分。
+ * 新增的商品带有一个没有规格、没有关联库存的SKU，之后通过SKU接口修改
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct GoodsPayload {
+    #[validate(length(min = 1, max = 140))]
+    pub goods_name: String,
+    #[serde(default)]
+    #[validate(length(max = 140))]
+    pub goods_image: String,
+    #[serde(default)]
+    #[validate(length(max = 140))]
+    pub goods_des: String,
+    #[validate(range(min = 0))]
+    pub unit_price: i32,
+}
+
+/**
+ * 修改商品，version为读取商品时拿到的版本号，商品在此期间被别人修改过时返回409。
+ * goods_image会被忽略，商品图片通过图片接口上传
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct UpdateGoods {
+    #[serde(flatten)]
+    #[validate]
+    pub goods: GoodsPayload,
+    pub version: i32,
+}
+
+/**
+ * 下架、删除商品时带上的版本号
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GoodsVersion {
+    pub version: i32,
+}
+
+/**
+ * 搜索结果的排序方式，默认按相关度
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchSort {
+    #[default]
+    Relevance,
+    PriceAsc,
+    PriceDesc,
+    Newest,
+}
+
+impl SearchSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchSort::Relevance => "relevance",
+            SearchSort::PriceAsc => "price_asc",
+            SearchSort::PriceDesc => "price_desc",
+            SearchSort::Newest => "newest",
+        }
+    }
+}
+
+/**
+ * 搜索商品
+ * q 搜索词，支持websearch语法：多个词之间为与，"短语"，or，-排除
+ * min_price、max_price 单价范围，单位分，包含边界
+ * in_stock 为true时不返回已经确认无货的商品，库存未知的商品仍然返回
+ * cursor、page_size、with_total 同PageRequest
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+#[validate(schema(function = "validate_price_range"))]
+pub struct SearchGoodsRequest {
+    #[validate(length(min = 1, max = 100))]
+    pub q: String,
+    #[validate(range(min = 0))]
+    pub min_price: Option<i32>,
+    #[validate(range(min = 0))]
+    pub max_price: Option<i32>,
+    #[serde(default)]
+    pub in_stock: bool,
+    #[serde(default)]
+    pub sort: SearchSort,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+    #[serde(default)]
+    pub with_total: bool,
+}
+
+impl SearchGoodsRequest {
+    pub fn page(&self) -> PageRequest {
+        PageRequest::new(self.cursor.clone(), self.page_size, self.with_total)
+    }
+
+    /**
+     * 游标中记录的上一页最后一条结果，第一页时返回None。换了排序方式之后旧的游标不能再用。
+     */
+    pub fn after(&self) -> Result<Option<SearchCursor>, (StatusCode, String)> {
+        let cursor = match self.page().cursor {
+            Some(cursor) => decode_keyset_cursor::<SearchCursor>(&cursor)?,
+            None => return Ok(None),
+        };
+        if cursor.sort != self.sort {
+            return Err((StatusCode::BAD_REQUEST, "cursor does not match sort.".to_string()));
+        }
+        Ok(Some(cursor))
+    }
+}
+
+/**
+ * 搜索结果的翻页游标：上一页最后一条结果的排序值和id，
+ * 下一页从 (sort_key, id) 之后开始查，不需要给全部结果编号。
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SearchCursor {
+    pub sort: SearchSort,
+    pub sort_key: f64,
+    pub id: i32,
+}
+
+impl SearchCursor {
+    pub fn of(sort: SearchSort, hit: &GoodsSearchHit) -> Self {
+        Self {
+            sort,
+            sort_key: hit.sort_key,
+            id: hit.id,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        encode_keyset_cursor(self)
+    }
+}
+
+fn validate_price_range(request: &SearchGoodsRequest) -> Result<(), ValidationError> {
+    match (request.min_price, request.max_price) {
+        (Some(min), Some(max)) if min > max => Err(ValidationError::new("min_price_greater_than_max_price")),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * 搜索结果
+ * highlighted_name、snippet 名称和描述中命中的词用<em></em>包起来，其余部分是没有做html转义的原文
+ * rank 相关度，越大越相关
+ * sort_key 按排序方式计算出的排序值，结果按 (sort_key降序, id升序) 排列，用作翻页游标
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GoodsSearchHit {
+    pub id: i32,
+    pub goods_name: String,
+    pub goods_image: String,
+    pub unit_price: i32,
+    pub highlighted_name: String,
+    pub snippet: String,
+    pub rank: f32,
+    pub inventory_count: Option<i32>,
+    pub stock_state: StockState,
+    #[serde(skip)]
+    pub sort_key: f64,
+}
+
+/**
+ * 搜索提示，用户输入时调用
+ * q 用户已经输入的内容
+ * limit 返回的提示数量，默认10，最多20
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct SuggestRequest {
+    #[validate(length(min = 1, max = 50))]
+    pub q: String,
+    #[validate(range(min = 1, max = 20))]
+    pub limit: Option<i64>,
+}
+
+impl SuggestRequest {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(10)
+    }
+}
+
+/**
+ * score 前缀匹配时为1，否则为输入内容和商品名的相似度（0~1）
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GoodsSuggestion {
+    pub id: i32,
+    pub goods_name: String,
+    pub score: f32,
+}
+
+/**
+ * degraded 为true时模糊匹配因为超时被跳过，只返回了前缀匹配的结果
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Suggestions {
+    pub items: Vec<GoodsSuggestion>,
+    pub degraded: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(sort: SearchSort, cursor: Option<String>) -> SearchGoodsRequest {
+        SearchGoodsRequest {
+            q: "book".to_string(),
+            min_price: None,
+            max_price: None,
+            in_stock: false,
+            sort,
+            cursor,
+            page_size: None,
+            with_total: false,
+        }
+    }
+
+    #[test]
+    fn test_validate_price_range() {
+        let mut request = search(SearchSort::Relevance, None);
+        assert!(validate_price_range(&request).is_ok());
+
+        request.min_price = Some(100);
+        assert!(validate_price_range(&request).is_ok());
+        request.max_price = Some(100);
+        assert!(validate_price_range(&request).is_ok());
+        request.max_price = Some(99);
+        assert!(validate_price_range(&request).is_err());
+        assert!(request.validate().is_err());
+
+        request.min_price = None;
+        assert!(validate_price_range(&request).is_ok());
+    }
+
+    #[test]
+    fn test_search_cursor_round_trip() {
+        assert_eq!(search(SearchSort::Relevance, None).after(), Ok(None));
+        assert_eq!(search(SearchSort::Relevance, Some(String::new())).after(), Ok(None));
+
+        //相关度是real，转成f64之后编码再解码不能有误差，否则翻页时会重复或者漏掉结果
+        let cursor = SearchCursor {
+            sort: SearchSort::Relevance,
+            sort_key: 0.1f32 as f64,
+            id: 7,
+        };
+        let request = search(SearchSort::Relevance, Some(cursor.encode()));
+        assert_eq!(request.after(), Ok(Some(cursor)));
+
+        //换了排序方式，或者不是搜索的游标
+        assert!(search(SearchSort::PriceAsc, Some(cursor.encode())).after().is_err());
+        assert!(search(SearchSort::Relevance, Some("bm90IGEgY3Vyc29y".to_string())).after().is_err());
+    }
 }
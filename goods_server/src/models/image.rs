@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{image_url, thumbnail::THUMBNAIL_SIZES};
+
+/**
+ * 商品图片
+ * url 原图地址
+ * thumbnails 缩略图地址，key为缩略图的尺寸（长边像素）
+ * sort_order 最小的是主图
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GoodsImage {
+    pub id: i32,
+    pub goods_id: i32,
+    pub url: String,
+    pub thumbnails: BTreeMap<u32, String>,
+    pub content_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub sort_order: i32,
+}
+
+/**
+ * goods_image表中的记录
+ */
+#[derive(Debug, Clone)]
+pub struct ImageRecord {
+    pub id: i32,
+    pub goods_id: i32,
+    pub image_key: String,
+    pub content_type: String,
+    pub extension: String,
+    pub thumbnail_ext: String,
+    pub width: i32,
+    pub height: i32,
+    pub sort_order: i32,
+}
+
+impl ImageRecord {
+    pub fn original_key(&self) -> String {
+        original_key(&self.image_key, &self.extension)
+    }
+
+    pub fn thumbnail_key(&self, size: u32) -> String {
+        thumbnail_key(&self.image_key, size, &self.thumbnail_ext)
+    }
+
+    /**
+     * 原图和所有缩略图的key
+     */
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = vec![self.original_key()];
+        keys.extend(THUMBNAIL_SIZES.iter().map(|size| self.thumbnail_key(*size)));
+        keys
+    }
+
+    pub fn to_goods_image(&self) -> GoodsImage {
+        GoodsImage {
+            id: self.id,
+            goods_id: self.goods_id,
+            url: image_url(&self.original_key()),
+            thumbnails: THUMBNAIL_SIZES
+                .iter()
+                .map(|size| (*size, image_url(&self.thumbnail_key(*size))))
+                .collect(),
+            content_type: self.content_type.clone(),
+            width: self.width,
+            height: self.height,
+            sort_order: self.sort_order,
+        }
+    }
+}
+
+/**
+ * 原图在对象存储中的key：image_key/original.扩展名
+ */
+pub fn original_key(image_key: &str, extension: &str) -> String {
+    format!("{}/original.{}", image_key, extension)
+}
+
+/**
+ * 缩略图在对象存储中的key：image_key/尺寸.扩展名
+ */
+pub fn thumbnail_key(image_key: &str, size: u32, extension: &str) -> String {
+    format!("{}/{}.{}", image_key, size, extension)
+}
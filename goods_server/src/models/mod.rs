@@ -1,2 +1,6 @@
+pub mod admin;
+pub mod category;
+pub mod image;
+pub mod sku;
 pub mod state;
 pub mod goods;
\ No newline at end of file
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationError};
+
+use super::goods::StockState;
+
+/**
+ * 一个SKU最多的规格属性数量
+ */
+const MAX_ATTRIBUTES: usize = 5;
+/**
+ * 规格属性名和属性值的长度上限
+ */
+const MAX_ATTRIBUTE_CHARS: usize = 32;
+
+/**
+ * 商品的SKU，下单时使用SKU的id
+ * attributes 规格属性，比如 {"color": "red", "size": "M"}，没有规格的商品为空
+ * unit_price 单价。单位分。
+ * image 规格图片的地址，为空时使用商品的主图
+ * inventory_id 库存服务中的库存id，为空时库存未知
+ * inventory_count 库存数量，从库存服务查询，查询不到时为null
+ * version 乐观锁版本号，修改SKU时需要带上
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Sku {
+    pub id: i32,
+    pub goods_id: i32,
+    pub sku_code: String,
+    pub attributes: BTreeMap<String, String>,
+    pub unit_price: i32,
+    pub image: String,
+    pub inventory_id: Option<i32>,
+    pub sort_order: i32,
+    pub inventory_count: Option<i32>,
+    pub stock_state: StockState,
+    pub version: i32,
+}
+
+/**
+ * 规格矩阵中的一个维度，比如 {"name": "color", "values": ["red", "blue"]}
+ */
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VariantOption {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+/**
+ * 新增SKU。同一个商品的SKU规格属性名必须相同，属性值的组合不能重复。
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct SkuPayload {
+    #[validate(length(min = 1, max = 64))]
+    pub sku_code: String,
+    #[serde(default)]
+    #[validate(custom = "validate_attributes")]
+    pub attributes: BTreeMap<String, String>,
+    #[validate(range(min = 0))]
+    pub unit_price: i32,
+    #[serde(default)]
+    #[validate(length(max = 140))]
+    pub image: String,
+    pub inventory_id: Option<i32>,
+    #[serde(default)]
+    pub sort_order: i32,
+}
+
+/**
+ * 修改SKU，version为读取时拿到的版本号
+ */
+#[derive(Deserialize, Validate, Serialize, Debug, Clone)]
+pub struct UpdateSku {
+    #[serde(flatten)]
+    #[validate]
+    pub sku: SkuPayload,
+    pub version: i32,
+}
+
+/**
+ * SKU和库存的对应关系，inventory_id为空时还没有关联库存，不能下单
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InventoryMapping {
+    pub sku_id: i32,
+    pub sku_code: String,
+    pub attributes: BTreeMap<String, String>,
+    pub inventory_id: Option<i32>,
+    pub version: i32,
+}
+
+/**
+ * 下单时SKU对应的库存id和当前的单价
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedSku {
+    pub inventory_id: i32,
+    pub unit_price: i32,
+}
+
+/**
+ * 修改SKU关联的库存，inventory_id为空时取消关联
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SetInventory {
+    pub inventory_id: Option<i32>,
+    pub version: i32,
+}
+
+fn validate_attributes(attributes: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    if attributes.len() > MAX_ATTRIBUTES {
+        return Err(ValidationError::new("too_many_attributes"));
+    }
+    let illegal = |s: &String| s.trim().is_empty() || s.chars().count() > MAX_ATTRIBUTE_CHARS;
+    if attributes.iter().any(|(name, value)| illegal(name) || illegal(value)) {
+        return Err(ValidationError::new("illegal_attribute"));
+    }
+    Ok(())
+}
+
+/**
+ * 由SKU列表得到规格矩阵：属性名按字母顺序，属性值按SKU的顺序，去掉重复
+ */
+pub fn variant_options(skus: &[Sku]) -> Vec<VariantOption> {
+    let mut options: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for sku in skus {
+        for (name, value) in sku.attributes.iter() {
+            let values = options.entry(name).or_default();
+            if !values.contains(value) {
+                values.push(value.clone());
+            }
+        }
+    }
+    options
+        .into_iter()
+        .map(|(name, values)| VariantOption {
+            name: name.to_string(),
+            values,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn sku(id: i32, pairs: &[(&str, &str)]) -> Sku {
+        Sku {
+            id,
+            goods_id: 1,
+            sku_code: format!("sku-{}", id),
+            attributes: attributes(pairs),
+            unit_price: 100,
+            image: String::new(),
+            inventory_id: None,
+            sort_order: 0,
+            inventory_count: None,
+            stock_state: StockState::Unknown,
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_validate_attributes() {
+        assert!(validate_attributes(&BTreeMap::new()).is_ok());
+        assert!(validate_attributes(&attributes(&[("color", "red"), ("size", "M")])).is_ok());
+        let longest = "长".repeat(MAX_ATTRIBUTE_CHARS);
+        assert!(validate_attributes(&attributes(&[(&longest, &longest)])).is_ok());
+
+        let code = |attributes: BTreeMap<String, String>| validate_attributes(&attributes).unwrap_err().code;
+        let names = ["a", "b", "c", "d", "e", "f"];
+        let too_many: Vec<(&str, &str)> = names.iter().map(|name| (*name, "x")).collect();
+        assert_eq!(code(attributes(&too_many)), "too_many_attributes");
+        assert!(validate_attributes(&attributes(&too_many[..MAX_ATTRIBUTES])).is_ok());
+
+        let too_long = "长".repeat(MAX_ATTRIBUTE_CHARS + 1);
+        assert_eq!(code(attributes(&[("color", &too_long)])), "illegal_attribute");
+        assert_eq!(code(attributes(&[(&too_long, "red")])), "illegal_attribute");
+        assert_eq!(code(attributes(&[("color", "")])), "illegal_attribute");
+        assert_eq!(code(attributes(&[(" ", "red")])), "illegal_attribute");
+    }
+
+    #[test]
+    fn test_variant_options() {
+        assert!(variant_options(&[]).is_empty());
+        assert!(variant_options(&[sku(1, &[])]).is_empty());
+
+        let skus = [
+            sku(1, &[("size", "M"), ("color", "red")]),
+            sku(2, &[("size", "L"), ("color", "red")]),
+            sku(3, &[("size", "M"), ("color", "blue")]),
+        ];
+        assert_eq!(
+            variant_options(&skus),
+            vec![
+                VariantOption {
+                    name: "color".to_string(),
+                    values: vec!["red".to_string(), "blue".to_string()],
+                },
+                VariantOption {
+                    name: "size".to_string(),
+                    values: vec!["M".to_string(), "L".to_string()],
+                },
+            ]
+        );
+    }
+}
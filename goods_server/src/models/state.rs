@@ -1,6 +1,12 @@
+use std::sync::Arc;
+
 use sqlx::PgPool;
 
-#[derive(Clone,Debug)]
+use crate::{db_access::repo::StockCache, storage::ObjectStore};
+
+#[derive(Clone)]
 pub struct AppState {
     pub pool: PgPool,
+    pub stock: StockCache,
+    pub store: Arc<dyn ObjectStore>,
 }
\ No newline at end of file
@@ -0,0 +1,101 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::{content_type_of, is_valid_key, ObjectStore, StoredObject};
+
+/**
+ * 保存在本地目录中的对象存储，key就是相对路径，content type由扩展名推断
+ */
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path_of(&self, key: &str) -> Result<PathBuf, String> {
+        if !is_valid_key(key) {
+            return Err(format!("illegal object key {}.", key));
+        }
+        Ok(self.dir.join(Path::new(key)))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalStore {
+    async fn put(&self, key: &str, _content_type: &str, body: Bytes) -> Result<(), String> {
+        let path = self.path_of(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("create dir {} failed: {}", parent.display(), e))?;
+        }
+        //先写临时文件再改名，读的时候不会读到写了一半的文件
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, &body)
+            .await
+            .map_err(|e| format!("write {} failed: {}", tmp.display(), e))?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .map_err(|e| format!("rename {} failed: {}", tmp.display(), e))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, String> {
+        let path = self.path_of(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(body) => Ok(Some(StoredObject {
+                content_type: content_type_of(key).to_string(),
+                body: Bytes::from(body),
+            })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("read {} failed: {}", path.display(), e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let path = self.path_of(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("delete {} failed: {}", path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("goods-images-{}", uuid::Uuid::new_v4()));
+        let store = LocalStore::new(&dir);
+        let key = "goods/1/abc.png";
+
+        assert!(store.get(key).await.unwrap().is_none());
+        store.put(key, "image/png", Bytes::from_static(b"first")).await.unwrap();
+        store.put(key, "image/png", Bytes::from_static(b"second")).await.unwrap();
+        let object = store.get(key).await.unwrap().unwrap();
+        assert_eq!(object.content_type, "image/png");
+        assert_eq!(object.body, Bytes::from_static(b"second"));
+        //写入时用的临时文件已经改名
+        assert!(!dir.join("goods/1/abc.tmp").exists());
+
+        store.delete(key).await.unwrap();
+        assert!(store.get(key).await.unwrap().is_none());
+        //删除不存在的对象也成功
+        store.delete(key).await.unwrap();
+
+        assert!(store.put("../escape.png", "image/png", Bytes::new()).await.is_err());
+        assert!(store.get("/etc/passwd").await.is_err());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+/**
+ * 商品图片的对象存储。
+ *
+ * 图片和缩略图按key保存到ObjectStore中，由goods_server的/images/接口读出来返回给前端。
+ * 通过环境变量IMAGE_STORE选择后端：
+ * - local（默认） 保存到本地目录IMAGE_DIR，只适合单副本部署
+ * - s3 保存到S3兼容的对象存储（比如MinIO），多个副本共享同一个bucket
+ */
+pub mod local;
+pub mod s3;
+pub mod thumbnail;
+
+use std::{
+    env,
+    sync::{Arc, OnceLock},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use self::{local::LocalStore, s3::S3Store};
+
+/**
+ * 从对象存储中读出来的对象
+ */
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub content_type: String,
+    pub body: Bytes,
+}
+
+/**
+ * 对象存储。key只包含字母、数字和 / - . 这几个字符，由调用方生成，保证不会重复。
+ */
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /**
+     * 写入对象，key已经存在时覆盖
+     */
+    async fn put(&self, key: &str, content_type: &str, body: Bytes) -> Result<(), String>;
+
+    /**
+     * 读取对象，不存在时返回None
+     */
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>, String>;
+
+    /**
+     * 删除对象，不存在时也返回成功
+     */
+    async fn delete(&self, key: &str) -> Result<(), String>;
+}
+
+/**
+ * 根据环境变量创建对象存储，配置不完整时直接退出
+ */
+pub fn from_env() -> Arc<dyn ObjectStore> {
+    match env::var("IMAGE_STORE").unwrap_or_default().as_str() {
+        "" | "local" => {
+            let dir = env::var("IMAGE_DIR").unwrap_or_else(|_| "./goods_images".to_string());
+            Arc::new(LocalStore::new(dir))
+        }
+        "s3" => Arc::new(S3Store::from_env().unwrap_or_else(|e| panic!("illegal s3 config: {}", e))),
+        other => panic!("illegal IMAGE_STORE {}, expect local or s3.", other),
+    }
+}
+
+/**
+ * key是否合法，防止通过 .. 之类的key读到存储目录之外的文件
+ */
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 200
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.'))
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '.'))
+}
+
+/**
+ * 按扩展名推断对象的content type
+ */
+pub fn content_type_of(key: &str) -> &'static str {
+    match key.rsplit('.').next() {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/**
+ * 图片对外的地址，IMAGE_BASE_URL默认为/images/，由goods_server自己返回图片；
+ * 前面有CDN时可以配置成CDN的地址
+ */
+pub fn image_url(key: &str) -> String {
+    static BASE_URL: OnceLock<String> = OnceLock::new();
+    let base = BASE_URL.get_or_init(|| {
+        let base = env::var("IMAGE_BASE_URL").unwrap_or_else(|_| "/images/".to_string());
+        format!("{}/", base.trim_end_matches('/'))
+    });
+    format!("{}{}", base, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_key() {
+        assert!(is_valid_key("goods/1/abc-123.jpg"));
+        assert!(is_valid_key("goods/1/abc-123.256.jpg"));
+
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key(&"a".repeat(201)));
+        //跳出存储目录
+        assert!(!is_valid_key(".."));
+        assert!(!is_valid_key("goods/../../etc/passwd"));
+        assert!(!is_valid_key("goods/.hidden"));
+        //绝对路径、空的路径段
+        assert!(!is_valid_key("/etc/passwd"));
+        assert!(!is_valid_key("goods//1.jpg"));
+        assert!(!is_valid_key("goods/1/"));
+        //windows的路径分隔符和盘符
+        assert!(!is_valid_key("goods\\..\\1.jpg"));
+        assert!(!is_valid_key("C:\\images\\1.jpg"));
+        assert!(!is_valid_key("goods/1 2.jpg"));
+    }
+}
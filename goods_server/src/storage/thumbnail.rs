@@ -0,0 +1,175 @@
+use std::io::Cursor;
+
+use axum::http::StatusCode;
+use bytes::Bytes;
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    io::{Limits, Reader},
+    DynamicImage, ImageEncoder, ImageFormat,
+};
+
+/**
+ * 单张图片的大小上限
+ */
+pub const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+/**
+ * 缩略图的尺寸，缩放到长边不超过这个像素，比原图小时不放大
+ */
+pub const THUMBNAIL_SIZES: [u32; 3] = [128, 256, 512];
+/**
+ * 商品列表中展示的主图使用的缩略图尺寸
+ */
+pub const LIST_THUMBNAIL_SIZE: u32 = 256;
+/**
+ * 图片的长宽上限，防止很小的文件解压出巨大的图片
+ */
+const MAX_IMAGE_DIMENSION: u32 = 6000;
+const JPEG_QUALITY: u8 = 85;
+
+/**
+ * 处理之后的图片，原图原样保存，缩略图没有透明通道时用jpeg，否则用png
+ */
+pub struct ProcessedImage {
+    pub content_type: &'static str,
+    pub extension: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub original: Bytes,
+    pub thumbnail_extension: &'static str,
+    pub thumbnails: Vec<(u32, Bytes)>,
+}
+
+/**
+ * 检查上传的图片并生成缩略图，比较耗CPU，需要在spawn_blocking中调用。
+ * 只接受jpeg、png、webp，声明的content type和实际的格式不一致时拒绝。
+ */
+pub fn process(declared_content_type: &str, body: Bytes) -> Result<ProcessedImage, (StatusCode, String)> {
+    if body.len() > MAX_IMAGE_BYTES {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("image is larger than {} bytes.", MAX_IMAGE_BYTES),
+        ));
+    }
+    let (format, content_type, extension) = match declared_content_type {
+        "image/jpeg" => (ImageFormat::Jpeg, "image/jpeg", "jpg"),
+        "image/png" => (ImageFormat::Png, "image/png", "png"),
+        "image/webp" => (ImageFormat::WebP, "image/webp", "webp"),
+        other => {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("unsupported content type {}, expect image/jpeg, image/png or image/webp.", other),
+            ))
+        }
+    };
+    if image::guess_format(&body).ok() != Some(format) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("content of the image is not {}.", content_type),
+        ));
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    let mut reader = Reader::with_format(Cursor::new(&body), format);
+    reader.limits(limits);
+    let image = reader
+        .decode()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("decode image failed: {}", e)))?;
+
+    let has_alpha = image.color().has_alpha();
+    let thumbnail_extension = if has_alpha { "png" } else { "jpg" };
+    let thumbnails = THUMBNAIL_SIZES
+        .iter()
+        .map(|size| {
+            let resized = if image.width() > *size || image.height() > *size {
+                image.thumbnail(*size, *size)
+            } else {
+                image.clone()
+            };
+            encode(&resized, has_alpha).map(|body| (*size, body))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ProcessedImage {
+        content_type,
+        extension,
+        width: image.width(),
+        height: image.height(),
+        original: body,
+        thumbnail_extension,
+        thumbnails,
+    })
+}
+
+fn encode(image: &DynamicImage, has_alpha: bool) -> Result<Bytes, (StatusCode, String)> {
+    let mut buf = vec![];
+    let result = if has_alpha {
+        let rgba = image.to_rgba8();
+        PngEncoder::new(&mut buf).write_image(&rgba, rgba.width(), rgba.height(), image::ColorType::Rgba8)
+    } else {
+        let rgb = image.to_rgb8();
+        JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY).write_image(
+            &rgb,
+            rgb.width(),
+            rgb.height(),
+            image::ColorType::Rgb8,
+        )
+    };
+    result.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("encode thumbnail failed: {}", e)))?;
+    Ok(Bytes::from(buf))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, RgbaImage};
+
+    use super::*;
+
+    fn png(image: DynamicImage) -> Bytes {
+        let mut buf = Cursor::new(vec![]);
+        image.write_to(&mut buf, ImageFormat::Png).unwrap();
+        Bytes::from(buf.into_inner())
+    }
+
+    #[test]
+    fn test_process_png() {
+        let body = png(DynamicImage::ImageRgba8(RgbaImage::new(600, 300)));
+        let processed = process("image/png", body.clone()).unwrap();
+        assert_eq!((processed.width, processed.height), (600, 300));
+        assert_eq!(processed.extension, "png");
+        assert_eq!(processed.thumbnail_extension, "png");
+        assert_eq!(processed.original, body);
+        let sizes: Vec<u32> = processed.thumbnails.iter().map(|(size, _)| *size).collect();
+        assert_eq!(sizes, THUMBNAIL_SIZES.to_vec());
+    }
+
+    #[test]
+    fn test_process_rejects_spoofed_type() {
+        let body = png(DynamicImage::ImageLuma8(GrayImage::new(10, 10)));
+        let (status, _) = process("image/jpeg", body.clone()).err().unwrap();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, _) = process("image/gif", body).err().unwrap();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, _) = process("image/png", Bytes::from_static(b"<svg></svg>")).err().unwrap();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_process_rejects_oversized_bytes() {
+        let (status, _) = process("image/png", Bytes::from(vec![0; MAX_IMAGE_BYTES + 1]))
+            .err()
+            .unwrap();
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_process_rejects_oversized_dimensions() {
+        //文件只有几十个字节，解码之后超过长宽上限
+        let body = png(DynamicImage::ImageLuma8(GrayImage::new(MAX_IMAGE_DIMENSION + 1, 1)));
+        assert!(body.len() < MAX_IMAGE_BYTES);
+        let (status, _) = process("image/png", body).err().unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
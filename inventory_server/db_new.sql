@@ -23,4 +23,6 @@ create table inventory_change (
        description varchar(140)
 );
 
+CREATE INDEX idx_inventory_change_inventory_id ON inventory_change (inventory_id, id);
+
 insert into inventory_change (count ,inventory_id, deduction_order_id, description) values(1,1, null ,'test_goods');
@@ -0,0 +1,4 @@
+-- 库存变更记录按库存id翻页：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。
+
+create index if not exists idx_inventory_change_inventory_id on inventory_change (inventory_id, id);
@@ -1,8 +1,12 @@
 use std::f32::consts::E;
 
 use axum::http::StatusCode;
-use common_lib::internal_error;
+use common_lib::{
+    internal_error,
+    pagination::{Page, PageRequest},
+};
 use sqlx::postgres::PgPool;
+use tracing::{info, instrument};
 
 use crate::models::{
     inventory::{
@@ -11,6 +15,7 @@ use crate::models::{
     },
 };
 
+#[instrument(skip(pool))]
 pub async fn query_inventory_from_db(
     pool: &PgPool,
     inventoey_id: i32,
@@ -28,38 +33,85 @@ pub async fn query_inventory_from_db(
             .await
             .map_err(internal_error)?;
 
-    println!("query_orders_from_db size: {}", inventory.len());
+    info!("query_inventory_from_db size: {}", inventory.len());
 
     Ok(inventory)
 }
 
+/**
+ * 批量查询库存，不存在的id不会出现在结果中
+ */
+#[instrument(skip(pool))]
+pub async fn query_stock_from_db(
+    pool: &PgPool,
+    inventory_ids: &[i32],
+) -> Result<Vec<Inventory>, (StatusCode, String)> {
+    let inventory: Vec<Inventory> = sqlx::query!(
+        "SELECT * FROM inventory WHERE id = ANY($1) ORDER BY id",
+        inventory_ids
+    )
+    .map({
+        |row| Inventory {
+            id: row.id,
+            count: row.count,
+            description: row.description,
+        }
+    })
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)?;
+
+    Ok(inventory)
+}
+
+#[instrument(skip(pool))]
 pub async fn query_inventory_change_from_db(
     pool: &PgPool,
     inventoey_id: i32,
-) -> Result<Vec<InventoryChange>, (StatusCode, String)> {
-    let inventory: Vec<InventoryChange> =
-        sqlx::query!("SELECT * FROM inventory_change WHERE id = $1", inventoey_id)
-            .map({
-                |row| InventoryChange {
-                    id: row.id,
-                    inventory_id: row.inventory_id,
-                    deduction_order_id: row.deduction_order_id,
-                    count: row.count,
-                    description: row.description,
-                }
-            })
-            .fetch_all(pool)
-            .await
-            .map_err(internal_error)?;
+    page: &PageRequest,
+) -> Result<Page<InventoryChange>, (StatusCode, String)> {
+    let after = page.after()?.unwrap_or(0);
+    let inventory: Vec<InventoryChange> = sqlx::query!(
+        "SELECT * FROM inventory_change WHERE inventory_id = $1 AND id > $2::int8 ORDER BY id LIMIT $3",
+        inventoey_id,
+        after,
+        page.fetch_limit()
+    )
+    .map({
+        |row| InventoryChange {
+            id: row.id,
+            inventory_id: row.inventory_id,
+            deduction_order_id: row.deduction_order_id,
+            count: row.count,
+            description: row.description,
+        }
+    })
+    .fetch_all(pool)
+    .await
+    .map_err(internal_error)?;
 
-    println!("query_orders_from_db size: {}", inventory.len());
+    info!("query_inventory_change_from_db size: {}", inventory.len());
 
-    Ok(inventory)
+    let total = if page.with_total {
+        let count = sqlx::query_scalar!(
+            r#"SELECT count(*) as "count!" FROM inventory_change WHERE inventory_id = $1"#,
+            inventoey_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(internal_error)?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Page::from_rows(inventory, page, |c| c.id as i64).with_total(total))
 }
 
 /**
  * 添加库存
  */
+#[instrument(skip(pool))]
 pub async fn add_inventory_from_db(
     pool: &PgPool,
     data: AddInventoryRequest,
@@ -126,6 +178,7 @@ pub async fn add_inventory_from_db(
  *
  * TODO 如果库存同一个订单的库存已经扣减过了，我们需要直接返回成功
  */
+#[instrument(skip(pool))]
 pub async fn de_inventory_from_db(
     pool: &PgPool,
     data: DeducteInventoryRequest,
@@ -1,10 +1,16 @@
 use sqlx::PgPool;
+use tracing::{debug, info};
 
 use crate::{
-    db_access::db::{de_inventory_from_db},
+    db_access::db::{de_inventory_from_db, query_stock_from_db},
     models::inventory::{DeducteInventoryRequest},
 };
 
+/**
+ * 批量查询库存时一次最多查询的数量
+ */
+const MAX_STOCK_QUERY: usize = 200;
+
 use self::proto::inventory_service_server::{InventoryService, InventoryServiceServer};
 
 mod proto {
@@ -27,7 +33,7 @@ impl InventoryService for GrpcServiceImpl {
         &self,
         request: tonic::Request<proto::DeductionInventoryRequest>,
     ) -> Result<tonic::Response<proto::DeductionInventoryRespone>, tonic::Status> {
-        println!("GrpcServiceImpl deduction_inventory call.");
+        info!("GrpcServiceImpl deduction_inventory call.");
 
         
         let request_data = request.into_inner();
@@ -45,12 +51,43 @@ impl InventoryService for GrpcServiceImpl {
             result : 200,
         };
 
-        println!("GrpcServiceImpl get_orders result: {:?}", response);
+        debug!("GrpcServiceImpl deduction_inventory result: {:?}", response);
         Ok(tonic::Response::new(response))
     }
 
+    async fn query_stock(
+        &self,
+        request: tonic::Request<proto::QueryStockRequest>,
+    ) -> Result<tonic::Response<proto::QueryStockRespone>, tonic::Status> {
+        let inventory_ids = request.into_inner().inventory_ids;
+        if inventory_ids.len() > MAX_STOCK_QUERY {
+            return Err(tonic::Status::invalid_argument(format!(
+                "query at most {} stocks at once.",
+                MAX_STOCK_QUERY
+            )));
+        }
+
+        let inventory = query_stock_from_db(&self.pool, &inventory_ids)
+            .await
+            .map_err(|(_, e)| tonic::Status::internal(e))?;
+
+        let stocks = inventory
+            .into_iter()
+            .map(|i| proto::Stock {
+                inventory_id: i.id,
+                count: i.count,
+            })
+            .collect();
+        Ok(tonic::Response::new(proto::QueryStockRespone { stocks }))
+    }
+
 }
 
-pub fn get_grpc_router(pg_pool: PgPool) -> InventoryServiceServer<GrpcServiceImpl> {
+/**
+ * 服务器上提供的grpc服务类型，用于设置它的grpc健康状态
+ */
+pub type GrpcServer = InventoryServiceServer<GrpcServiceImpl>;
+
+pub fn get_grpc_router(pg_pool: PgPool) -> GrpcServer {
     InventoryServiceServer::new(GrpcServiceImpl::new(pg_pool))
 }
@@ -1,21 +1,16 @@
 use axum::{
     extract::{Query, State},
     http::StatusCode,
-    response::Html,
 };
 
+use common_lib::pagination::{Page, PageRequest};
 use sqlx::PgPool;
 
 use crate::{
     db_access::db::{query_inventory_change_from_db, query_inventory_from_db},
-    models::inventory::{Inventory, InventoryChange, QueryRequest},
+    models::inventory::{Inventory, InventoryChange, QueryChangeRequest, QueryRequest},
 };
 
-pub async fn health_handler() -> Html<&'static str> {
-    println!("some one call health check api.");
-    Html("<h1>Inventory server health ok.</h1>")
-}
-
 pub async fn query_inventory(
     State(pool): State<PgPool>,
     Query(query_params): Query<QueryRequest>,
@@ -41,10 +36,15 @@ pub async fn query_inventory(
  */
 pub async fn query_inventory_change_history(
     State(pool): State<PgPool>,
-    Query(query_params): Query<QueryRequest>,
-) -> Result<axum::Json<Vec<InventoryChange>>, (StatusCode, String)> {
+    Query(query_params): Query<QueryChangeRequest>,
+) -> Result<axum::Json<Page<InventoryChange>>, (StatusCode, String)> {
     // println!("get_all_orders user_id: {}", query_params.user_id);
-    let result = query_inventory_change_from_db(&pool, query_params.id)
+    let page = PageRequest::new(
+        query_params.cursor,
+        query_params.page_size,
+        query_params.with_total,
+    );
+    let result = query_inventory_change_from_db(&pool, query_params.id, &page)
         .await
         .map(map_ok_result);
 
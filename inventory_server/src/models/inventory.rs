@@ -53,4 +53,16 @@ pub struct ChangeInventoryResult {
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct QueryRequest {
     pub id: i32,
+}
+
+/**
+ * 查询库存变化记录，id为库存id
+ */
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QueryChangeRequest {
+    pub id: i32,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+    #[serde(default)]
+    pub with_total: bool,
 }
\ No newline at end of file
@@ -1,12 +1,29 @@
-use std::error::Error;
+use std::{error::Error, time::Instant};
 
 use bcrypt::{BcryptError, DEFAULT_COST};
+use prometheus::{HistogramOpts, HistogramVec};
+
+lazy_static! {
+    /**
+     * bcrypt任务在rayon线程池中排队等待的时间，由使用方注册到自己的指标注册表中
+     */
+    pub static ref BCRYPT_QUEUE_SECONDS: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "bcrypt_queue_seconds",
+            "Time bcrypt jobs wait in the rayon pool before running."
+        ),
+        &["op"],
+    )
+    .unwrap();
+}
 
 
 // consume password value to make it unusable
 pub async fn hash_password(password: String) -> Result<String, Box<dyn Error>> {
     let (send, recv) = tokio::sync::oneshot::channel();
+    let queued_at = Instant::now();
     rayon::spawn(move || {
+        observe_queue_time("hash", queued_at);
         let result = bcrypt::hash(password, DEFAULT_COST);
         let _ = send.send(result);
     });
@@ -15,9 +32,17 @@ pub async fn hash_password(password: String) -> Result<String, Box<dyn Error>> {
 
 pub async fn verify_password(password: String, hash: String) -> Result<bool, Box<dyn Error>> {
     let (send, recv) = tokio::sync::oneshot::channel();
+    let queued_at = Instant::now();
     rayon::spawn(move || {
+        observe_queue_time("verify", queued_at);
         let result = bcrypt::verify(password, &hash);
         let _ = send.send(result);
     });
     Ok(recv.await??)
 }
+
+fn observe_queue_time(op: &str, queued_at: Instant) {
+    BCRYPT_QUEUE_SECONDS
+        .with_label_values(&[op])
+        .observe(queued_at.elapsed().as_secs_f64());
+}
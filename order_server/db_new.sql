@@ -4,7 +4,11 @@ create table orders (
 
        user_id UUID not null,
 
+       -- item_id 商品id，sku_id 下单时选定的商品SKU（规格）id，增加SKU之前的订单为空
+       -- inventory_id 下单时从商品服务查到的SKU对应的库存id，扣减库存和重试都使用它
        item_id INT not null,
+       sku_id INT,
+       inventory_id INT not null,
        price INT not null,
        count INT not null,
 
@@ -19,7 +23,7 @@ create table orders (
 );
 
 -- 创建索引
-CREATE INDEX idx_orders_user_id ON orders (user_id);
+CREATE INDEX idx_orders_user_id ON orders (user_id, id);
 
 -- insert into orders (items_id, price, total_price, currency, sub_time, pay_time, description)
 -- values(       '',
@@ -40,5 +44,10 @@ create table orders_de_inventory_msg (
 
        order_id INT not null,
 
+       create_time TIMESTAMP default now(),
+
+       -- 定时任务已经重试的次数
+       retry_count INT not null default 0,
+
        description varchar(140)
 );
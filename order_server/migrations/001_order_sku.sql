@@ -0,0 +1,4 @@
+-- 订单引用商品的SKU：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。之前的订单没有SKU，sku_id为空。
+
+alter table orders add column if not exists sku_id INT;
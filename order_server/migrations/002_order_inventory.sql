@@ -0,0 +1,6 @@
+-- 订单保存扣减的库存id：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。之前的订单按商品id扣减库存，这里沿用。
+
+alter table orders add column if not exists inventory_id INT;
+update orders set inventory_id = item_id where inventory_id is null;
+alter table orders alter column inventory_id set not null;
@@ -0,0 +1,4 @@
+-- 消息积压时间：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。已有的消息以执行脚本的时间作为创建时间。
+
+alter table orders_de_inventory_msg add column if not exists create_time TIMESTAMP default now();
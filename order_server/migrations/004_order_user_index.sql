@@ -0,0 +1,14 @@
+-- 订单列表按 (user_id, id) 翻页：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行，索引已经包含id时不会重建。
+
+do $$
+begin
+       if not exists (
+              select 1 from pg_indexes
+              where tablename = 'orders' and indexname = 'idx_orders_user_id' and indexdef like '%(user_id, id)%'
+       ) then
+              drop index if exists idx_orders_user_id;
+              create index idx_orders_user_id on orders (user_id, id);
+       end if;
+end
+$$;
@@ -0,0 +1,4 @@
+-- 扣减库存消息的重试次数：已有数据的数据库执行这个脚本升级，新建的数据库直接执行db_new.sql即可。
+-- 可以重复执行。已有的消息从0开始计数。
+
+alter table orders_de_inventory_msg add column if not exists retry_count INT not null default 0;
@@ -1,31 +1,49 @@
 #[macro_use]
 extern crate num_derive;
+#[macro_use]
+extern crate lazy_static;
 
 use axum::{
+    middleware,
     routing::{get, post},
     Router,
 };
+use std::{env, time::Duration};
+
 use chrono::Utc;
-use dotenv::dotenv;
+use common_lib::{
+    bootstrap::ServiceBuilder,
+    dynamic_config::DynamicConfig,
+    health,
+    maintenance,
+    metrics::{metrics_handler, track_http, GrpcMetrics},
+    multiplex::MultiplexService,
+    request_id::{request_id_http, GrpcRequestId},
+    telemetry::{trace_http, GrpcTracing},
+};
+use consul_reg_lib::{
+    consul::Consul,
+    discover::{balance_channel, BalancedChannel, LoadBalance},
+    discovery,
+    lock::{Lock, LockOption},
+};
 use idgenerator::{IdGeneratorOptions, IdInstance};
-use std::{env, fs::File, sync::Arc};
-use std::{net::SocketAddr, thread};
+use sqlx::PgPool;
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::{info, span, Level, Subscriber};
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tokio_util::sync::CancellationToken;
 use tower_http::cors::CorsLayer;
-
-use sqlx::postgres::PgPoolOptions;
+use tracing::{info, span, warn, Instrument, Level};
 
 use crate::{
+    db_access::repo::{check_goods_health, check_inventory_health},
     handlers::grpc::*,
-    handlers::{corn::poll_inventory_state_order_from_db, rest::*},
-    models::state::AppState,
-    multiplexservice::MultiplexService,
+    handlers::{
+        corn::{self, poll_inventory_state_order_from_db},
+        rest::*,
+    },
+    models::{config::OrderConfig, state::AppState},
 };
 
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
-
 #[path = "../db_access/mod.rs"]
 mod db_access;
 #[path = "../handlers/mod.rs"]
@@ -34,33 +52,14 @@ mod handlers;
 #[path = "../models/mod.rs"]
 mod models;
 
-#[path = "../multiplex_service.rs"]
-mod multiplexservice;
-
-
-fn main() {
-    thread::spawn(|| {
-        //定时任务，用于定时轮询本地消息列表中有没有失败的任务没有处理
-        tokio::runtime::Runtime::new().unwrap().block_on(async {
-            println!("start corn sched in main.");
-            corn_aysnc().await;
-        });
-    });
-
-    tokio::runtime::Runtime::new().unwrap().block_on(async {
-        println!("start web_server in main.");
-        web_server().await;
-    });
-}
-
-async fn web_server() {
-    dotenv().ok();
-
-    //初始化tracing
-    let file_appender = tracing_appender::rolling::hourly("./axum_log", "prefix.log");
-    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
-    // tracing_subscriber::registry().with(fmt::layer()).init();
-    tracing_subscriber::fmt().with_writer(non_blocking).init();
+#[tokio::main]
+async fn main() {
+    let mut builder = ServiceBuilder::new("order-srv", "127.0.0.1:3002")
+        .init_logging()
+        .register_consul()
+        //就绪检查的结果由服务自己定期上报给consul，降级时上报warning
+        .ttl_check(Duration::from_secs(15))
+        .meta("version", env!("CARGO_PKG_VERSION"));
 
     // 雪花算法生成唯一id
     let options = IdGeneratorOptions::new().worker_id(1).worker_id_bit_len(6);
@@ -71,82 +70,155 @@ async fn web_server() {
     // let id = IdInstance::next_id();
 
     //set database pool
-    //设置数据库连接池。restful和grpc服务各用一个，不用考虑生命周期标注会比较简单。
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL should be set.");
-    let local_database_url =
-        env::var("DATABASE_URL_LOCAL").expect("DATABASE_URL_LOCAL should be set.");
-    let db_pool = PgPoolOptions::new().connect(&database_url).await.unwrap();
-    let db_pool2 = PgPoolOptions::new().connect(&database_url).await.unwrap();
-
-    let local_db_pool = PgPoolOptions::new()
-        .connect(&local_database_url)
-        .await
-        .unwrap();
-
-    let local_db_pool2 = PgPoolOptions::new()
-        .connect(&local_database_url)
-        .await
-        .unwrap();
+    let db_pool = builder.pg_pool("DATABASE_URL").await;
+    let local_db_pool = builder.pg_pool("DATABASE_URL_LOCAL").await;
+
+    //库存服务的grpc连接，在consul中的所有健康实例之间负载均衡，
+    //负载均衡方式通过环境变量INVENTORY_LOAD_BALANCE（round_robin或者p2c）配置，默认p2c
+    let inventory_srv_name = "inventory-srv".to_string();
+    let load_balance = env::var("INVENTORY_LOAD_BALANCE")
+        .map(|lb| lb.parse().unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or(LoadBalance::PowerOfTwoChoices);
+    let consul = Consul::from_env().unwrap_or_else(|e| panic!("create consul client failed: {}", e));
+    //超时时间、重试次数等配置保存在consul KV中，修改后自动生效
+    let order_config = DynamicConfig::<OrderConfig>::load(consul.clone(), "config/order-srv").await;
+
+    //多个副本中只有获取到锁的leader执行定时任务，避免重复处理同一条消息
+    let corn_lock = Lock::new(
+        consul.clone(),
+        "service/order-srv/leader/corn",
+        LockOption {
+            value: builder.instance_id().to_string(),
+            ..LockOption::default()
+        },
+    );
+
+    //服务发现方式由环境变量SERVICE_DISCOVERY配置，默认使用consul，本地开发时可以使用static
+    let service_discovery = discovery::from_env(consul);
+    let inventory_channel = balance_channel(service_discovery.as_ref(), &inventory_srv_name, "http", load_balance);
+    //下单时通过商品服务查询SKU对应的库存id
+    let goods_srv_name = "goods-srv".to_string();
+    let goods_channel = balance_channel(service_discovery.as_ref(), &goods_srv_name, "http", load_balance);
+
+    corn::register_metrics();
+
+    //定时任务，用于定时轮询本地消息列表中有没有失败的任务没有处理
+    let corn_pool = db_pool.clone();
+    let corn_channel = inventory_channel.clone();
+    let corn_config = order_config.clone();
+    builder.background_task("corn", move |shutdown| {
+        corn_leader(corn_lock, corn_pool, corn_channel, corn_config, shutdown)
+    });
 
     let app_state = AppState {
-        pool: db_pool,
-        local_pool: local_db_pool,
-        inventory_srv_id: "inventory-srv".to_string(),
+        pool: db_pool.clone(),
+        local_pool: local_db_pool.clone(),
+        inventory_srv_name,
+        inventory_channel: inventory_channel.clone(),
+        goods_channel: goods_channel.clone(),
+        config: order_config.clone(),
     };
 
-    let health_check_path = "/health_check";
+    //下单时需要扣减库存，库存服务不可用时订单服务也不能算就绪
+    let inventory_srv_name = app_state.inventory_srv_name.clone();
+    let inventory_discovery = service_discovery.clone();
+    builder.readiness().add_check("inventory", move || {
+        check_inventory_health(inventory_discovery.clone(), inventory_srv_name.clone())
+    });
+    //不能确定扣减哪一条库存时也不能下单
+    builder.readiness().add_check("goods", move || {
+        check_goods_health(service_discovery.clone(), goods_srv_name.clone())
+    });
+    let grpc_health = builder.grpc_health::<GrpcServer>();
 
     // build our application with a route
     let rest = Router::new()
-        .route(health_check_path, get(health_handler))
+        .merge(health::router(builder.readiness()))
         .route("/orders", get(get_all_orders))
         .route("/add_order", post(add_new_order))
         .route("/request_order_token", get(request_new_order_token).post(request_new_order_token))
+        .route_layer(middleware::from_fn(track_http))
+        .route("/metrics", get(metrics_handler))
+        .merge(maintenance::router(builder.maintenance()))
+        .layer(middleware::from_fn(trace_http))
+        .layer(middleware::from_fn(request_id_http))
         .layer(CorsLayer::permissive())
         .with_state(app_state);
 
-    let grpc = get_grpc_router(db_pool2, local_db_pool2);
+    let grpc = Router::new()
+        .route_service("/grpc.health.v1.Health/*rpc", grpc_health)
+        .fallback_service(get_grpc_router(db_pool, local_db_pool, inventory_channel, goods_channel, order_config));
+    let grpc = GrpcRequestId::new(GrpcTracing::new(GrpcMetrics::new(grpc)));
 
     // combine them into one service
     // 将rest和grpc两种路由合并到一起
     let service = MultiplexService::new(rest, grpc);
 
-    // run it
-    let addr = "127.0.0.1:3002";
-    println!("listening on {}", addr);
-
-    //向consul中心注册自己
-    tokio::spawn(register_consul(&addr, health_check_path));
+    builder.serve(service).await;
+}
 
-    axum::Server::bind(&addr.parse().unwrap())
-        // .serve(rest.into_make_service())
-        .serve(tower::make::Shared::new(service))
-        .await
-        .unwrap();
+/**
+ * 竞争定时任务的leader，成为leader之后才启动定时任务，失去leader时停止并重新竞争，直到服务退出
+ */
+async fn corn_leader(
+    lock: Lock,
+    db_pool: PgPool,
+    inventory_channel: BalancedChannel,
+    config: DynamicConfig<OrderConfig>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let mut leadership = tokio::select! {
+            leadership = lock.acquire() => leadership,
+            _ = shutdown.cancelled() => return,
+        };
+        info!("became corn leader.");
+
+        let stop = shutdown.child_token();
+        let corn = tokio::spawn(corn_async(
+            db_pool.clone(),
+            inventory_channel.clone(),
+            config.clone(),
+            stop.clone(),
+        ));
+
+        tokio::select! {
+            _ = leadership.lost() => warn!("lost corn leadership, stop corn sched."),
+            _ = shutdown.cancelled() => {},
+        }
+        stop.cancel();
+        let _ = corn.await;
+
+        //服务退出时主动释放锁，其它副本可以立刻接管
+        if shutdown.is_cancelled() {
+            leadership.release().await;
+            return;
+        }
+    }
 }
 
-async fn corn_aysnc() {
+async fn corn_async(
+    db_pool: PgPool,
+    inventory_channel: BalancedChannel,
+    config: DynamicConfig<OrderConfig>,
+    shutdown: CancellationToken,
+) {
     let mut sched = JobScheduler::new().await.unwrap();
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL should be set.");
-    let local_database_url =
-        env::var("DATABASE_URL_LOCAL").expect("DATABASE_URL_LOCAL should be set.");
-
-    let db_pool_arc = Arc::new(PgPoolOptions::new().connect(&database_url).await.unwrap());
-
-    let job = Job::new("1/10 * * * * *", move |uuid, l| {
-        let span = span!(Level::TRACE, "corn_async");
-        let enter = span.enter();
+    let job = Job::new_async("1/10 * * * * *", move |_uuid, _l| {
+        let db_pool = db_pool.clone();
+        let inventory_channel = inventory_channel.clone();
+        let config = config.clone();
+        Box::pin(
+            async move {
+                let now = Utc::now().timestamp_millis();
 
-        let now = Utc::now().timestamp_millis();
+                info!("I run every 10 seconds ts:{}", now);
 
-        info!("I run every 10 seconds ts:{}", now);
-
-        let db_pool = db_pool_arc.clone();
-        poll_inventory_state_order_from_db(
-            &db_pool,
-            "https://127.0.0.1:3001".to_string(),
-        );
+                poll_inventory_state_order_from_db(&db_pool, inventory_channel, &config.get()).await;
+            }
+            .instrument(span!(Level::TRACE, "corn_async")),
+        )
     })
     .unwrap();
 
@@ -154,42 +226,12 @@ async fn corn_aysnc() {
     sched.add(job).await.unwrap();
     sched.start().await.unwrap();
 
-    println!("start corn sched.");
+    info!("start corn sched.");
 
-    loop {
-        //一直等待，这里的定时任务要永久执行下去。
-        if let Ok(Some(it)) = sched.time_till_next_job().await {
-            info!("time_till_next_job {:?}", it);
-            tokio::time::sleep(it).await;
-        };
+    //定时任务一直执行，直到服务退出
+    shutdown.cancelled().await;
+    if let Err(e) = sched.shutdown().await {
+        info!("corn sched shutdown error: {:?}", e);
     }
-}
-
-/**
- * 注册微服务到consul中
- */
-async fn register_consul(addr: &str, health_check_path: &str) {
-    println!("register consul doing...");
-    let addrs: Vec<&str> = addr.split(":").collect();
-    let addr = addrs[0];
-    let port: i32 = addrs[1].parse().unwrap();
-    let opt = consul_reg_lib::model::ConsulOption::default();
-    let cs = consul_reg_lib::consul::Consul::new(opt).unwrap();
-
-    let health_check_url = format!("http://{}:{}{}", addr, port, health_check_path);
-
-    let health_check = consul_reg_lib::model::HealthCheck::new(health_check_url.to_string());
-
-    println!("register consul health_check params:{:?}", health_check);
-
-    //register consul name as order-srv.
-    let reg = consul_reg_lib::model::Registration::simple_with_health_check(
-        "order-srv",
-        addr,
-        port,
-        health_check,
-    );
-
-    cs.register(&reg).await.unwrap();
-    println!("register consul done.");
+    info!("corn sched stopped.");
 }
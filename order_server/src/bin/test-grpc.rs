@@ -28,8 +28,9 @@ async fn grpc_get_order(user_id: Uuid) -> Result<String, String> {
 
     let req = tonic::Request::new(GetOrderRequest {
         user_id: user_id.to_string(),
-        page: 0,
         page_size: 5,
+        cursor: String::new(),
+        with_total: false,
     });
     let get_order_respone = client
         .get_orders(req)
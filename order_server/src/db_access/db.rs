@@ -1,16 +1,22 @@
 use std::f32::consts::E;
+use std::time::Duration;
 
 use axum::http::StatusCode;
 use chrono::NaiveDateTime;
-use common_lib::internal_error;
+use consul_reg_lib::discover::BalancedChannel;
+use common_lib::{
+    internal_error,
+    pagination::{Page, PageRequest},
+};
 use sqlx::{postgres::PgPool, Acquire};
-use tracing::info;
+use tracing::{error, info, instrument};
 use uuid::Uuid;
 
 use crate::{
-    db_access::repo::deduction_inventory_call,
+    db_access::repo::{deduction_inventory_call, resolve_inventory_call},
     models::{
         order::{AddOrder, AddOrderResult, Order},
+        config::OrderConfig,
         state::{InventoryResult, InventoryState},
     },
 };
@@ -19,24 +25,27 @@ mod inventory_proto {
     tonic::include_proto!("inventory");
 }
 
+#[instrument(skip(pool))]
 pub async fn get_all_orders_from_db(
     pool: &PgPool,
     user_id: Uuid,
-    page: i64,
-    page_size: i64,
-) -> Result<Vec<Order>, (StatusCode, String)> {
-    let offset = page_size * page;
+    page: &PageRequest,
+) -> Result<Page<Order>, (StatusCode, String)> {
+    //订单按时间倒序，新的订单在前
+    let before = page.after()?.unwrap_or(i64::MAX);
     let orders = sqlx::query!(
-        "SELECT * FROM orders WHERE user_id = $1 LIMIT $2 OFFSET $3",
+        "SELECT * FROM orders WHERE user_id = $1 AND id < $2::int8 ORDER BY id DESC LIMIT $3",
         user_id,
-        page_size,
-        offset
+        before,
+        page.fetch_limit()
     )
     .map({
         |row| Order {
             id: row.id,
             user_id: row.user_id,
             item_id: row.item_id,
+            sku_id: row.sku_id,
+            inventory_id: row.inventory_id,
             price: row.price,
             count: row.count,
             currency: row.currency.unwrap_or_default(),
@@ -52,19 +61,50 @@ pub async fn get_all_orders_from_db(
 
     info!("get_all_orders_from_db size: {}", orders.len());
 
-    Ok(orders)
+    let total = if page.with_total {
+        let count = sqlx::query_scalar!(
+            r#"SELECT count(*) as "count!" FROM orders WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(internal_error)?;
+        Some(count)
+    } else {
+        None
+    };
+
+    Ok(Page::from_rows(orders, page, |o| o.id as i64).with_total(total))
 }
 
+#[instrument(skip(pool, inventory_channel, goods_channel))]
 pub async fn add_new_order_from_db(
     pool: &PgPool,
-    inventory_addr: String,
+    inventory_channel: BalancedChannel,
+    goods_channel: BalancedChannel,
+    config: &OrderConfig,
     data: AddOrder,
     uuid: Uuid,
 ) -> Result<AddOrderResult, (StatusCode, String)> {
     let des = data.description.unwrap_or_default();
     let price = data.price;
 
-    println!("add_new_order des: {}", des);
+    info!("add_new_order des: {}", des);
+
+    //扣减哪一条库存由商品服务决定，和订单一起保存，定时任务重试时也使用它
+    let resolved =
+        resolve_inventory_call(goods_channel, data.items_id, data.sku_id, config.goods_timeout()).await?;
+    let inventory_id = resolved.inventory_id;
+    //价格以商品服务中SKU当前的单价为准，客户端看到的价格已经过期时拒绝下单，让用户重新确认
+    if price != resolved.unit_price {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "price {} does not match the unit price {} of sku {}.",
+                price, resolved.unit_price, data.sku_id
+            ),
+        ));
+    }
 
     //本地订单插入
     // let item_ids_str = serde_json::to_string(&data.items_id).unwrap_or_default();
@@ -74,7 +114,7 @@ pub async fn add_new_order_from_db(
     let mut conn = pool.acquire().await.unwrap();
     let mut tx = conn.begin().await.map_err(internal_error)?;
 
-    let insert_order =  sqlx::query!("INSERT INTO orders (user_id, item_id, price, count, currency, pay_time, description,inventory_state) VALUES ($1, $2, $3, $4, $5, $6, $7,$8) RETURNING id",uuid,data.items_id, data.price,data.count, data.currency,ts_1970, des,InventoryState::DOING as i32)
+    let insert_order =  sqlx::query!("INSERT INTO orders (user_id, item_id, sku_id, inventory_id, price, count, currency, pay_time, description,inventory_state) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id",uuid,data.items_id, data.sku_id, inventory_id, data.price,data.count, data.currency,ts_1970, des,InventoryState::DOING as i32)
                 .map(|row| row.id)
                 .fetch_one(&mut tx)
                 .await;
@@ -84,7 +124,7 @@ pub async fn add_new_order_from_db(
         Ok(order_id) => {
             order_id_cp = order_id;
 
-            println!("insert_order suceess");
+            info!("insert_order suceess");
 
             let insert_msg = sqlx::query!(
                 "INSERT INTO orders_de_inventory_msg (user_id, order_id) VALUES ($1, $2) RETURNING id",
@@ -97,11 +137,11 @@ pub async fn add_new_order_from_db(
             .map_err(internal_error);
 
             let innerResult = if let Err(e) = insert_msg {
-                println!("insert_msg fail should rollback.");
+                error!("insert_msg fail should rollback.");
 
                 Err(e)
             } else {
-                println!("insert_msg success,try rpc call.");
+                info!("insert_msg success,try rpc call.");
 
                 
                 let addResult = AddOrderResult {
@@ -113,7 +153,7 @@ pub async fn add_new_order_from_db(
             innerResult
         }
         Err(e) => {
-            println!("insert_order failed should rollback.");
+            error!("insert_order failed should rollback.");
 
             Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
         }
@@ -121,7 +161,10 @@ pub async fn add_new_order_from_db(
 
     if let Ok(_) = result {
         tx.commit().await.unwrap();
-        deduction_inventory(pool, inventory_addr, data.items_id, data.count, order_id_cp).await;
+        //关闭立即扣减时，由定时任务从本地消息表中取出消息扣减
+        if config.deduct_inventory_on_order {
+            deduction_inventory(pool, inventory_channel, inventory_id, data.count, order_id_cp, config.inventory_timeout()).await;
+        }
     } else {
         tx.rollback().await.unwrap();
     }
@@ -133,15 +176,17 @@ pub async fn add_new_order_from_db(
  * 扣减库存，并更新本地数据库。
  * 没有返回，调用者不关心这个函数的执行情况，因为结果是会放到数据库中，并由定时器定期轮询检查。
  */
+#[instrument(skip(pool, inventory_channel))]
 pub async fn deduction_inventory(
     pool: &PgPool,
-    inventory_addr: String,
-    items_id: i32,
+    inventory_channel: BalancedChannel,
+    inventory_id: i32,
     count: i32,
     order_id: i32,
+    timeout: Duration,
 ) {
     //分布式事务，扣减库存
-    let deducation_resp = deduction_inventory_call(inventory_addr, items_id, count, order_id).await;
+    let deducation_resp = deduction_inventory_call(inventory_channel, inventory_id, count, order_id, timeout).await;
     if let Ok(resp) = deducation_resp {
         //响应为success的时候我们记录扣减库存成功
         let inventory_state = if InventoryResult::SUCCESS as i32 == resp.result {
@@ -152,14 +197,20 @@ pub async fn deduction_inventory(
 
         //删除消息数据库
         let mut conn = pool.acquire().await.unwrap();
-        let mut tx = conn.begin().await.map_err(internal_error)?;
+        let mut tx = match conn.begin().await {
+            Ok(tx) => tx,
+            Err(e) => {
+                error!("deduction_inventory begin tx failed: {}", e);
+                return;
+            }
+        };
 
         //注意，这一步可能写成功也可能写失败，所以可能导致deduction_inventory_call反复被调用，库存那边需要保证同一个订单id不会重复扣减。
         let _update_msg_result = sqlx::query!(
             "DELETE FROM orders_de_inventory_msg where  order_id = ($1)",
             order_id
         )
-        .fetch_one(&mut tx)
+        .execute(&mut tx)
         .await
         .map_err(internal_error);
 
@@ -169,7 +220,7 @@ pub async fn deduction_inventory(
             inventory_state as i32,
             order_id
         )
-        .fetch_one(&mut tx)
+        .execute(&mut tx)
         .await
         .map_err(internal_error);
 
@@ -182,8 +233,9 @@ pub async fn deduction_inventory(
         } else {
             tx.rollback().await.unwrap();
         }
-    } else {
+    } else if let Err(e) = deducation_resp {
         // 远程调用失败，不代表扣减库存失败，等待定时器轮训的时候继续尝试
+        error!("deduction inventory of order {} failed: {}", order_id, e);
         //TODO 添加定时器轮询orders_de_inventory_msg表
     }
 }
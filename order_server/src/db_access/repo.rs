@@ -1,37 +1,151 @@
-use tonic::transport::Channel;
+use std::{sync::Arc, time::Duration};
 
-use self::inventory_proto::{
-    inventory_service_client::InventoryServiceClient, DeductionInventoryRequest,
+use axum::http::StatusCode;
+use common_lib::{health::check_srv_health, telemetry::inject_trace_context};
+use consul_reg_lib::{discover::BalancedChannel, discovery::ServiceDiscovery};
+use tonic::Code;
+use tracing::instrument;
+
+use self::{
+    goods_proto::{goods_service_client::GoodsServiceClient, ResolveInventoryRequest, ResolveInventoryRespone},
+    inventory_proto::{inventory_service_client::InventoryServiceClient, DeductionInventoryRequest},
 };
 
 mod inventory_proto {
     tonic::include_proto!("inventory");
 }
 
+mod goods_proto {
+    tonic::include_proto!("goods");
+}
+
+/**
+ * 库存服务的grpc服务全名
+ */
+const INVENTORY_GRPC_SERVICE: &str = "inventory.InventoryService";
+/**
+ * 商品服务的grpc服务全名
+ */
+const GOODS_GRPC_SERVICE: &str = "goods.GoodsService";
+
+/**
+ * 就绪检查：从服务发现中取出库存服务的实例，并通过grpc健康检查协议确认至少有一个可以正常提供服务
+ */
+pub async fn check_inventory_health(
+    discovery: Arc<dyn ServiceDiscovery>,
+    inventory_srv_name: String,
+) -> Result<(), String> {
+    check_srv_health(discovery, &inventory_srv_name, INVENTORY_GRPC_SERVICE).await
+}
+
+/**
+ * 就绪检查：商品服务是否可用，下单时需要通过它查询SKU对应的库存
+ */
+pub async fn check_goods_health(
+    discovery: Arc<dyn ServiceDiscovery>,
+    goods_srv_name: String,
+) -> Result<(), String> {
+    check_srv_health(discovery, &goods_srv_name, GOODS_GRPC_SERVICE).await
+}
+
+/**
+ * 查询商品SKU对应的库存id和单价。商品和库存的对应关系由商品服务维护，订单服务不能假设二者的id相同。
+ * SKU不存在、商品已经下架或者SKU还没有关联库存时返回400，商品服务不可用时返回503。
+ */
+#[instrument(skip(goods_channel))]
+pub async fn resolve_inventory_call(
+    goods_channel: BalancedChannel,
+    goods_id: i32,
+    sku_id: i32,
+    timeout: Duration,
+) -> Result<ResolveInventoryRespone, (StatusCode, String)> {
+    let mut client = GoodsServiceClient::with_interceptor(goods_channel, inject_trace_context);
+
+    let mut req = tonic::Request::new(ResolveInventoryRequest { goods_id, sku_id });
+    req.set_timeout(timeout);
+
+    let response = tokio::time::timeout(timeout, client.resolve_inventory(req))
+        .await
+        .map_err(|_| resolve_unavailable(format!("timeout after {:?}.", timeout)))?
+        .map_err(resolve_error)?
+        .into_inner();
+
+    Ok(response)
+}
+
+/**
+ * 商品服务返回的grpc状态转换成下单接口的错误：不能下单的SKU是请求的问题，返回400，其他的返回503
+ */
+fn resolve_error(status: tonic::Status) -> (StatusCode, String) {
+    match status.code() {
+        Code::NotFound | Code::FailedPrecondition => (StatusCode::BAD_REQUEST, status.message().to_string()),
+        _ => resolve_unavailable(status.to_string()),
+    }
+}
+
+fn resolve_unavailable(msg: String) -> (StatusCode, String) {
+    (StatusCode::SERVICE_UNAVAILABLE, format!("resolve inventory failed: {}", msg))
+}
+
 /**
  * 扣减库存call
+ * inventory_channel 在库存服务所有健康实例之间做负载均衡的连接，clone的开销很小
  */
+#[instrument(skip(inventory_channel))]
 pub async fn deduction_inventory_call(
-    addr: String,
+    inventory_channel: BalancedChannel,
     inventory_id: i32,
     deduction_count: i32,
     order_id: i32,
+    timeout: Duration,
 ) -> Result<inventory_proto::DeductionInventoryRespone, String> {
-    let mut client = InventoryServiceClient::connect(addr)
-        .await
-        .map_err(|err| err.to_string())?;
+    //拦截器把当前的追踪上下文写入grpc metadata，让库存服务的span挂到同一条链路上
+    let mut client = InventoryServiceClient::with_interceptor(inventory_channel, inject_trace_context);
 
-    let req = tonic::Request::new(DeductionInventoryRequest {
+    let mut req = tonic::Request::new(DeductionInventoryRequest {
         inventory_id: inventory_id,
         deduction_count: deduction_count,
         orders_id: order_id,
     });
+    //超时时间同时通过grpc-timeout告诉库存服务
+    req.set_timeout(timeout);
 
-    let deduction_inventory = client
-        .deduction_inventory(req)
+    let deduction_inventory = tokio::time::timeout(timeout, client.deduction_inventory(req))
         .await
+        .map_err(|_| format!("deduction inventory timeout after {:?}.", timeout))?
         .map_err(|err| err.to_string())?
         .into_inner();
 
     return Ok(deduction_inventory);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_error() {
+        assert_eq!(
+            resolve_error(tonic::Status::not_found("sku 2 of goods 1 not found.")),
+            (StatusCode::BAD_REQUEST, "sku 2 of goods 1 not found.".to_string())
+        );
+        assert_eq!(
+            resolve_error(tonic::Status::failed_precondition("goods 1 has been archived.")).0,
+            StatusCode::BAD_REQUEST
+        );
+        for status in [
+            tonic::Status::unavailable("connection refused"),
+            tonic::Status::deadline_exceeded("timeout"),
+            tonic::Status::internal("db error"),
+        ] {
+            assert_eq!(resolve_error(status).0, StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(
+            resolve_unavailable("timeout after 1s.".to_string()),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "resolve inventory failed: timeout after 1s.".to_string()
+            )
+        );
+    }
+}
@@ -1,22 +1,71 @@
+use std::time::Duration;
+
 use chrono::NaiveDateTime;
-use common_lib::internal_error;
+use common_lib::{internal_error, metrics};
+use consul_reg_lib::discover::BalancedChannel;
+use prometheus::{IntGauge, Gauge};
 use sqlx::PgPool;
-use tracing::instrument;
+use tracing::{error, instrument};
 
 use crate::{
     db_access::{repo::deduction_inventory_call, db::deduction_inventory},
     models::{
+        config::OrderConfig,
         order::{Order, OrderDeInventoryMsg},
         state::{InventoryResult, InventoryState},
     },
 };
 
+lazy_static! {
+    static ref DE_INVENTORY_MSG_BACKLOG: IntGauge = IntGauge::new(
+        "orders_de_inventory_msg_backlog",
+        "Number of messages waiting in orders_de_inventory_msg."
+    )
+    .unwrap();
+    static ref DE_INVENTORY_MSG_OLDEST_AGE: Gauge = Gauge::new(
+        "orders_de_inventory_msg_oldest_age_seconds",
+        "Age of the oldest message in orders_de_inventory_msg."
+    )
+    .unwrap();
+}
+
+pub fn register_metrics() {
+    metrics::register(Box::new(DE_INVENTORY_MSG_BACKLOG.clone()));
+    metrics::register(Box::new(DE_INVENTORY_MSG_OLDEST_AGE.clone()));
+}
+
+/**
+ * 更新本地消息表积压的消息数量和最老一条消息的等待时间
+ */
+async fn update_backlog_metrics(pool: &PgPool) {
+    let backlog = sqlx::query!(
+        r#"SELECT count(*) as "count!", EXTRACT(EPOCH FROM now() - min(create_time))::float8 as oldest_age FROM orders_de_inventory_msg"#
+    )
+    .fetch_one(pool)
+    .await;
+
+    match backlog {
+        Ok(row) => {
+            DE_INVENTORY_MSG_BACKLOG.set(row.count);
+            DE_INVENTORY_MSG_OLDEST_AGE.set(row.oldest_age.unwrap_or_default());
+        }
+        Err(e) => error!("update_backlog_metrics error: {}", e),
+    }
+}
+
 pub async fn poll_inventory_state_order_from_db(
     pool: &PgPool,
-    inventory_addr: String,
+    inventory_channel: BalancedChannel,
+    config: &OrderConfig,
 ) {
+    update_backlog_metrics(pool).await;
+
+    //超过重试次数的消息不再自动处理，它们仍然计入积压指标
     let orders_msg: Result<Vec<OrderDeInventoryMsg>, _> =
-        sqlx::query!("SELECT * FROM orders_de_inventory_msg",)
+        sqlx::query!(
+            "SELECT id, user_id, order_id FROM orders_de_inventory_msg WHERE retry_count < $1",
+            config.outbox_max_retries
+        )
             .map({
                 |row| OrderDeInventoryMsg {
                     id: row.id,
@@ -31,25 +80,39 @@ pub async fn poll_inventory_state_order_from_db(
 
     match orders_msg {
         Ok(msg_list) => for msg in msg_list {
-            try_de_inventory(pool, inventory_addr.clone(), msg);
+            try_de_inventory(pool, inventory_channel.clone(), msg, config.inventory_timeout()).await;
         },
-        Err(e) => {
-            //print error msg;
+        Err((_, e)) => {
+            error!("query orders_de_inventory_msg failed: {}", e);
         }
     }
 }
 
 async fn try_de_inventory(
     pool: &PgPool,
-    inventory_addr: String,
+    inventory_channel: BalancedChannel,
     msg: OrderDeInventoryMsg,
+    timeout: Duration,
 ) {
+    let retry = sqlx::query!(
+        "UPDATE orders_de_inventory_msg SET retry_count = retry_count + 1 WHERE id = $1",
+        msg.id
+    )
+    .execute(pool)
+    .await;
+    if let Err(e) = retry {
+        error!("update retry_count of msg {} error: {}", msg.id, e);
+        return;
+    }
+
     let orders = sqlx::query!("SELECT * FROM orders WHERE id = $1", msg.order_id,)
         .map({
             |row| Order {
                 id: row.id,
                 user_id: row.user_id,
                 item_id: row.item_id,
+                sku_id: row.sku_id,
+                inventory_id: row.inventory_id,
                 price: row.price,
                 count: row.count,
                 currency: row.currency.unwrap_or_default(),
@@ -66,10 +129,12 @@ async fn try_de_inventory(
     if let Ok(order) = orders {
         deduction_inventory(
             pool,
-            inventory_addr,
-            order.item_id,
+            inventory_channel,
+            order.inventory_id,
             order.count,
             msg.order_id,
-        );
+            timeout,
+        )
+        .await;
     }
 }
\ No newline at end of file
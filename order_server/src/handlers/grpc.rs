@@ -1,11 +1,15 @@
 use std::f32::consts::E;
 
+use axum::http::StatusCode;
+use common_lib::{dynamic_config::DynamicConfig, pagination::PageRequest};
+use consul_reg_lib::discover::BalancedChannel;
 use sqlx::PgPool;
+use tracing::debug;
 use uuid::Uuid;
 
 use crate::{
     db_access::db::{add_new_order_from_db, get_all_orders_from_db},
-    models::order::AddOrder,
+    models::{config::OrderConfig, order::AddOrder},
 };
 
 use self::order_proto::order_service_server::{OrderService, OrderServiceServer};
@@ -17,13 +21,25 @@ mod order_proto {
 pub struct GrpcServiceImpl {
     pool: PgPool,
     local_pool: PgPool,
+    inventory_channel: BalancedChannel,
+    goods_channel: BalancedChannel,
+    config: DynamicConfig<OrderConfig>,
 }
 
 impl GrpcServiceImpl {
-    pub fn new(pg_pool: PgPool, local_pool: PgPool) -> GrpcServiceImpl {
+    pub fn new(
+        pg_pool: PgPool,
+        local_pool: PgPool,
+        inventory_channel: BalancedChannel,
+        goods_channel: BalancedChannel,
+        config: DynamicConfig<OrderConfig>,
+    ) -> GrpcServiceImpl {
         return GrpcServiceImpl {
             pool: pg_pool,
             local_pool: local_pool,
+            inventory_channel,
+            goods_channel,
+            config,
         };
     }
 }
@@ -41,17 +57,31 @@ impl OrderService for GrpcServiceImpl {
         if let Ok(uid) = uuid_result {
             uuid = uid;
         } else {
-            let response = order_proto::GetOrderRespone { orders: vec![] };
+            let response = order_proto::GetOrderRespone {
+                orders: vec![],
+                next_cursor: String::new(),
+                total: None,
+            };
             return Ok(tonic::Response::new(response));
         }
 
-        let db =
-            get_all_orders_from_db(&self.pool, uuid, request_data.page, request_data.page_size)
-                .await;
+        let page = PageRequest::new(
+            Some(request_data.cursor),
+            Some(request_data.page_size),
+            request_data.with_total,
+        );
+        let db = get_all_orders_from_db(&self.pool, uuid, &page).await;
 
         let mut response_datas: Vec<order_proto::Order> = Vec::new();
+        let mut next_cursor = String::new();
+        let mut total = None;
+        if let Err((StatusCode::BAD_REQUEST, msg)) = db {
+            return Err(tonic::Status::invalid_argument(msg));
+        }
         if let Ok(datas) = db {
-            for order in datas {
+            next_cursor = datas.next_cursor.unwrap_or_default();
+            total = datas.total;
+            for order in datas.items {
                 // let item_id_str = serde_json::to_string(&order.items_id).unwrap_or_default();
                 let des = order.description.unwrap_or_default();
 
@@ -59,6 +89,7 @@ impl OrderService for GrpcServiceImpl {
                 let proto_order = order_proto::Order {
                     user_id: uuid_str,
                     items_id: order.item_id,
+                    sku_id: order.sku_id,
                     price: order.price,
                     count: order.count,
                     currency: order.currency,
@@ -70,9 +101,11 @@ impl OrderService for GrpcServiceImpl {
 
         let response = order_proto::GetOrderRespone {
             orders: response_datas,
+            next_cursor,
+            total,
         };
 
-        println!("GrpcServiceImpl get_orders result: {:?}", response);
+        debug!("GrpcServiceImpl get_orders result: {:?}", response);
         Ok(tonic::Response::new(response))
     }
 
@@ -91,8 +124,18 @@ impl OrderService for GrpcServiceImpl {
             return Ok(tonic::Response::new(response));
         }
 
+        //订单需要指定SKU
+        let sku_id = match request_data.sku_id {
+            Some(sku_id) => sku_id,
+            None => {
+                let response = order_proto::AddOrderRespone { result: 1 };
+                return Ok(tonic::Response::new(response));
+            }
+        };
+
         let add = AddOrder {
             items_id: request_data.items_id,
+            sku_id,
             price: request_data.price,
             count: request_data.count,
             currency: request_data.currency,
@@ -101,7 +144,9 @@ impl OrderService for GrpcServiceImpl {
         };
         let db_result = add_new_order_from_db(
             &self.pool,
-            "https://127.0.0.1:3001".to_string(),
+            self.inventory_channel.clone(),
+            self.goods_channel.clone(),
+            &self.config.get(),
             add,
             uuid,
         )
@@ -116,6 +161,23 @@ impl OrderService for GrpcServiceImpl {
     }
 }
 
-pub fn get_grpc_router(pg_pool: PgPool, local_pool: PgPool) -> OrderServiceServer<GrpcServiceImpl> {
-    OrderServiceServer::new(GrpcServiceImpl::new(pg_pool, local_pool))
+/**
+ * 服务器上提供的grpc服务类型，用于设置它的grpc健康状态
+ */
+pub type GrpcServer = OrderServiceServer<GrpcServiceImpl>;
+
+pub fn get_grpc_router(
+    pg_pool: PgPool,
+    local_pool: PgPool,
+    inventory_channel: BalancedChannel,
+    goods_channel: BalancedChannel,
+    config: DynamicConfig<OrderConfig>,
+) -> GrpcServer {
+    OrderServiceServer::new(GrpcServiceImpl::new(
+        pg_pool,
+        local_pool,
+        inventory_channel,
+        goods_channel,
+        config,
+    ))
 }
@@ -3,13 +3,13 @@ use std::f32::consts::E;
 use axum::{
     extract::{Query, State},
     http::StatusCode,
-    response::Html,
     Json,
 };
 
 use futures::TryFutureExt;
 use idgenerator::IdInstance;
 
+use common_lib::pagination::{Page, PageRequest};
 use jwt_lib::jwt::Claims;
 use tracing::{info, instrument};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
@@ -22,27 +22,20 @@ use crate::{
     },
 };
 
-#[instrument]
-pub async fn health_handler() -> Html<&'static str> {
-    println!("some one call health check api.");
-    Html("<h1>Order server health ok.</h1>")
-}
-
 #[instrument]
 pub async fn get_all_orders(
     State(state): State<AppState>,
     Query(query_params): Query<GetOrderParams>,
-) -> Result<axum::Json<Vec<Order>>, (StatusCode, String)> {
+) -> Result<axum::Json<Page<Order>>, (StatusCode, String)> {
     info!("get_all_orders user_id: {}", query_params.user_id);
-    println!("get_all_orders user_id: {}", query_params.user_id);
-    get_all_orders_from_db(
-        &state.pool,
-        query_params.user_id,
-        query_params.page,
+    let page = PageRequest::new(
+        query_params.cursor,
         query_params.page_size,
-    )
-    .await
-    .map(map_ok_result)
+        query_params.with_total,
+    );
+    get_all_orders_from_db(&state.pool, query_params.user_id, &page)
+        .await
+        .map(map_ok_result)
 }
 
 /**
@@ -55,7 +48,7 @@ pub async fn request_new_order_token(
 ) -> Result<axum::Json<NewOrderToken>, (StatusCode, String)> {
     if let Some(claims) = claims_op {
         let id = IdInstance::next_id();
-        println!("request_new_order_token: {}", id);
+        info!("request_new_order_token: {}", id);
         Ok(axum::Json(NewOrderToken { token: id }))
     } else {
         return Err((
@@ -73,22 +66,17 @@ pub async fn add_new_order(
     //TODO 此处插入token数据合法性校验
     if let Some(claims) = claims_op {
         let uuid = claims.sub;
-        //从consul获取库存微服务的地址
-        let cs = consul_reg_lib::consul::Consul::newDefault().map_err(map_consult_error)?;
-        let filter = consul_reg_lib::model::Filter::ID(state.inventory_srv_id);
-        let srv_option = cs.get_service(&filter).await.map_err(map_consult_error)?;
-
-        if let Some(srv) = srv_option {
-            let inventory_addr = srv.address;
-            add_new_order_from_db(&state.pool, inventory_addr, data, uuid)
-                .await
-                .map(map_ok_result)
-        } else {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "cannot found inventory_srv from consul.".to_string(),
-            ));
-        }
+        //库存服务的实例由consul发现，请求在所有健康实例之间负载均衡
+        add_new_order_from_db(
+            &state.pool,
+            state.inventory_channel,
+            state.goods_channel,
+            &state.config.get(),
+            data,
+            uuid,
+        )
+        .await
+        .map(map_ok_result)
     } else {
         return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -101,9 +89,3 @@ pub fn map_ok_result<T>(r: T) -> axum::Json<T> {
     axum::Json(r)
 }
 
-pub fn map_consult_error(err: reqwest::Error) -> (StatusCode, String) {
-    return (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        "consul error.".to_string(),
-    );
-}
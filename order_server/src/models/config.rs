@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use validator::Validate;
+
+/**
+ * 订单服务的动态配置，保存在consul KV的config/order-srv中，修改后不需要重新部署，比如：
+ * {"inventory_timeout_ms": 3000, "goods_timeout_ms": 1000, "outbox_max_retries": 10, "deduct_inventory_on_order": true}
+ * 没有设置的字段使用默认值。
+ */
+#[derive(Deserialize, Validate, Debug, Clone)]
+#[serde(default)]
+pub struct OrderConfig {
+    /**
+     * 调用库存服务扣减库存的超时时间
+     */
+    #[validate(range(min = 100, max = 60000))]
+    pub inventory_timeout_ms: u64,
+    /**
+     * 下单时调用商品服务查询SKU对应库存的超时时间
+     */
+    #[validate(range(min = 100, max = 60000))]
+    pub goods_timeout_ms: u64,
+    /**
+     * 本地消息表中每条扣减库存消息最多重试的次数，超过之后定时任务不再处理，需要人工介入
+     */
+    #[validate(range(min = 1, max = 1000))]
+    pub outbox_max_retries: i32,
+    /**
+     * 下单成功后是否立即扣减库存，关闭时全部交给定时任务处理
+     */
+    pub deduct_inventory_on_order: bool,
+}
+
+impl OrderConfig {
+    pub fn inventory_timeout(&self) -> Duration {
+        Duration::from_millis(self.inventory_timeout_ms)
+    }
+
+    pub fn goods_timeout(&self) -> Duration {
+        Duration::from_millis(self.goods_timeout_ms)
+    }
+}
+
+impl Default for OrderConfig {
+    fn default() -> Self {
+        Self {
+            inventory_timeout_ms: 3000,
+            goods_timeout_ms: 1000,
+            outbox_max_retries: 10,
+            deduct_inventory_on_order: true,
+        }
+    }
+}
@@ -1,2 +1,3 @@
+pub mod config;
 pub mod order;
 pub mod state;
\ No newline at end of file
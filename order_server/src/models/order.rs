@@ -5,12 +5,16 @@ use uuid::Uuid;
 #[allow(dead_code)]
 pub struct GetOrderParams {
     pub user_id: Uuid,
-    pub page: i64,
-    pub page_size: i64,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+    #[serde(default)]
+    pub with_total: bool,
 }
 
 /**
  * inventory_success 库存是否扣减成功
+ * item_id 商品id，sku_id 下单时选定的SKU（规格）id，增加SKU之前的订单没有sku_id
+ * inventory_id 扣减的库存id，下单时由商品服务根据SKU查询
  */
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Order {
@@ -19,6 +23,8 @@ pub struct Order {
     pub user_id: Uuid,
 
     pub item_id: i32,
+    pub sku_id: Option<i32>,
+    pub inventory_id: i32,
     pub price: i32,
     pub count : i32,
     pub currency: String,
@@ -31,9 +37,13 @@ pub struct Order {
     pub description: Option<String>,
 }
 
+/**
+ * items_id 商品id，sku_id 商品的SKU id，从商品详情的skus中选择，price是SKU的单价，和商品服务中的不一致时拒绝下单
+ */
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AddOrder {
     pub items_id: i32,
+    pub sku_id: i32,
     pub price: i32,
     pub count : i32,
     pub currency: String,
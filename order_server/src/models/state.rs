@@ -1,10 +1,17 @@
+use common_lib::dynamic_config::DynamicConfig;
+use consul_reg_lib::discover::BalancedChannel;
 use sqlx::PgPool;
 
-#[derive(Clone,Debug)]
+use super::config::OrderConfig;
+
+#[derive(Clone, Debug)]
 pub struct AppState {
     pub pool: PgPool,
     pub local_pool: PgPool,
-    pub inventory_srv_id: String,
+    pub inventory_srv_name: String,
+    pub inventory_channel: BalancedChannel,
+    pub goods_channel: BalancedChannel,
+    pub config: DynamicConfig<OrderConfig>,
     // pub inventory_addr: String,
 }
 